@@ -0,0 +1,636 @@
+// Action rules: run a command when an event matches a path pattern and mask.
+//
+// Rules come from `[[action]]` entries in the configuration or from
+// incrontab files. Commands run in their own process group with a cleaned
+// environment, at most `max_running` at a time, and are killed when they
+// exceed the timeout; exit status and output come back as `ActionResult`s
+// for the event log. Like incron's IN_NO_LOOP, a rule never fires for
+// events caused by its own commands: not for events from their process
+// group, and not for the path a command was started for while it runs (and
+// briefly after, until its queued events have been read).
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::event::Event;
+use crate::mark::MarkSet;
+use crate::mask::{inotify_names, mask_from_inotify_name, mask_names};
+use crate::sys::*;
+
+pub const DEFAULT_MAX_RUNNING: usize = 4;
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Environment variables passed to commands unless configured otherwise.
+pub const DEFAULT_ENV: &[&str] = &["PATH", "HOME", "LANG", "TZ"];
+/// Output kept per stream; anything beyond is dropped.
+const OUTPUT_LIMIT: usize = 16 * 1024;
+/// How long a finished command still suppresses its rule on its path.
+const LOOP_GRACE: Duration = Duration::from_secs(1);
+/// How often running commands are checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pattern {
+    /// `*` and `?` match within one path component, `**` across components.
+    Glob(String),
+    /// incron semantics: the path itself or a direct child of it.
+    Watched(PathBuf),
+}
+
+impl Pattern {
+    pub fn matches(&self, path: &Path) -> bool {
+        match self {
+            Pattern::Glob(glob) => glob_match(glob.as_bytes(), path.as_os_str().as_bytes()),
+            Pattern::Watched(watched) => path == watched || path.parent() == Some(watched),
+        }
+    }
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::Glob(glob) => f.write_str(glob),
+            Pattern::Watched(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Shell-style matching: `*` and `?` stop at `/`, `**` does not.
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob_match(rest, &text[i..])),
+        [b'?', rest @ ..] => matches!(text, [c, tail @ ..] if *c != b'/' && glob_match(rest, tail)),
+        [p, rest @ ..] => matches!(text, [c, tail @ ..] if c == p && glob_match(rest, tail)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ActionCommand {
+    /// Program and arguments, each expanded on its own; no shell involved.
+    Argv(Vec<String>),
+    /// A `/bin/sh -c` command line (incrontab); expanded values are quoted.
+    Shell(String),
+}
+
+impl std::fmt::Display for ActionCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ActionCommand::Argv(argv) => f.write_str(&argv.join(" ")),
+            ActionCommand::Shell(line) => f.write_str(line),
+        }
+    }
+}
+
+/// Values available to command templates.
+struct Context<'a> {
+    path: &'a Path,
+    dir: &'a Path,
+    name: &'a [u8],
+    mask: u64,
+    pid: i32,
+}
+
+impl Context<'_> {
+    /// `$path $name $dir $mask $pid`, incron's `$@ $# $% $&`, and `$$`.
+    /// Anything else (e.g. `$HOME` in a shell line) is left alone.
+    fn expand(&self, template: &str, quote: bool) -> String {
+        let mut out = String::new();
+        let mut rest = template;
+        while let Some(at) = rest.find('$') {
+            out.push_str(&rest[..at]);
+            rest = &rest[at + 1..];
+            let word_len = rest.bytes().take_while(|b| b.is_ascii_lowercase()).count();
+            let (value, used) = match rest.as_bytes().first() {
+                Some(b'$') => {
+                    out.push('$');
+                    rest = &rest[1..];
+                    continue;
+                }
+                Some(b'@') => (Some(self.dir.to_string_lossy().into_owned()), 1),
+                Some(b'#') => (Some(String::from_utf8_lossy(self.name).into_owned()), 1),
+                Some(b'%') => (Some(inotify_names(self.mask).iter().map(|n| format!("IN_{}", n)).collect::<Vec<_>>().join(",")), 1),
+                Some(b'&') => (Some((self.mask & 0xffff_ffff).to_string()), 1),
+                _ => match &rest[..word_len] {
+                    "path" => (Some(self.path.to_string_lossy().into_owned()), word_len),
+                    "name" => (Some(String::from_utf8_lossy(self.name).into_owned()), word_len),
+                    "dir" => (Some(self.dir.to_string_lossy().into_owned()), word_len),
+                    "mask" => (Some(mask_names(self.mask).join(",")), word_len),
+                    "pid" => (Some(self.pid.to_string()), word_len),
+                    _ => (None, 0),
+                },
+            };
+            match value {
+                Some(value) if quote => out.push_str(&shell_quote(&value)),
+                Some(value) => out.push_str(&value),
+                None => out.push('$'),
+            }
+            rest = &rest[used..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActionRule {
+    /// Where the rule came from, e.g. `action #2` or `/etc/incron.d/x:3`.
+    pub source: String,
+    pub pattern: Pattern,
+    pub mask: u64,
+    pub command: ActionCommand,
+}
+
+impl ActionRule {
+    pub fn matches(&self, mask: u64, path: &Path) -> bool {
+        mask & self.mask != 0 && self.pattern.matches(path)
+    }
+
+    /// The program and arguments to run for an event on `path`.
+    pub fn argv(&self, mask: u64, pid: i32, path: &Path) -> Vec<String> {
+        let (dir, name) = match &self.pattern {
+            // incron: $@ is the watched path, $# the name below it (empty for the path itself)
+            Pattern::Watched(watched) if path == watched => (watched.as_path(), &b""[..]),
+            _ => (
+                path.parent().unwrap_or(Path::new("/")),
+                path.file_name().map_or(&b""[..], |n| n.as_bytes()),
+            ),
+        };
+        let context = Context { path, dir, name, mask: (mask & self.mask) | (mask & FAN_ONDIR), pid };
+        match &self.command {
+            ActionCommand::Argv(argv) => argv.iter().map(|arg| context.expand(arg, false)).collect(),
+            ActionCommand::Shell(line) => vec!["/bin/sh".into(), "-c".into(), context.expand(line, true)],
+        }
+    }
+
+    /// Marks the path an incrontab rule watches; glob rules rely on `[[watch]]` marks.
+    pub fn mark(&self, marks: &mut MarkSet) -> std::io::Result<bool> {
+        let Pattern::Watched(path) = &self.pattern else {
+            return Ok(false);
+        };
+        let dirent = FAN_CREATE | FAN_DELETE | FAN_MOVE;
+        if path.is_dir() {
+            marks.add(path, FAN_MARK_INODE, self.mask | FAN_EVENT_ON_CHILD, FAN_MARK_ONLYDIR)?;
+        } else {
+            // Directory entry events only exist on directories
+            marks.add(path, FAN_MARK_INODE, self.mask & !dirent, 0)?;
+        }
+        Ok(true)
+    }
+}
+
+impl std::fmt::Display for ActionRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} [{}] -> {}", self.source, self.pattern, mask_names(self.mask).join(","), self.command)
+    }
+}
+
+/// incron options that change how the watch is made; they are accepted and ignored.
+const INCRON_OPTIONS: &[&str] = &["IN_DONT_FOLLOW", "IN_ONESHOT", "IN_ONLYDIR", "IN_NO_LOOP", "IN_MASK_ADD", "IN_EXCL_UNLINK"];
+
+/// Parses an incrontab: `<path> <mask,...> <command>` per line, `#` comments,
+/// `\ ` for spaces in the path.
+pub fn parse_incrontab(text: &str, origin: &Path) -> Result<Vec<ActionRule>, String> {
+    let mut rules = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let source = format!("{}:{}", origin.display(), number + 1);
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut path = String::new();
+        let mut chars = line.char_indices().peekable();
+        let mut rest = "";
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' if chars.peek().is_some_and(|(_, next)| next.is_whitespace()) => {
+                    path.push(chars.next().unwrap().1);
+                }
+                c if c.is_whitespace() => {
+                    rest = line[i..].trim_start();
+                    break;
+                }
+                c => path.push(c),
+            }
+        }
+        let (masks, command) = rest.split_once(char::is_whitespace).ok_or_else(|| format!("{}: expected <path> <mask> <command>", source))?;
+        let mut mask = 0;
+        for name in masks.split(',') {
+            if name.contains('=') || INCRON_OPTIONS.contains(&name) {
+                continue;
+            }
+            mask |= match name.parse::<u64>() {
+                Ok(bits) => bits & 0xfff,
+                Err(_) => mask_from_inotify_name(name).map_err(|e| format!("{}: {}", source, e))?,
+            };
+        }
+        if mask == 0 {
+            return Err(format!("{}: no events in {}", source, masks));
+        }
+        let command = command.trim();
+        if command.is_empty() {
+            return Err(format!("{}: missing command", source));
+        }
+        rules.push(ActionRule {
+            source,
+            pattern: Pattern::Watched(PathBuf::from(path)),
+            mask,
+            command: ActionCommand::Shell(command.to_string()),
+        });
+    }
+    Ok(rules)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActionSettings {
+    pub max_running: usize,
+    pub timeout: Duration,
+    /// Names of the variables commands inherit; everything else is cleared.
+    pub env: Vec<String>,
+}
+
+impl Default for ActionSettings {
+    fn default() -> Self {
+        ActionSettings {
+            max_running: DEFAULT_MAX_RUNNING,
+            timeout: DEFAULT_TIMEOUT,
+            env: DEFAULT_ENV.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+}
+
+/// How a command ended, for the event log.
+#[derive(Debug, Clone)]
+pub struct ActionResult {
+    pub rule: String,
+    pub command: Vec<String>,
+    pub path: PathBuf,
+    pub mask: u64,
+    /// The process whose event triggered the rule.
+    pub pid: i32,
+    /// The command's pid (and process group), 0 if it never started.
+    pub child: i32,
+    pub status: Option<i32>,
+    pub signal: Option<i32>,
+    pub timed_out: bool,
+    pub duration: Duration,
+    pub stdout: String,
+    pub stderr: String,
+    /// Why the command could not be run.
+    pub error: Option<String>,
+}
+
+impl ActionResult {
+    pub fn success(&self) -> bool {
+        self.status == Some(0)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "event": "action",
+            "rule": self.rule,
+            "command": self.command,
+            "path": self.path,
+            "mask": self.mask,
+            "events": mask_names(self.mask),
+            "pid": self.pid,
+            "child": self.child,
+            "status": self.status,
+            "signal": self.signal,
+            "timed_out": self.timed_out,
+            "duration_ms": self.duration.as_millis() as u64,
+            "stdout": self.stdout,
+            "stderr": self.stderr,
+            "error": self.error,
+        })
+    }
+}
+
+impl std::fmt::Display for ActionResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} for {}: ", self.rule, self.path.display())?;
+        match (&self.error, self.status, self.signal) {
+            (Some(error), _, _) => write!(f, "failed to run: {}", error),
+            (None, _, Some(signal)) if self.timed_out => {
+                write!(f, "killed (signal {}) after the {:?} timeout", signal, self.duration)
+            }
+            (None, Some(status), _) => write!(f, "exited with status {} after {:?}", status, self.duration),
+            (None, None, Some(signal)) => write!(f, "killed by signal {} after {:?}", signal, self.duration),
+            (None, None, None) => write!(f, "ended in an unknown state after {:?}", self.duration),
+        }
+    }
+}
+
+// What a command was started for and the processes seen acting for it
+struct Guard {
+    rule: usize,
+    path: PathBuf,
+    /// The process group id, plus every pid found in that group while alive.
+    pids: HashSet<i32>,
+}
+
+impl Guard {
+    fn caused(&self, rule: usize, pid: i32, group: Option<i32>) -> bool {
+        self.rule == rule && (self.pids.contains(&pid) || group.is_some_and(|g| self.pids.contains(&g)))
+    }
+}
+
+struct Job {
+    rule: usize,
+    argv: Vec<String>,
+    path: PathBuf,
+    mask: u64,
+    pid: i32,
+}
+
+pub struct ActionRunner {
+    rules: Vec<ActionRule>,
+    settings: ActionSettings,
+    queue: VecDeque<Job>,
+    /// Process group of each running command -> what it runs for.
+    running: HashMap<i32, Guard>,
+    /// Recently finished commands, still guarding their rule, path and pids:
+    /// their events may be read after they exited.
+    finished: Vec<(Guard, Instant)>,
+    results_tx: Sender<ActionResult>,
+    results_rx: Receiver<ActionResult>,
+    failed: Vec<ActionResult>,
+}
+
+impl ActionRunner {
+    pub fn new(rules: Vec<ActionRule>, settings: ActionSettings) -> Self {
+        let (results_tx, results_rx) = mpsc::channel();
+        ActionRunner {
+            rules,
+            settings,
+            queue: VecDeque::new(),
+            running: HashMap::new(),
+            finished: Vec::new(),
+            results_tx,
+            results_rx,
+            failed: Vec::new(),
+        }
+    }
+
+    pub fn rules(&self) -> &[ActionRule] {
+        &self.rules
+    }
+
+    pub fn settings(&self) -> &ActionSettings {
+        &self.settings
+    }
+
+    /// Queues the commands of every rule the event matches and starts what
+    /// the concurrency limit allows. Returns how many were queued.
+    pub fn handle(&mut self, event: &Event) -> usize {
+        let Some(path) = event.path.as_deref() else {
+            return 0;
+        };
+        let group = process_group(event.pid);
+        let now = Instant::now();
+        self.finished.retain(|(_, at)| now.duration_since(*at) < LOOP_GRACE);
+        // Remember it while it can still be looked up
+        if let Some(guard) = group.and_then(|g| self.running.get_mut(&g)) {
+            guard.pids.insert(event.pid);
+        }
+
+        let mut queued = 0;
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.matches(event.mask, path) {
+                continue;
+            }
+            let mut guards = self.running.values().chain(self.finished.iter().map(|(guard, _)| guard));
+            if guards.any(|g| g.caused(index, event.pid, group) || (g.rule == index && g.path == path)) {
+                println!("DEBUG: {} not run for {}: caused by its own command", rule.source, path.display());
+                continue;
+            }
+            let argv = rule.argv(event.mask, event.pid, path);
+            self.queue.push_back(Job { rule: index, argv, path: path.to_path_buf(), mask: event.mask, pid: event.pid });
+            queued += 1;
+        }
+        self.start_queued();
+        queued
+    }
+
+    /// Results of commands that ended since the last call.
+    pub fn poll(&mut self) -> Vec<ActionResult> {
+        let mut results = std::mem::take(&mut self.failed);
+        while let Ok(result) = self.results_rx.try_recv() {
+            if let Some(guard) = self.running.remove(&result.child) {
+                self.finished.push((guard, Instant::now()));
+            }
+            results.push(result);
+        }
+        self.start_queued();
+        results.append(&mut self.failed);
+        results
+    }
+
+    /// Commands running or waiting for a slot.
+    pub fn busy(&self) -> usize {
+        self.running.len() + self.queue.len()
+    }
+
+    /// When `poll` should be called next, for use as a poll() timeout.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        (self.busy() > 0 || !self.failed.is_empty()).then_some(POLL_INTERVAL)
+    }
+
+    fn start_queued(&mut self) {
+        while self.running.len() < self.settings.max_running.max(1) {
+            let Some(job) = self.queue.pop_front() else {
+                break;
+            };
+            let source = self.rules[job.rule].source.clone();
+            let started = Instant::now();
+            match self.spawn(&job.argv) {
+                Ok(child) => {
+                    let pid = child.id() as i32;
+                    println!("DEBUG: {} started pid {}: {}", source, pid, job.argv.join(" "));
+                    let guard = Guard { rule: job.rule, path: job.path.clone(), pids: HashSet::from([pid]) };
+                    self.running.insert(pid, guard);
+                    let timeout = self.settings.timeout;
+                    let tx = self.results_tx.clone();
+                    std::thread::spawn(move || {
+                        let _ = tx.send(supervise(child, started, timeout, source, job));
+                    });
+                }
+                Err(e) => self.failed.push(ActionResult {
+                    rule: source,
+                    command: job.argv,
+                    path: job.path,
+                    mask: job.mask,
+                    pid: job.pid,
+                    child: 0,
+                    status: None,
+                    signal: None,
+                    timed_out: false,
+                    duration: Duration::ZERO,
+                    stdout: String::new(),
+                    stderr: String::new(),
+                    error: Some(e.to_string()),
+                }),
+            }
+        }
+    }
+
+    fn spawn(&self, argv: &[String]) -> std::io::Result<Child> {
+        let (program, args) = argv.split_first().ok_or_else(|| std::io::Error::other("empty command"))?;
+        let env = std::env::vars_os().filter(|(name, _)| self.settings.env.iter().any(|allowed| name == allowed.as_str()));
+        Command::new(program)
+            .args(args)
+            .env_clear()
+            .envs(env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Own process group: events it causes can be told apart, and a
+            // timeout kills everything it started
+            .process_group(0)
+            .spawn()
+    }
+}
+
+/// Process group of a live process, from /proc/<pid>/stat.
+fn process_group(pid: i32) -> Option<i32> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces; fields after it are plain
+    stat.rsplit_once(')')?.1.split_whitespace().nth(2)?.parse().ok()
+}
+
+fn capture(mut stream: impl Read) -> String {
+    let mut kept = Vec::new();
+    let mut buf = [0u8; 4096];
+    while let Ok(n) = stream.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        let room = OUTPUT_LIMIT.saturating_sub(kept.len());
+        kept.extend_from_slice(&buf[..n.min(room)]);
+    }
+    String::from_utf8_lossy(&kept).into_owned()
+}
+
+// Runs on its own thread: waits for the command, enforcing the timeout, and collects its output.
+fn supervise(mut child: Child, started: Instant, timeout: Duration, rule: String, job: Job) -> ActionResult {
+    let pid = child.id() as i32;
+    let stdout = child.stdout.take().map(|s| std::thread::spawn(move || capture(s)));
+    let stderr = child.stderr.take().map(|s| std::thread::spawn(move || capture(s)));
+
+    let mut timed_out = false;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) if started.elapsed() >= timeout => {
+                timed_out = true;
+                unsafe { libc::kill(-pid, libc::SIGKILL) };
+                break child.wait().ok();
+            }
+            Ok(None) => std::thread::sleep(Duration::from_millis(20)),
+            Err(_) => break None,
+        }
+    };
+    let duration = started.elapsed();
+    // Whatever the command left behind in its group would keep the pipes open
+    unsafe { libc::kill(-pid, libc::SIGKILL) };
+
+    let join = |reader: Option<std::thread::JoinHandle<String>>| reader.and_then(|r| r.join().ok()).unwrap_or_default();
+    ActionResult {
+        rule,
+        command: job.argv,
+        path: job.path,
+        mask: job.mask,
+        pid: job.pid,
+        child: pid,
+        status: status.and_then(|s| s.code()),
+        signal: status.and_then(|s| s.signal()),
+        timed_out,
+        duration,
+        stdout: join(stdout),
+        stderr: join(stderr),
+        error: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, path: &str) -> bool {
+        glob_match(pattern.as_bytes(), path.as_bytes())
+    }
+
+    #[test]
+    fn star_stays_within_a_component() {
+        assert!(glob("/src/*.rs", "/src/main.rs"));
+        assert!(!glob("/src/*.rs", "/src/bin/main.rs"));
+        assert!(glob("/src/?ain.rs", "/src/main.rs"));
+        assert!(!glob("/src/?", "/src/"));
+        assert!(!glob("/src?main.rs", "/src/main.rs"));
+    }
+
+    #[test]
+    fn double_star_crosses_components() {
+        assert!(glob("/src/**.rs", "/src/bin/main.rs"));
+        assert!(glob("/src/**", "/src/a/b/c"));
+        assert!(glob("**", "/anything"));
+        assert!(!glob("/src/**.rs", "/lib/main.rs"));
+    }
+
+    #[test]
+    fn parses_incrontab_lines() {
+        let text = "# comment\n\n/srv/my\\ dir IN_CLOSE_WRITE,IN_MOVED_TO,IN_NO_LOOP echo $@/$#\n/etc 8 true\n";
+        let rules = parse_incrontab(text, Path::new("/etc/incron.d/x")).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].source, "/etc/incron.d/x:3");
+        assert_eq!(rules[0].pattern, Pattern::Watched(PathBuf::from("/srv/my dir")));
+        assert_eq!(rules[0].mask, FAN_CLOSE_WRITE | FAN_MOVED_TO);
+        assert_eq!(rules[0].command, ActionCommand::Shell("echo $@/$#".to_string()));
+        assert_eq!(rules[1].mask, FAN_CLOSE_WRITE);
+    }
+
+    #[test]
+    fn rejects_broken_incrontab_lines() {
+        let origin = Path::new("t");
+        assert!(parse_incrontab("/srv IN_CLOSE_WRITE", origin).unwrap_err().contains("t:1"));
+        assert!(parse_incrontab("/srv IN_NO_LOOP true", origin).unwrap_err().contains("no events"));
+        assert!(parse_incrontab("/srv IN_BOGUS true", origin).is_err());
+    }
+
+    fn context<'a>(path: &'a Path, name: &'a [u8]) -> Context<'a> {
+        Context { path, dir: path.parent().unwrap(), name, mask: FAN_CLOSE_WRITE, pid: 42 }
+    }
+
+    #[test]
+    fn expands_variables() {
+        let path = Path::new("/srv/in/a.txt");
+        let ctx = context(path, b"a.txt");
+        assert_eq!(ctx.expand("$dir|$name|$path|$pid|$$|$HOME", false), "/srv/in|a.txt|/srv/in/a.txt|42|$|$HOME");
+        assert_eq!(ctx.expand("$@/$# $% $&", false), "/srv/in/a.txt IN_CLOSE_WRITE 8");
+        assert_eq!(ctx.expand("$mask", false), "close_write");
+    }
+
+    #[test]
+    fn quotes_expanded_values_for_the_shell() {
+        let path = Path::new("/srv/in/it's $(rm -rf ~).txt");
+        let ctx = context(path, b"it's $(rm -rf ~).txt");
+        assert_eq!(ctx.expand("cat $path", true), r#"cat '/srv/in/it'\''s $(rm -rf ~).txt'"#);
+        assert_eq!(ctx.expand("cp $@/$# /backup", true), r#"cp '/srv/in'/'it'\''s $(rm -rf ~).txt' /backup"#);
+        // The template itself is not quoted
+        assert_eq!(ctx.expand("echo \"$HOME\" $$", true), "echo \"$HOME\" $");
+    }
+}
@@ -0,0 +1,231 @@
+// Application allowlisting on FAN_OPEN_EXEC_PERM, in the spirit of fapolicyd.
+//
+// The trust database is sha256sum(1) output: `<hex digest>  <path>` per
+// line, so `sha256sum /usr/bin/* > trust.db` makes one. An exec is allowed
+// when the path is listed with the digest of the file being executed.
+// Learning mode allows everything and appends what would have been denied
+// to a file in the same format, ready to be reviewed and merged.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::perm::{Decision, Policy, Request};
+
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(2);
+
+pub type Sha256Digest = [u8; 32];
+
+fn to_hex(digest: &Sha256Digest) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Sha256Digest> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// Allowed executables: path to the digests it may have.
+#[derive(Debug, Default)]
+pub struct TrustDb {
+    entries: HashMap<PathBuf, HashSet<Sha256Digest>>,
+}
+
+impl TrustDb {
+    /// Parses sha256sum output; `#` starts a comment, `*` before the path
+    /// (binary mode) is accepted.
+    pub fn parse(text: &str, origin: &Path) -> Result<Self, String> {
+        let mut db = TrustDb::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line
+                .split_once(char::is_whitespace)
+                .and_then(|(hex, path)| Some((from_hex(hex)?, path.trim_start().trim_start_matches('*'))));
+            let Some((digest, path)) = parsed.filter(|(_, path)| path.starts_with('/')) else {
+                return Err(format!("{}:{}: expected `<sha256>  <absolute path>`", origin.display(), n + 1));
+            };
+            db.insert(PathBuf::from(path), digest);
+        }
+        Ok(db)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        TrustDb::parse(&text, path)
+    }
+
+    pub fn insert(&mut self, path: PathBuf, digest: Sha256Digest) {
+        self.entries.entry(path).or_default().insert(digest);
+    }
+
+    pub fn is_trusted(&self, path: &Path, digest: &Sha256Digest) -> bool {
+        self.entries.get(path).is_some_and(|digests| digests.contains(digest))
+    }
+
+    /// Number of (path, digest) pairs.
+    pub fn len(&self) -> usize {
+        self.entries.values().map(HashSet::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// SHA-256 of an open file, read from the start without moving its offset.
+pub fn sha256_fd(fd: &impl AsRawFd) -> io::Result<Sha256Digest> {
+    // Borrowed: the kernel's event fd stays owned by the request
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd.as_raw_fd()) });
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut offset = 0;
+    loop {
+        let n = file.read_at(&mut buffer, offset)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        offset += n as u64;
+    }
+    Ok(hasher.finalize().into())
+}
+
+// What a cached digest is valid for: the same inode, unchanged since hashing
+type FileKey = (u64, u64, u64, i64, i64, i64, i64);
+
+fn file_key(fd: &impl AsRawFd) -> io::Result<FileKey> {
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd.as_raw_fd()) });
+    let meta = file.metadata()?;
+    Ok((meta.dev(), meta.ino(), meta.size(), meta.mtime(), meta.mtime_nsec(), meta.ctime(), meta.ctime_nsec()))
+}
+
+/// Learning mode output and what was already written to it.
+struct LearnLog {
+    file: File,
+    seen: HashSet<(PathBuf, Sha256Digest)>,
+}
+
+pub struct Allowlist {
+    db: TrustDb,
+    /// Learning mode: where would-be denials are appended.
+    learn: Option<Mutex<LearnLog>>,
+    digests: Mutex<HashMap<FileKey, Sha256Digest>>,
+}
+
+impl Allowlist {
+    pub fn new(db: TrustDb, learn: Option<&Path>) -> io::Result<Self> {
+        let learn = match learn {
+            Some(path) => {
+                let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+                Some(Mutex::new(LearnLog { file, seen: HashSet::new() }))
+            }
+            None => None,
+        };
+        Ok(Allowlist { db, learn, digests: Mutex::new(HashMap::new()) })
+    }
+
+    pub fn trusted(&self) -> usize {
+        self.db.len()
+    }
+
+    pub fn learning(&self) -> bool {
+        self.learn.is_some()
+    }
+
+    fn judge(&self, request: &Request, digest: &Sha256Digest) -> Decision {
+        if self.db.is_trusted(&request.path, digest) {
+            return Decision::allow(format!("trusted, sha256 {}", to_hex(digest))).cached();
+        }
+        let Some(learn) = &self.learn else {
+            return Decision::deny(format!("not in the trust database (sha256 {})", to_hex(digest)));
+        };
+        let mut learn = learn.lock().unwrap();
+        if learn.seen.insert((request.path.clone(), *digest))
+            && let Err(e) = writeln!(learn.file, "{}  {}", to_hex(digest), request.path.display())
+        {
+            eprintln!("✗ Failed to record learned executable {}: {}", request.path.display(), e);
+        }
+        Decision::allow(format!("learning: would deny, sha256 {}", to_hex(digest)))
+    }
+}
+
+impl Policy for Allowlist {
+    fn quick(&self, request: &Request) -> Option<Decision> {
+        let key = file_key(&request.file).ok()?;
+        let digest = *self.digests.lock().unwrap().get(&key)?;
+        Some(self.judge(request, &digest))
+    }
+
+    fn decide(&self, request: &Request) -> Decision {
+        let digest = match (file_key(&request.file), sha256_fd(&request.file)) {
+            (Ok(key), Ok(digest)) => {
+                self.digests.lock().unwrap().insert(key, digest);
+                digest
+            }
+            (Err(e), _) | (_, Err(e)) if self.learning() => return Decision::allow(format!("learning: cannot hash: {}", e)),
+            (Err(e), _) | (_, Err(e)) => return Decision::deny(format!("cannot hash: {}", e)),
+        };
+        self.judge(request, &digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sha256 of "" and of "abc"
+    const EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn digest(hex: &str) -> Sha256Digest {
+        from_hex(hex).unwrap()
+    }
+
+    #[test]
+    fn parses_sha256sum_output() {
+        let text = format!("# trusted\n\n{}  /usr/bin/true\n{} */usr/bin/my tool\n", EMPTY, ABC.to_uppercase());
+        let db = TrustDb::parse(&text, Path::new("trust.db")).unwrap();
+        assert_eq!(db.len(), 2);
+        assert!(db.is_trusted(Path::new("/usr/bin/true"), &digest(EMPTY)));
+        assert!(db.is_trusted(Path::new("/usr/bin/my tool"), &digest(ABC)));
+        assert!(!db.is_trusted(Path::new("/usr/bin/true"), &digest(ABC)));
+        assert_eq!(to_hex(&digest(ABC)), ABC);
+    }
+
+    #[test]
+    fn a_path_may_have_several_digests() {
+        let text = format!("{}  /bin/sh\n{}  /bin/sh\n{}  /bin/sh\n", EMPTY, ABC, ABC);
+        let db = TrustDb::parse(&text, Path::new("trust.db")).unwrap();
+        assert_eq!(db.len(), 2);
+        assert!(db.is_trusted(Path::new("/bin/sh"), &digest(EMPTY)));
+        assert!(db.is_trusted(Path::new("/bin/sh"), &digest(ABC)));
+    }
+
+    #[test]
+    fn rejects_malformed_lines_with_their_number() {
+        let origin = Path::new("trust.db");
+        let relative = format!("{}  /ok\n{}  bin/sh\n", EMPTY, EMPTY);
+        assert!(TrustDb::parse(&relative, origin).unwrap_err().starts_with("trust.db:2:"));
+        assert!(TrustDb::parse(&format!("{}  /bin/sh", &EMPTY[1..]), origin).is_err());
+        assert!(TrustDb::parse(&format!("{}  /bin/sh", EMPTY.replace('e', "g")), origin).is_err());
+        assert!(TrustDb::parse(EMPTY, origin).is_err());
+        assert!(TrustDb::parse("", origin).unwrap().is_empty());
+    }
+}
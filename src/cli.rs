@@ -0,0 +1,200 @@
+// Command line parsing for the monitor.
+
+use std::path::PathBuf;
+
+use crate::mask::mask_from_list;
+use crate::sys::FAN_CLOSE_WRITE;
+
+pub const USAGE: &str = "\
+Usage: fanotify_demo [OPTIONS]
+       fanotify_demo run [OPTIONS] -- <COMMAND> [ARGS...]   (rerun COMMAND on changes, see run --help)
+       fanotify_demo inotifywait [OPTIONS] <FILE>...        (inotifywait-compatible output, also as a symlink named inotifywait)
+
+Options:
+  --config <PATH>         Load watches, filters and outputs from a TOML file (reloaded on SIGHUP)
+  --mount <PATH>          Also watch the whole mount containing PATH
+  --ignore <PATH>         Silence events for PATH (repeatable), e.g. databases and logs
+  --ignore-until-modify   Drop ignore marks on the first write instead of keeping them
+  --sticky <PATH>         Keep watching PATH by name when it is replaced by an atomic save (repeatable)
+  --wait-for <PATH>       Watch PATH even if it (or its directories) does not exist yet (repeatable)
+  --recursive <DIR>       Watch a whole directory tree with one inode mark per directory (repeatable)
+  --recursive-events <LIST>
+                          Comma-separated events reported inside --recursive trees [default: close_write]
+  --control-socket <PATH> Accept JSON-RPC commands (add-watch, remove-watch, ...) on PATH
+  --control-group <GROUP> Group allowed to change marks over the control socket (root always is)
+  --subscribe-socket <PATH>
+                          Stream filtered events as JSON lines to clients connecting to PATH
+  --coalesce <MS>         Merge events for the same object arriving within MS milliseconds
+  --coalesce-policy <LIST>
+                          Per-event policies, e.g. close_write=flush,modify=merge (merge | flush | pass)
+  --detect-saves          Report editor save sequences (temp+rename, vim backups, truncate+write) as one saved event
+  --ready <DIR>           Report files in DIR as ready once their last writer closed them (repeatable)
+  --ready-into <DIR>      Atomically move ready files into DIR (same filesystem) for processing
+  --incrontab <FILE>      Run the commands of an incrontab file when its events occur (repeatable)
+  --max-actions <N>       Commands run at once by action rules [default: 4]
+  --exec-inventory <FILE> Record every executable and library run (FAN_OPEN_EXEC) in FILE, kept across runs
+  --exec-watch <PATH>     Filesystem whose execs are recorded (repeatable) [default: /]
+  --exec-report           Print the inventory of --exec-inventory and exit
+  --fs-error <PATH>       Report errors of the filesystem holding PATH as critical (FAN_FS_ERROR, repeatable)
+  --on-fs-error <COMMAND> Shell command run on filesystem errors; $path is the mount point
+  --canary <DIR>          Plant decoy credential files in DIR and alert on any touch (repeatable)
+  --canary-file <PATH>    Use PATH as a decoy, created with fake contents if missing (repeatable)
+  --mount-events          Report mounts and unmounts in our mount namespace (Linux 6.15+)
+  --follow-mounts         Add --mount marks to mounts attached below the --mount paths (implies --mount-events)
+  --allowlist <DB>        Deny execs of files not listed with their SHA-256 in DB (sha256sum format)
+  --allowlist-learn <FILE>
+                          Learning mode: allow everything, append what would be denied to FILE
+  --allowlist-watch <PATH>
+                          Filesystem the allowlist applies to (repeatable) [default: /]
+  --allowlist-deadline <MS>
+                          Answer the --allowlist-fail verdict when a decision takes longer [default: 2000]
+  --allowlist-fail <open|closed>
+                          Verdict when the deadline passes [default: closed]
+  --protect <PATH>        Deny opens for writing of PATH and the files below it (repeatable)
+  --protect-allow <RULE>  Exception to --protect: exe:<path> or uid:<number> (repeatable)
+  --prompt <PATH>         Ask in the terminal before opens and execs below PATH are allowed (repeatable)
+  --prompt-rules <FILE>   Keep the always answers to --prompt in FILE
+  --prompt-timeout <SECS> Apply --prompt-default when nobody answers in time [default: 30]
+  --prompt-default <allow|deny>
+                          Verdict when the prompt times out [default: deny]
+  --hsm <DIR>             Fill placeholder files in DIR from --hsm-backing on first access (Linux 6.14+)
+  --hsm-backing <PATH>    Directory or uncompressed tar archive holding the real contents
+  --hsm-checkout          Create placeholders in --hsm DIR for backing files it does not have yet
+  --poll <PATH>           Poll watched paths below PATH with stat() (repeatable); NFS, FUSE and procfs are polled anyway
+  --poll-interval <MS>    Interval of the polling backend [default: 1000]
+  -h, --help              Print this help
+";
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub config: Option<PathBuf>,
+    pub mounts: Vec<PathBuf>,
+    pub ignores: Vec<PathBuf>,
+    pub ignore_surv_modify: bool,
+    pub sticky: Vec<PathBuf>,
+    pub wait_for: Vec<PathBuf>,
+    pub recursive: Vec<PathBuf>,
+    pub recursive_events: Option<String>,
+    pub control_socket: Option<PathBuf>,
+    pub control_group: Option<String>,
+    pub subscribe_socket: Option<PathBuf>,
+    pub coalesce_ms: Option<u64>,
+    pub coalesce_policies: Option<String>,
+    pub detect_saves: bool,
+    pub ready: Vec<PathBuf>,
+    pub ready_into: Option<PathBuf>,
+    pub incrontabs: Vec<PathBuf>,
+    pub max_actions: Option<usize>,
+    pub exec_inventory: Option<PathBuf>,
+    pub exec_watch: Vec<PathBuf>,
+    pub exec_report: bool,
+    pub fs_error: Vec<PathBuf>,
+    pub on_fs_error: Option<String>,
+    pub canary: Vec<PathBuf>,
+    pub canary_files: Vec<PathBuf>,
+    pub mount_events: bool,
+    pub follow_mounts: bool,
+    pub allowlist: Option<PathBuf>,
+    pub allowlist_learn: Option<PathBuf>,
+    pub allowlist_watch: Vec<PathBuf>,
+    pub allowlist_deadline_ms: Option<u64>,
+    pub allowlist_fail: Option<String>,
+    pub protect: Vec<PathBuf>,
+    pub protect_allow: Vec<String>,
+    pub prompt: Vec<PathBuf>,
+    pub prompt_rules: Option<PathBuf>,
+    pub prompt_timeout_secs: Option<u64>,
+    pub prompt_default: Option<String>,
+    pub hsm: Option<PathBuf>,
+    pub hsm_backing: Option<PathBuf>,
+    pub hsm_checkout: bool,
+    pub poll: Vec<PathBuf>,
+    pub poll_interval_ms: Option<u64>,
+    pub help: bool,
+}
+
+impl Options {
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut opts = Options { ignore_surv_modify: true, ..Default::default() };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => opts.config = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--mount" => opts.mounts.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--ignore" => opts.ignores.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--ignore-until-modify" => opts.ignore_surv_modify = false,
+                "--sticky" => opts.sticky.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--wait-for" => opts.wait_for.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--recursive" => opts.recursive.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--recursive-events" => opts.recursive_events = Some(value(&mut args, &arg)?),
+                "--control-socket" => opts.control_socket = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--control-group" => opts.control_group = Some(value(&mut args, &arg)?),
+                "--subscribe-socket" => opts.subscribe_socket = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--coalesce" => {
+                    let ms = value(&mut args, &arg)?;
+                    opts.coalesce_ms = Some(ms.parse().map_err(|_| format!("{}: not a number of milliseconds: {}", arg, ms))?);
+                }
+                "--coalesce-policy" => opts.coalesce_policies = Some(value(&mut args, &arg)?),
+                "--detect-saves" => opts.detect_saves = true,
+                "--ready" => opts.ready.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--ready-into" => opts.ready_into = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--incrontab" => opts.incrontabs.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--max-actions" => {
+                    let n = value(&mut args, &arg)?;
+                    opts.max_actions = Some(n.parse().ok().filter(|n| *n > 0).ok_or_else(|| format!("{}: not a positive number: {}", arg, n))?);
+                }
+                "--exec-inventory" => opts.exec_inventory = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--exec-watch" => opts.exec_watch.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--exec-report" => opts.exec_report = true,
+                "--fs-error" => opts.fs_error.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--on-fs-error" => opts.on_fs_error = Some(value(&mut args, &arg)?),
+                "--canary" => opts.canary.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--canary-file" => opts.canary_files.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--mount-events" => opts.mount_events = true,
+                "--follow-mounts" => opts.follow_mounts = true,
+                "--allowlist" => opts.allowlist = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--allowlist-learn" => opts.allowlist_learn = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--allowlist-watch" => opts.allowlist_watch.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--allowlist-deadline" => {
+                    let ms = value(&mut args, &arg)?;
+                    opts.allowlist_deadline_ms = Some(ms.parse().map_err(|_| format!("{}: not a number of milliseconds: {}", arg, ms))?);
+                }
+                "--allowlist-fail" => opts.allowlist_fail = Some(value(&mut args, &arg)?),
+                "--protect" => opts.protect.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--protect-allow" => opts.protect_allow.push(value(&mut args, &arg)?),
+                "--prompt" => opts.prompt.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--prompt-rules" => opts.prompt_rules = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--prompt-timeout" => {
+                    let secs = value(&mut args, &arg)?;
+                    opts.prompt_timeout_secs =
+                        Some(secs.parse().ok().filter(|s| *s > 0).ok_or_else(|| format!("{}: not a positive number of seconds: {}", arg, secs))?);
+                }
+                "--prompt-default" => opts.prompt_default = Some(value(&mut args, &arg)?),
+                "--hsm" => opts.hsm = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--hsm-backing" => opts.hsm_backing = Some(PathBuf::from(value(&mut args, &arg)?)),
+                "--hsm-checkout" => opts.hsm_checkout = true,
+                "--poll" => opts.poll.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--poll-interval" => {
+                    let ms = value(&mut args, &arg)?;
+                    opts.poll_interval_ms =
+                        Some(ms.parse().ok().filter(|ms| *ms > 0).ok_or_else(|| format!("{}: not a positive number of milliseconds: {}", arg, ms))?);
+                }
+                "-h" | "--help" => opts.help = true,
+                _ => return Err(format!("unknown argument: {}", arg)),
+            }
+        }
+        Ok(opts)
+    }
+
+    /// Events reported inside --recursive trees.
+    pub fn recursive_mask(&self) -> Result<u64, String> {
+        match &self.recursive_events {
+            Some(list) => mask_from_list(list),
+            None => Ok(FAN_CLOSE_WRITE),
+        }
+    }
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} requires a value", flag))
+}
@@ -0,0 +1,407 @@
+// Coalescing stage between parsing and output.
+//
+// Events for the same object that arrive within a window are merged into one
+// record carrying the union of their masks and how many raw events it stands
+// for. What each event type does to a pending record is a policy, and time
+// comes from a `Clock` so the stage behaves the same under a fake clock.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::event::{Event, FileHandle};
+use crate::mask::mask_from_name;
+use crate::sys::*;
+
+/// Source of monotonic time, as an offset from an arbitrary origin.
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+/// The real monotonic clock.
+pub struct MonotonicClock {
+    origin: Instant,
+}
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        MonotonicClock { origin: Instant::now() }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: Cell<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+
+    pub fn set(&self, now: Duration) {
+        self.now.set(now);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> Duration {
+        (**self).now()
+    }
+}
+
+/// What an event type does to the pending record of its object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CoalescePolicy {
+    /// Merge into the pending record and wait for the window to pass.
+    Merge,
+    /// Merge, then emit the record right away (e.g. a close ends a burst).
+    Flush,
+    /// Never merge: emit whatever is pending for the object, then the event on its own.
+    Pass,
+}
+
+impl CoalescePolicy {
+    pub fn from_name(name: &str) -> Result<CoalescePolicy, String> {
+        match name.trim() {
+            "merge" => Ok(CoalescePolicy::Merge),
+            "flush" => Ok(CoalescePolicy::Flush),
+            "pass" => Ok(CoalescePolicy::Pass),
+            other => Err(format!("unknown coalesce policy: {} (merge, flush, pass)", other)),
+        }
+    }
+}
+
+/// Parses `close_write=flush,modify=merge` style lists.
+pub fn policies_from_list(list: &str) -> Result<Vec<(u64, CoalescePolicy)>, String> {
+    list.split(',')
+        .filter(|item| !item.trim().is_empty())
+        .map(|item| {
+            let (name, policy) = item.split_once('=').ok_or_else(|| format!("expected EVENT=POLICY, got {}", item))?;
+            Ok((mask_from_name(name)?, CoalescePolicy::from_name(policy)?))
+        })
+        .collect()
+}
+
+/// Policies used for event types that are not configured explicitly.
+pub const DEFAULT_POLICIES: &[(u64, CoalescePolicy)] = &[
+    (FAN_CLOSE_WRITE, CoalescePolicy::Flush),
+    (FAN_CLOSE_NOWRITE, CoalescePolicy::Flush),
+    (FAN_CREATE, CoalescePolicy::Pass),
+    (FAN_DELETE, CoalescePolicy::Pass),
+    (FAN_MOVED_FROM, CoalescePolicy::Pass),
+    (FAN_MOVED_TO, CoalescePolicy::Pass),
+    (FAN_DELETE_SELF, CoalescePolicy::Pass),
+    (FAN_MOVE_SELF, CoalescePolicy::Pass),
+];
+
+#[derive(Debug, Clone)]
+pub struct CoalesceConfig {
+    /// A record is emitted once no event for its object arrived for this long.
+    pub window: Duration,
+    /// ... or at the latest this long after its first event, so a steady
+    /// stream of writes still shows up.
+    pub max_delay: Duration,
+    /// Per event bit; bits not listed use [`DEFAULT_POLICIES`], then `Merge`.
+    pub policies: Vec<(u64, CoalescePolicy)>,
+}
+
+impl CoalesceConfig {
+    pub fn new(window: Duration) -> Self {
+        CoalesceConfig { window, max_delay: window * 10, policies: Vec::new() }
+    }
+
+    /// The strongest policy of any bit in `mask` (Pass > Flush > Merge).
+    pub fn policy_for(&self, mask: u64) -> CoalescePolicy {
+        let mut policy = CoalescePolicy::Merge;
+        for bit in (0..64).map(|i| 1u64 << i).filter(|bit| mask & bit != 0) {
+            let configured = self.policies.iter().chain(DEFAULT_POLICIES).find(|(b, _)| *b == bit);
+            if let Some((_, p)) = configured {
+                policy = policy.max(*p);
+            }
+        }
+        policy
+    }
+}
+
+/// One or more raw events for the same object.
+#[derive(Debug)]
+pub struct Coalesced {
+    /// The first event, with `mask` widened to the union of all merged events.
+    pub event: Event,
+    pub count: u32,
+    /// Clock readings of the first and last merged event.
+    pub first: Duration,
+    pub last: Duration,
+    /// Every pid that contributed, in order of first appearance.
+    pub pids: Vec<i32>,
+}
+
+impl Coalesced {
+    /// A record standing for exactly one event.
+    pub fn single(event: Event, at: Duration) -> Self {
+        let pids = vec![event.pid];
+        Coalesced { event, count: 1, first: at, last: at, pids }
+    }
+
+    fn merge(&mut self, event: &Event, at: Duration) {
+        self.event.mask |= event.mask;
+        self.count += 1;
+        self.last = at;
+        if !self.pids.contains(&event.pid) {
+            self.pids.push(event.pid);
+        }
+        if self.event.path.is_none() {
+            self.event.path.clone_from(&event.path);
+        }
+    }
+
+    /// [`Event::to_json`] plus the merge information.
+    pub fn to_json(&self) -> Value {
+        let mut json = self.event.to_json();
+        json["count"] = self.count.into();
+        json["pids"] = self.pids.clone().into();
+        json["span_ms"] = ((self.last - self.first).as_millis() as u64).into();
+        json
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ObjectKey {
+    Handle(FileHandle),
+    Entry(FileHandle, OsString),
+    Path(PathBuf),
+    Unknown(i32),
+}
+
+impl ObjectKey {
+    fn of(event: &Event) -> ObjectKey {
+        if let Some(fid) = &event.fid {
+            ObjectKey::Handle(fid.clone())
+        } else if let (Some(dir), Some(name)) = (&event.dir_fid, &event.name) {
+            ObjectKey::Entry(dir.clone(), name.clone())
+        } else if let Some(path) = &event.path {
+            ObjectKey::Path(path.clone())
+        } else {
+            ObjectKey::Unknown(event.pid)
+        }
+    }
+}
+
+pub struct Coalescer<C: Clock = MonotonicClock> {
+    config: CoalesceConfig,
+    clock: C,
+    pending: HashMap<ObjectKey, Coalesced>,
+}
+
+impl<C: Clock> Coalescer<C> {
+    pub fn new(config: CoalesceConfig, clock: C) -> Self {
+        Coalescer { config, clock, pending: HashMap::new() }
+    }
+
+    pub fn config(&self) -> &CoalesceConfig {
+        &self.config
+    }
+
+    /// Number of objects with a record waiting for its window.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Feeds one raw event; returns the records that are complete because of it.
+    pub fn push(&mut self, event: Event) -> Vec<Coalesced> {
+        let now = self.clock.now();
+        let key = ObjectKey::of(&event);
+        match self.config.policy_for(event.mask) {
+            CoalescePolicy::Pass => {
+                let mut ready: Vec<Coalesced> = self.pending.remove(&key).into_iter().collect();
+                ready.push(Coalesced::single(event, now));
+                ready
+            }
+            policy => {
+                match self.pending.get_mut(&key) {
+                    Some(record) => record.merge(&event, now),
+                    None => {
+                        self.pending.insert(key.clone(), Coalesced::single(event, now));
+                    }
+                }
+                match policy {
+                    CoalescePolicy::Flush => self.pending.remove(&key).into_iter().collect(),
+                    _ => Vec::new(),
+                }
+            }
+        }
+    }
+
+    /// Records whose window has passed, oldest first.
+    pub fn expire(&mut self) -> Vec<Coalesced> {
+        let now = self.clock.now();
+        let due: Vec<ObjectKey> = self
+            .pending
+            .iter()
+            .filter(|(_, r)| now >= r.last + self.config.window || now >= r.first + self.config.max_delay)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut ready: Vec<Coalesced> = due.into_iter().filter_map(|key| self.pending.remove(&key)).collect();
+        ready.sort_by_key(|r| r.first);
+        ready
+    }
+
+    /// Everything still pending, oldest first (e.g. on shutdown).
+    pub fn drain(&mut self) -> Vec<Coalesced> {
+        let mut ready: Vec<Coalesced> = self.pending.drain().map(|(_, r)| r).collect();
+        ready.sort_by_key(|r| r.first);
+        ready
+    }
+
+    /// Time until the next record expires, for use as a poll() timeout.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        let now = self.clock.now();
+        self.pending
+            .values()
+            .map(|r| (r.last + self.config.window).min(r.first + self.config.max_delay))
+            .min()
+            .map(|deadline| deadline.saturating_sub(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    const WINDOW: Duration = Duration::from_millis(100);
+
+    fn event(mask: u64, pid: i32, path: &str) -> Event {
+        Event { mask, pid, path: Some(PathBuf::from(path)), ..Event::default() }
+    }
+
+    #[test]
+    fn merges_within_the_window() {
+        let clock = ManualClock::new();
+        let mut coalescer = Coalescer::new(CoalesceConfig::new(WINDOW), &clock);
+        assert!(coalescer.push(event(FAN_OPEN, 1, "/a")).is_empty());
+        clock.advance(WINDOW / 2);
+        assert!(coalescer.push(event(FAN_MODIFY, 2, "/a")).is_empty());
+        assert!(coalescer.push(event(FAN_MODIFY, 1, "/b")).is_empty());
+        assert_eq!(coalescer.pending(), 2);
+
+        clock.advance(WINDOW / 2);
+        assert!(coalescer.expire().is_empty(), "/a was touched half a window ago");
+        clock.advance(WINDOW / 2);
+        let ready = coalescer.expire();
+        assert_eq!(ready.len(), 2);
+        assert_eq!(ready[0].event.path.as_deref(), Some(Path::new("/a")));
+        assert_eq!(ready[0].event.mask, FAN_OPEN | FAN_MODIFY);
+        assert_eq!(ready[0].count, 2);
+        assert_eq!(ready[0].pids, vec![1, 2]);
+        assert_eq!(ready[0].last - ready[0].first, WINDOW / 2);
+        assert_eq!(coalescer.pending(), 0);
+    }
+
+    #[test]
+    fn flushes_at_the_max_delay() {
+        let clock = ManualClock::new();
+        let mut config = CoalesceConfig::new(WINDOW);
+        config.max_delay = WINDOW * 3;
+        let mut coalescer = Coalescer::new(config, &clock);
+        for _ in 0..3 {
+            coalescer.push(event(FAN_MODIFY, 1, "/a"));
+            clock.advance(WINDOW - Duration::from_millis(1));
+            assert!(coalescer.expire().is_empty());
+        }
+        clock.set(WINDOW * 3);
+        let ready = coalescer.expire();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].count, 3);
+    }
+
+    #[test]
+    fn pass_emits_pending_record_then_the_event() {
+        let clock = ManualClock::new();
+        let mut coalescer = Coalescer::new(CoalesceConfig::new(WINDOW), &clock);
+        coalescer.push(event(FAN_MODIFY, 1, "/a"));
+        coalescer.push(event(FAN_MODIFY, 1, "/a"));
+        let ready = coalescer.push(event(FAN_DELETE_SELF, 1, "/a"));
+        assert_eq!(ready.len(), 2);
+        assert_eq!((ready[0].event.mask, ready[0].count), (FAN_MODIFY, 2));
+        assert_eq!((ready[1].event.mask, ready[1].count), (FAN_DELETE_SELF, 1));
+        assert_eq!(coalescer.pending(), 0);
+    }
+
+    #[test]
+    fn flush_merges_then_emits() {
+        let clock = ManualClock::new();
+        let mut coalescer = Coalescer::new(CoalesceConfig::new(WINDOW), &clock);
+        coalescer.push(event(FAN_OPEN, 1, "/a"));
+        coalescer.push(event(FAN_MODIFY, 1, "/a"));
+        let ready = coalescer.push(event(FAN_CLOSE_WRITE, 1, "/a"));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].event.mask, FAN_OPEN | FAN_MODIFY | FAN_CLOSE_WRITE);
+        assert_eq!(ready[0].count, 3);
+    }
+
+    #[test]
+    fn configured_policy_wins_over_the_default() {
+        let mut config = CoalesceConfig::new(WINDOW);
+        config.policies = policies_from_list("close_write=merge,modify=pass").unwrap();
+        assert_eq!(config.policy_for(FAN_CLOSE_WRITE), CoalescePolicy::Merge);
+        assert_eq!(config.policy_for(FAN_MODIFY), CoalescePolicy::Pass);
+        assert_eq!(config.policy_for(FAN_OPEN | FAN_CLOSE_NOWRITE), CoalescePolicy::Flush);
+        assert!(policies_from_list("modify").is_err());
+        assert!(policies_from_list("modify=later").is_err());
+    }
+
+    #[test]
+    fn next_deadline_is_the_earliest_expiry() {
+        let clock = ManualClock::new();
+        let mut config = CoalesceConfig::new(WINDOW);
+        config.max_delay = WINDOW * 2;
+        let mut coalescer = Coalescer::new(config, &clock);
+        assert_eq!(coalescer.next_deadline(), None);
+        coalescer.push(event(FAN_MODIFY, 1, "/a"));
+        clock.advance(WINDOW / 4);
+        coalescer.push(event(FAN_MODIFY, 1, "/b"));
+        assert_eq!(coalescer.next_deadline(), Some(WINDOW * 3 / 4));
+        // Keeping /a busy moves its window: /b is due first now
+        clock.advance(WINDOW / 2);
+        coalescer.push(event(FAN_MODIFY, 1, "/a"));
+        assert_eq!(coalescer.next_deadline(), Some(WINDOW / 2));
+        clock.set(WINDOW * 3 / 2);
+        assert_eq!(coalescer.expire().len(), 1);
+        // ... but not past max_delay
+        coalescer.push(event(FAN_MODIFY, 1, "/a"));
+        assert_eq!(coalescer.next_deadline(), Some(WINDOW / 2));
+        clock.set(WINDOW * 2);
+        assert_eq!(coalescer.next_deadline(), Some(Duration::ZERO));
+    }
+}
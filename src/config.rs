@@ -0,0 +1,515 @@
+// TOML configuration file and the mark diffing used to hot-reload it.
+//
+// Example:
+//
+//   [[watch]]
+//   path = "/srv"
+//   type = "mount"                 # inode | mount | filesystem
+//   events = ["open", "modify", "close_write"]
+//
+//   [[ignore]]
+//   path = "/srv/app.sqlite"       # events default to everything watched
+//
+//   [filter]
+//   events = ["modify", "close_write"]
+//   path_prefix = "/srv"
+//
+//   [output]
+//   console = true
+//   control_socket = "/run/fanotify_demo.sock"
+//   subscribe_socket = "/run/fanotify_demo-events.sock"
+//
+//   [enrich]
+//   file_status = true
+//   process = true
+//
+//   [coalesce]
+//   window_ms = 200                # merge events per object; omit to disable
+//   max_delay_ms = 2000            # defaults to 10 windows
+//   policies = { close_write = "flush", create = "pass" }
+//
+//   [saves]
+//   enabled = true                 # one "saved" event per editor save
+//   window_ms = 500
+//
+//   [actions]
+//   max_running = 4                # commands running at once, the rest wait
+//   timeout_ms = 60000
+//   env = ["PATH", "HOME"]         # variables commands inherit
+//
+//   [[action]]
+//   path = "/srv/uploads/*.csv"    # glob; events come from the [[watch]] marks
+//   events = ["close_write"]
+//   command = ["/usr/local/bin/ingest", "$path"]   # also $name $dir $mask $pid
+//
+//   [[action]]
+//   incrontab = "/etc/incron.d/uploads"   # marks the paths it lists itself
+//
+//   [poll]
+//   interval_ms = 1000             # stat() polling for NFS, FUSE, procfs, ...
+//   paths = ["/mnt/share"]         # poll these too, whatever their filesystem
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::action::{parse_incrontab, ActionCommand, ActionRule, ActionSettings, Pattern};
+use crate::coalesce::{CoalesceConfig, CoalescePolicy};
+use crate::filter::EventFilter;
+use crate::mark::{IgnoreMark, MarkObject, MarkSet};
+use crate::mask::{mark_type_from_name, mark_type_name, mask_from_name, mask_names};
+use crate::sys::*;
+
+fn yes() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, rename = "watch")]
+    pub watches: Vec<WatchConfig>,
+    #[serde(default, rename = "ignore")]
+    pub ignores: Vec<IgnoreConfig>,
+    #[serde(default)]
+    pub filter: FilterConfig,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub enrich: EnrichConfig,
+    #[serde(default)]
+    pub coalesce: CoalesceSection,
+    #[serde(default)]
+    pub saves: SavesConfig,
+    #[serde(default)]
+    pub actions: ActionsSection,
+    #[serde(default, rename = "action")]
+    pub action_rules: Vec<ActionConfig>,
+    #[serde(default)]
+    pub poll: PollSection,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchConfig {
+    pub path: PathBuf,
+    #[serde(default, rename = "type")]
+    pub mark_type: String,
+    pub events: Vec<String>,
+    #[serde(default)]
+    pub onlydir: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IgnoreConfig {
+    pub path: PathBuf,
+    #[serde(default, rename = "type")]
+    pub mark_type: String,
+    /// Empty means every event any watch asks for.
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default = "yes")]
+    pub surv_modify: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterConfig {
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default)]
+    pub path_prefix: Option<PathBuf>,
+    #[serde(default)]
+    pub pid: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputConfig {
+    /// Print the per-event blocks on stdout.
+    #[serde(default = "yes")]
+    pub console: bool,
+    #[serde(default)]
+    pub control_socket: Option<PathBuf>,
+    #[serde(default)]
+    pub control_group: Option<String>,
+    #[serde(default)]
+    pub subscribe_socket: Option<PathBuf>,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig { console: true, control_socket: None, control_group: None, subscribe_socket: None }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnrichConfig {
+    /// stat() the file after each event and print size, mode and mtime.
+    #[serde(default = "yes")]
+    pub file_status: bool,
+    /// Add the command name and executable of the reporting pid.
+    #[serde(default)]
+    pub process: bool,
+}
+
+impl Default for EnrichConfig {
+    fn default() -> Self {
+        EnrichConfig { file_status: true, process: false }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CoalesceSection {
+    #[serde(default)]
+    pub window_ms: Option<u64>,
+    #[serde(default)]
+    pub max_delay_ms: Option<u64>,
+    /// Event name to merge | flush | pass.
+    #[serde(default)]
+    pub policies: BTreeMap<String, CoalescePolicy>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SavesConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub window_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ActionsSection {
+    #[serde(default)]
+    pub max_running: Option<usize>,
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub env: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PollSection {
+    #[serde(default)]
+    pub interval_ms: Option<u64>,
+    /// Watched paths below these are polled even on local filesystems.
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
+}
+
+/// Either a glob rule or an incrontab to import.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ActionConfig {
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default)]
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub incrontab: Option<PathBuf>,
+}
+
+/// One mark the configuration asks for, with names resolved to bits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarkSpec {
+    pub path: PathBuf,
+    pub mark_type: u32,
+    pub mask: u64,
+    /// Extra fanotify_mark() flags (`FAN_MARK_ONLYDIR`).
+    pub flags: u32,
+    /// `Some(surv_modify)` for ignore marks.
+    pub ignore: Option<bool>,
+}
+
+impl MarkSpec {
+    fn key(&self) -> (PathBuf, u32, bool) {
+        (self.path.clone(), self.mark_type, self.ignore.is_some())
+    }
+}
+
+impl std::fmt::Display for MarkSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = if self.ignore.is_some() { "ignore" } else { "watch" };
+        write!(f, "{} {} [{}] {}", kind, self.path.display(), mark_type_name(self.mark_type), mask_names(self.mask).join(","))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MarkChange {
+    Add(MarkSpec),
+    Remove(MarkSpec),
+    Update { old: MarkSpec, new: MarkSpec },
+}
+
+impl MarkChange {
+    fn inverse(&self) -> MarkChange {
+        match self {
+            MarkChange::Add(spec) => MarkChange::Remove(spec.clone()),
+            MarkChange::Remove(spec) => MarkChange::Add(spec.clone()),
+            MarkChange::Update { old, new } => MarkChange::Update { old: new.clone(), new: old.clone() },
+        }
+    }
+}
+
+impl std::fmt::Display for MarkChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MarkChange::Add(spec) => write!(f, "+ {}", spec),
+            MarkChange::Remove(spec) => write!(f, "- {}", spec),
+            MarkChange::Update { old, new } => write!(f, "~ {} -> {}", old, mask_names(new.mask).join(",")),
+        }
+    }
+}
+
+fn names_to_mask(names: &[String]) -> Result<u64, String> {
+    names.iter().try_fold(0, |mask, name| Ok(mask | mask_from_name(name)?))
+}
+
+impl Config {
+    /// Reads and fully validates a configuration file.
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let config: Config = toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        config.mark_specs()?;
+        config.filter()?;
+        config.coalesce()?;
+        config.actions()?;
+        Ok(config)
+    }
+
+    /// Resolves watches and ignores, checking names and that every path can be marked.
+    pub fn mark_specs(&self) -> Result<Vec<MarkSpec>, String> {
+        let mut specs = Vec::new();
+        for watch in &self.watches {
+            let mark_type = mark_type_from_name(&watch.mark_type)?;
+            let mask = names_to_mask(&watch.events)?;
+            if mask == 0 {
+                return Err(format!("watch {}: events must not be empty", watch.path.display()));
+            }
+            MarkObject::of(&watch.path, mark_type, false).map_err(|e| format!("watch {}: {}", watch.path.display(), e))?;
+            let flags = if watch.onlydir { FAN_MARK_ONLYDIR } else { 0 };
+            specs.push(MarkSpec { path: watch.path.clone(), mark_type, mask, flags, ignore: None });
+        }
+        let watched = specs.iter().fold(0, |mask, spec| mask | spec.mask);
+        for ignore in &self.ignores {
+            let mark_type = mark_type_from_name(&ignore.mark_type)?;
+            let mask = match names_to_mask(&ignore.events)? {
+                0 => watched,
+                mask => mask,
+            };
+            MarkObject::of(&ignore.path, mark_type, false).map_err(|e| format!("ignore {}: {}", ignore.path.display(), e))?;
+            specs.push(MarkSpec { path: ignore.path.clone(), mark_type, mask, flags: 0, ignore: Some(ignore.surv_modify) });
+        }
+        let mut seen = HashSet::new();
+        for spec in &specs {
+            if !seen.insert(spec.key()) {
+                return Err(format!("{} is configured twice", spec));
+            }
+        }
+        Ok(specs)
+    }
+
+    pub fn filter(&self) -> Result<EventFilter, String> {
+        Ok(EventFilter {
+            mask: names_to_mask(&self.filter.events)?,
+            path_prefix: self.filter.path_prefix.clone(),
+            pid: self.filter.pid,
+        })
+    }
+
+    /// The coalescing stage, if `[coalesce]` sets a window.
+    pub fn coalesce(&self) -> Result<Option<CoalesceConfig>, String> {
+        let section = &self.coalesce;
+        let policies = section
+            .policies
+            .iter()
+            .map(|(name, policy)| Ok((mask_from_name(name)?, *policy)))
+            .collect::<Result<Vec<_>, String>>()?;
+        let Some(window_ms) = section.window_ms else {
+            if section.max_delay_ms.is_some() || !policies.is_empty() {
+                return Err("[coalesce] needs window_ms".to_string());
+            }
+            return Ok(None);
+        };
+        let mut coalesce = CoalesceConfig::new(Duration::from_millis(window_ms));
+        if let Some(max_delay_ms) = section.max_delay_ms {
+            coalesce.max_delay = Duration::from_millis(max_delay_ms);
+        }
+        coalesce.policies = policies;
+        Ok(Some(coalesce))
+    }
+
+    /// Action rules in file order (incrontabs are read here) and the runner settings.
+    pub fn actions(&self) -> Result<(Vec<ActionRule>, ActionSettings), String> {
+        let mut rules = Vec::new();
+        for (i, action) in self.action_rules.iter().enumerate() {
+            let source = format!("action #{}", i + 1);
+            match (&action.incrontab, &action.path) {
+                (Some(_), Some(_)) => return Err(format!("{}: set either incrontab or path, not both", source)),
+                (Some(table), None) => {
+                    if !action.events.is_empty() || !action.command.is_empty() {
+                        return Err(format!("{}: events and command come from the incrontab", source));
+                    }
+                    let text = std::fs::read_to_string(table).map_err(|e| format!("{}: {}", table.display(), e))?;
+                    rules.extend(parse_incrontab(&text, table)?);
+                }
+                (None, Some(path)) => {
+                    let mask = names_to_mask(&action.events)?;
+                    if mask == 0 || action.command.is_empty() {
+                        return Err(format!("{}: needs events and a command", source));
+                    }
+                    rules.push(ActionRule {
+                        source,
+                        pattern: Pattern::Glob(path.clone()),
+                        mask,
+                        command: ActionCommand::Argv(action.command.clone()),
+                    });
+                }
+                (None, None) => return Err(format!("{}: needs a path or an incrontab", source)),
+            }
+        }
+        let mut settings = ActionSettings::default();
+        let section = &self.actions;
+        if let Some(max_running) = section.max_running {
+            if max_running == 0 {
+                return Err("[actions] max_running must be at least 1".to_string());
+            }
+            settings.max_running = max_running;
+        }
+        if let Some(timeout_ms) = section.timeout_ms {
+            settings.timeout = Duration::from_millis(timeout_ms);
+        }
+        if let Some(env) = &section.env {
+            settings.env.clone_from(env);
+        }
+        Ok((rules, settings))
+    }
+}
+
+/// Mark changes that turn `old` into `new`.
+pub fn diff(old: &[MarkSpec], new: &[MarkSpec]) -> Vec<MarkChange> {
+    let old_by_key: HashMap<_, _> = old.iter().map(|spec| (spec.key(), spec)).collect();
+    let new_by_key: HashMap<_, _> = new.iter().map(|spec| (spec.key(), spec)).collect();
+    let mut changes = Vec::new();
+    // Removals first so an object that changes type never holds two marks at once
+    for spec in old {
+        if !new_by_key.contains_key(&spec.key()) {
+            changes.push(MarkChange::Remove(spec.clone()));
+        }
+    }
+    for spec in new {
+        match old_by_key.get(&spec.key()) {
+            None => changes.push(MarkChange::Add(spec.clone())),
+            Some(old) if *old != spec => changes.push(MarkChange::Update { old: (*old).clone(), new: spec.clone() }),
+            Some(_) => {}
+        }
+    }
+    changes
+}
+
+/// Applies `changes` in order. If one fails, the ones already applied are
+/// undone so the group keeps the previous configuration.
+pub fn apply(marks: &mut MarkSet, changes: &[MarkChange]) -> Result<(), String> {
+    for (i, change) in changes.iter().enumerate() {
+        if let Err(e) = apply_one(marks, change) {
+            for done in changes[..i].iter().rev() {
+                if let Err(undo) = apply_one(marks, &done.inverse()) {
+                    eprintln!("✗ Failed to roll back {}: {}", done, undo);
+                }
+            }
+            return Err(format!("{}: {}", change, e));
+        }
+    }
+    Ok(())
+}
+
+fn ignore_mark(spec: &MarkSpec) -> IgnoreMark {
+    IgnoreMark { mark_type: spec.mark_type, surv_modify: spec.ignore.unwrap_or(true), ..IgnoreMark::new(spec.mask) }
+}
+
+fn apply_one(marks: &mut MarkSet, change: &MarkChange) -> std::io::Result<()> {
+    let result = match change {
+        MarkChange::Add(spec) if spec.ignore.is_some() => marks.ignore(&spec.path, &ignore_mark(spec)).map(|_| ()),
+        MarkChange::Add(spec) => marks.add(&spec.path, spec.mark_type, spec.mask, spec.flags),
+        MarkChange::Remove(spec) if spec.ignore.is_some() => marks.unignore(&spec.path, &ignore_mark(spec)),
+        // Releases this config's reference only: bits a command-line, sticky or
+        // control-socket watch also asked for stay installed
+        MarkChange::Remove(spec) => marks.remove(&spec.path, spec.mark_type, spec.mask),
+        MarkChange::Update { old, new } if old.ignore.is_some() || old.flags != new.flags => {
+            apply_one(marks, &MarkChange::Remove(old.clone()))?;
+            apply_one(marks, &MarkChange::Add(new.clone()))
+        }
+        MarkChange::Update { old, new } => {
+            // Only touch the bits this config owns; MarkSet keeps the ones other watches hold
+            if new.mask & !old.mask != 0 {
+                marks.add(&new.path, new.mark_type, new.mask & !old.mask, new.flags)?;
+            }
+            if old.mask & !new.mask != 0 {
+                marks.remove(&new.path, new.mark_type, old.mask & !new.mask)?;
+            }
+            Ok(())
+        }
+    };
+    match (change, result) {
+        // The object is already gone, and so is its mark
+        (MarkChange::Remove(_), Err(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        (_, result) => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(path: &str, mask: u64) -> MarkSpec {
+        MarkSpec { path: PathBuf::from(path), mark_type: FAN_MARK_INODE, mask, flags: 0, ignore: None }
+    }
+
+    #[test]
+    fn diff_of_equal_configs_is_empty() {
+        let specs = vec![spec("/a", FAN_OPEN), spec("/b", FAN_MODIFY)];
+        assert!(diff(&specs, &specs).is_empty());
+    }
+
+    #[test]
+    fn diff_removes_before_adding() {
+        let old = vec![spec("/a", FAN_OPEN), spec("/b", FAN_MODIFY)];
+        let new = vec![spec("/c", FAN_OPEN), spec("/b", FAN_MODIFY | FAN_CLOSE_WRITE)];
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                MarkChange::Remove(spec("/a", FAN_OPEN)),
+                MarkChange::Add(spec("/c", FAN_OPEN)),
+                MarkChange::Update { old: spec("/b", FAN_MODIFY), new: spec("/b", FAN_MODIFY | FAN_CLOSE_WRITE) },
+            ]
+        );
+    }
+
+    #[test]
+    fn ignore_and_watch_on_one_path_are_separate() {
+        let old = vec![spec("/a", FAN_OPEN)];
+        let ignore = MarkSpec { ignore: Some(true), ..spec("/a", FAN_OPEN) };
+        let new = vec![spec("/a", FAN_OPEN), ignore.clone()];
+        assert_eq!(diff(&old, &new), vec![MarkChange::Add(ignore)]);
+    }
+
+    #[test]
+    fn inverse_undoes_each_change() {
+        let (a, b) = (spec("/a", FAN_OPEN), spec("/a", FAN_MODIFY));
+        assert_eq!(MarkChange::Add(a.clone()).inverse(), MarkChange::Remove(a.clone()));
+        assert_eq!(MarkChange::Remove(a.clone()).inverse(), MarkChange::Add(a.clone()));
+        let update = MarkChange::Update { old: a.clone(), new: b.clone() };
+        assert_eq!(update.inverse(), MarkChange::Update { old: b, new: a });
+        assert_eq!(update.inverse().inverse(), update);
+    }
+}
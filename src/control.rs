@@ -0,0 +1,341 @@
+// Runtime control socket: line-delimited JSON-RPC 2.0 over a Unix socket.
+//
+// Methods:
+//   add-watch     {"path", "type"?, "events", "onlydir"?, "ignore"?}
+//   remove-watch  {"path", "type"?, "events"?}   (no events = every event bit plus the ignore mask)
+//   list-watches  {}
+//   stats         {}
+//   set-filters   {"events"?, "path_prefix"?, "pid"?}
+//
+// Mutating methods are only accepted from root or members of the configured
+// admin group, checked through SO_PEERCRED.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::filter::EventFilter;
+use crate::mark::{IgnoreMark, MarkObject, MarkSet};
+use crate::mask::{mark_type_from_name, mark_type_name, mask_from_list, mask_from_name, mask_names};
+use crate::sys::*;
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const OS_ERROR: i64 = -32000;
+const PERMISSION_DENIED: i64 = -32001;
+
+/// Counters updated by the event loop.
+pub struct Stats {
+    pub reads: AtomicU64,
+    pub events: AtomicU64,
+    pub filtered: AtomicU64,
+    started: Instant,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            reads: AtomicU64::new(0),
+            events: AtomicU64::new(0),
+            filtered: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Value {
+        json!({
+            "reads": self.reads.load(Ordering::Relaxed),
+            "events": self.events.load(Ordering::Relaxed),
+            "filtered": self.filtered.load(Ordering::Relaxed),
+            "uptime_secs": self.started.elapsed().as_secs(),
+        })
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// State shared between the event loop and control clients.
+pub struct ControlState {
+    pub marks: Mutex<MarkSet>,
+    pub filter: Mutex<EventFilter>,
+    pub stats: Stats,
+}
+
+impl ControlState {
+    pub fn new(marks: MarkSet) -> Self {
+        ControlState { marks: Mutex::new(marks), filter: Mutex::new(EventFilter::default()), stats: Stats::new() }
+    }
+}
+
+pub struct ControlServer {
+    path: PathBuf,
+    listener: UnixListener,
+    state: Arc<ControlState>,
+    admin_gid: Option<libc::gid_t>,
+}
+
+impl ControlServer {
+    /// Binds the socket, replacing a stale socket file left by a previous run.
+    pub fn bind(path: &Path, state: Arc<ControlState>, admin_gid: Option<libc::gid_t>) -> io::Result<Self> {
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "path exists and is not a socket"));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        // Only root and the admin group may connect at all
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))?;
+        if let Some(gid) = admin_gid {
+            std::os::unix::fs::chown(path, None, Some(gid))?;
+        }
+        Ok(ControlServer { path: path.to_path_buf(), listener, state, admin_gid })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Serves clients on a background thread, one thread per connection.
+    pub fn spawn(self) -> io::Result<thread::JoinHandle<()>> {
+        thread::Builder::new().name("control".into()).spawn(move || {
+            for stream in self.listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("✗ control socket accept failed: {}", e);
+                        continue;
+                    }
+                };
+                let state = Arc::clone(&self.state);
+                let admin_gid = self.admin_gid;
+                let _ = thread::Builder::new().name("control-client".into()).spawn(move || {
+                    if let Err(e) = serve_client(stream, &state, admin_gid) {
+                        println!("DEBUG: control client error: {}", e);
+                    }
+                });
+            }
+        })
+    }
+}
+
+/// Looks up a group by name or numeric id.
+pub fn resolve_group(group: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = std::ffi::CString::new(group).map_err(|_| format!("invalid group name: {}", group))?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("unknown group: {}", group));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+/// Credentials of the process on the other end of `stream`.
+pub fn peer_credentials(stream: &UnixStream) -> io::Result<libc::ucred> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::from_raw_os_error(get_errno()));
+    }
+    Ok(cred)
+}
+
+fn is_admin(cred: &libc::ucred, admin_gid: Option<libc::gid_t>) -> bool {
+    if cred.uid == 0 {
+        return true;
+    }
+    let Some(gid) = admin_gid else {
+        return false;
+    };
+    if cred.gid == gid {
+        return true;
+    }
+    // Supplementary groups of the peer
+    std::fs::read_to_string(format!("/proc/{}/status", cred.pid))
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Groups:"))
+                .map(|groups| groups.split_whitespace().any(|g| g.parse() == Ok(gid)))
+        })
+        .unwrap_or(false)
+}
+
+fn serve_client(stream: UnixStream, state: &ControlState, admin_gid: Option<libc::gid_t>) -> io::Result<()> {
+    let cred = peer_credentials(&stream)?;
+    let admin = is_admin(&cred, admin_gid);
+    println!("DEBUG: control client connected: pid={} uid={} gid={} admin={}", cred.pid, cred.uid, cred.gid, admin);
+
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = handle_line(&line, state, admin);
+        writeln!(writer, "{}", response)?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+struct RpcError(i64, String);
+
+impl From<io::Error> for RpcError {
+    fn from(e: io::Error) -> Self {
+        RpcError(OS_ERROR, e.to_string())
+    }
+}
+
+fn invalid(message: impl Into<String>) -> RpcError {
+    RpcError(INVALID_PARAMS, message.into())
+}
+
+/// Handles one request line and returns the serialized response.
+pub fn handle_line(line: &str, state: &ControlState, admin: bool) -> Value {
+    let request: Request = match serde_json::from_str::<Value>(line) {
+        Err(e) => return error_response(Value::Null, RpcError(PARSE_ERROR, e.to_string())),
+        Ok(value) => match serde_json::from_value(value) {
+            Ok(request) => request,
+            Err(e) => return error_response(Value::Null, RpcError(INVALID_REQUEST, e.to_string())),
+        },
+    };
+    match dispatch(&request, state, admin) {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": request.id, "result": result }),
+        Err(e) => error_response(request.id, e),
+    }
+}
+
+fn error_response(id: Value, RpcError(code, message): RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn dispatch(request: &Request, state: &ControlState, admin: bool) -> Result<Value, RpcError> {
+    let params = &request.params;
+    let mutating = matches!(request.method.as_str(), "add-watch" | "remove-watch" | "set-filters");
+    if mutating && !admin {
+        return Err(RpcError(PERMISSION_DENIED, format!("{} requires root or the admin group", request.method)));
+    }
+    match request.method.as_str() {
+        "add-watch" => {
+            let path = param_path(params)?;
+            let mark_type = param_mark_type(params)?;
+            let mask = parse_events(params.get("events").unwrap_or(&Value::Null))?;
+            if mask == 0 {
+                return Err(invalid("events must not be empty"));
+            }
+            let mut marks = state.marks.lock().unwrap();
+            if params.get("ignore").and_then(Value::as_bool).unwrap_or(false) {
+                let mode = marks.ignore(&path, &IgnoreMark { mark_type, ..IgnoreMark::new(mask) })?;
+                return Ok(json!({ "path": path, "ignore": mode.name() }));
+            }
+            let onlydir = params.get("onlydir").and_then(Value::as_bool).unwrap_or(false);
+            marks.add(&path, mark_type, mask, if onlydir { FAN_MARK_ONLYDIR } else { 0 })?;
+            Ok(json!({ "path": path, "type": mark_type_name(mark_type), "events": mask_names(mask) }))
+        }
+        "remove-watch" => {
+            let path = param_path(params)?;
+            let mark_type = param_mark_type(params)?;
+            let mut marks = state.marks.lock().unwrap();
+            match params.get("events") {
+                Some(events) => marks.remove(&path, mark_type, parse_events(events)?)?,
+                None => marks.remove_object(&MarkObject::of(&path, mark_type, false)?)?,
+            }
+            Ok(json!({ "path": path, "removed": true }))
+        }
+        "list-watches" => {
+            let marks = state.marks.lock().unwrap();
+            let watches: Vec<Value> = marks
+                .marks()
+                .into_iter()
+                .map(|entry| {
+                    json!({
+                        "path": entry.path,
+                        "type": mark_type_name(entry.object.mark_type()),
+                        "object": entry.object.to_string(),
+                        "events": mask_names(entry.mask),
+                        "ignored": mask_names(entry.ignored_mask),
+                    })
+                })
+                .collect();
+            let mismatches: Vec<String> = marks.verify()?.iter().map(ToString::to_string).collect();
+            Ok(json!({ "watches": watches, "mismatches": mismatches }))
+        }
+        "stats" => {
+            let mut stats = state.stats.snapshot();
+            stats["marks"] = json!(state.marks.lock().unwrap().len());
+            Ok(stats)
+        }
+        "set-filters" => {
+            let filter = EventFilter {
+                mask: parse_events(params.get("events").unwrap_or(&Value::Null))?,
+                path_prefix: params.get("path_prefix").and_then(Value::as_str).map(PathBuf::from),
+                pid: match params.get("pid") {
+                    None | Some(Value::Null) => None,
+                    Some(pid) => Some(pid.as_i64().ok_or_else(|| invalid("pid must be a number"))? as i32),
+                },
+            };
+            *state.filter.lock().unwrap() = filter.clone();
+            Ok(serde_json::to_value(filter).unwrap_or(Value::Null))
+        }
+        other => Err(RpcError(METHOD_NOT_FOUND, format!("unknown method: {}", other))),
+    }
+}
+
+fn param_path(params: &Value) -> Result<PathBuf, RpcError> {
+    params.get("path").and_then(Value::as_str).map(PathBuf::from).ok_or_else(|| invalid("missing path"))
+}
+
+fn param_mark_type(params: &Value) -> Result<u32, RpcError> {
+    mark_type_from_name(params.get("type").and_then(Value::as_str).unwrap_or("inode")).map_err(invalid)
+}
+
+/// Accepts `["open", "modify"]`, `"open,modify"` or a raw numeric mask.
+fn parse_events(events: &Value) -> Result<u64, RpcError> {
+    match events {
+        Value::Null => Ok(0),
+        Value::Number(n) => n.as_u64().ok_or_else(|| invalid("mask must be a positive integer")),
+        Value::String(list) => mask_from_list(list).map_err(invalid),
+        Value::Array(names) => names.iter().try_fold(0, |mask, name| {
+            let name = name.as_str().ok_or_else(|| invalid("event names must be strings"))?;
+            Ok(mask | mask_from_name(name).map_err(invalid)?)
+        }),
+        _ => Err(invalid("events must be a list, a string or a number")),
+    }
+}
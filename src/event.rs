@@ -0,0 +1,320 @@
+// Parsing of fanotify read() buffers into owned events, and path resolution
+// for FID-mode events that carry file handles instead of file descriptors.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use crate::mark::path_cstring;
+use crate::mask::mask_names;
+use crate::mounts::Filesystem;
+use crate::sys::*;
+
+/// Filesystem id as reported by statfs(2) and in FID info records.
+pub type Fsid = [i32; 2];
+
+/// A `struct file_handle` together with the filesystem it belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileHandle {
+    pub fsid: Fsid,
+    pub handle_type: i32,
+    pub bytes: Vec<u8>,
+}
+
+impl FileHandle {
+    // Layout after the info header: fsid[2], handle_bytes, handle_type, f_handle[]
+    fn parse(record: &[u8]) -> Option<(FileHandle, &[u8])> {
+        let int = |at: usize| record.get(at..at + 4).map(|b| i32::from_ne_bytes(b.try_into().unwrap()));
+        let fsid = [int(0)?, int(4)?];
+        let handle_bytes = int(8)? as usize;
+        let handle_type = int(12)?;
+        let bytes = record.get(16..16 + handle_bytes)?.to_vec();
+        Some((FileHandle { fsid, handle_type, bytes }, &record[16 + handle_bytes..]))
+    }
+
+    /// The handle of `path` as FID events report it (symlinks not followed).
+    pub fn of(path: &Path) -> io::Result<FileHandle> {
+        const MAX_HANDLE_SZ: usize = 128;
+        let path_cstr = path_cstring(path)?;
+        let mut raw = vec![0u8; 8 + MAX_HANDLE_SZ];
+        raw[..4].copy_from_slice(&(MAX_HANDLE_SZ as u32).to_ne_bytes());
+        let mut mount_id = 0;
+        if unsafe { name_to_handle_at(AT_FDCWD, path_cstr.as_ptr(), raw.as_mut_ptr(), &mut mount_id, 0) } == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        let handle_bytes = u32::from_ne_bytes(raw[..4].try_into().unwrap()) as usize;
+        let handle_type = i32::from_ne_bytes(raw[4..8].try_into().unwrap());
+        Ok(FileHandle { fsid: fsid_of(path)?, handle_type, bytes: raw[8..8 + handle_bytes].to_vec() })
+    }
+
+    /// Opens the object with open_by_handle_at(2). Needs CAP_DAC_READ_SEARCH.
+    pub fn open(&self, mount_fd: libc::c_int, flags: libc::c_int) -> io::Result<OwnedFd> {
+        let mut raw = Vec::with_capacity(8 + self.bytes.len());
+        raw.extend_from_slice(&(self.bytes.len() as u32).to_ne_bytes());
+        raw.extend_from_slice(&self.handle_type.to_ne_bytes());
+        raw.extend_from_slice(&self.bytes);
+        let fd = unsafe { open_by_handle_at(mount_fd, raw.as_ptr(), flags | libc::O_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+/// One event from the fanotify queue with its info records decoded.
+#[derive(Debug, Default)]
+pub struct Event {
+    pub mask: u64,
+    pub pid: i32,
+    /// Open file descriptor (non-FID groups and permission events); closed on drop.
+    pub fd: Option<OwnedFd>,
+    /// Handle of the object itself (`FAN_EVENT_INFO_TYPE_FID`).
+    pub fid: Option<FileHandle>,
+    /// Handle of the parent directory (`FAN_EVENT_INFO_TYPE_DFID[_NAME]`).
+    pub dir_fid: Option<FileHandle>,
+    /// Entry name within `dir_fid` (`FAN_EVENT_INFO_TYPE_DFID_NAME`).
+    pub name: Option<OsString>,
+    /// Best-effort absolute path, filled in by [`PathResolver::resolve`].
+    pub path: Option<PathBuf>,
+    /// Bytes about to be accessed (`FAN_EVENT_INFO_TYPE_RANGE`): (offset, count).
+    pub range: Option<(u64, u64)>,
+    /// `FAN_FS_ERROR` record: (errno, number of errors merged into the event).
+    pub error: Option<(i32, u32)>,
+    /// Unique id of the mount attached or detached (`FAN_EVENT_INFO_TYPE_MNT`).
+    pub mnt_id: Option<u64>,
+    /// Filesystem and mount the event happened on, from [`crate::mounts::MountRegistry::attach`].
+    pub fs: Option<Arc<Filesystem>>,
+}
+
+impl Event {
+    pub fn raw_fd(&self) -> i32 {
+        self.fd.as_ref().map_or(FAN_NOFD, |fd| fd.as_raw_fd())
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mask & FAN_ONDIR != 0
+    }
+
+    /// JSON representation used by remote subscribers.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "mask": self.mask,
+            "events": mask_names(self.mask),
+            "pid": self.pid,
+            "path": self.path,
+            "name": self.name.as_ref().map(|n| n.to_string_lossy()),
+            "fs": self.fs.as_ref().map(|fs| fs.to_json(self.path.as_deref())),
+        })
+    }
+}
+
+/// Splits the bytes returned by read(2) into events. Malformed trailing data
+/// is ignored, as the kernel never splits an event across reads.
+pub fn parse_events(buf: &[u8]) -> Vec<Event> {
+    let mut events = Vec::new();
+    let mut offset = 0;
+    while offset + mem::size_of::<FanotifyEventMetadata>() <= buf.len() {
+        let metadata: FanotifyEventMetadata =
+            unsafe { std::ptr::read_unaligned(buf.as_ptr().add(offset) as *const FanotifyEventMetadata) };
+        let event_len = metadata.event_len as usize;
+        if event_len < mem::size_of::<FanotifyEventMetadata>() || offset + event_len > buf.len() {
+            break;
+        }
+        let mut event = Event {
+            mask: metadata.mask,
+            pid: metadata.pid,
+            fd: (metadata.fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(metadata.fd) }),
+            fid: None,
+            dir_fid: None,
+            name: None,
+            path: None,
+            range: None,
+            error: None,
+            mnt_id: None,
+            fs: None,
+        };
+        if metadata.vers == FANOTIFY_METADATA_VERSION {
+            let records = &buf[offset + metadata.metadata_len as usize..offset + event_len];
+            parse_info_records(records, &mut event);
+        }
+        events.push(event);
+        offset += event_len;
+    }
+    events
+}
+
+fn parse_info_records(mut records: &[u8], event: &mut Event) {
+    while records.len() >= mem::size_of::<FanotifyEventInfoHeader>() {
+        let header: FanotifyEventInfoHeader =
+            unsafe { std::ptr::read_unaligned(records.as_ptr() as *const FanotifyEventInfoHeader) };
+        let len = header.len as usize;
+        if len < mem::size_of::<FanotifyEventInfoHeader>() || len > records.len() {
+            return;
+        }
+        let body = &records[mem::size_of::<FanotifyEventInfoHeader>()..len];
+        match header.info_type {
+            FAN_EVENT_INFO_TYPE_FID => event.fid = FileHandle::parse(body).map(|(h, _)| h),
+            FAN_EVENT_INFO_TYPE_DFID => event.dir_fid = FileHandle::parse(body).map(|(h, _)| h),
+            FAN_EVENT_INFO_TYPE_DFID_NAME => {
+                if let Some((handle, rest)) = FileHandle::parse(body) {
+                    let name = rest.split(|b| *b == 0).next().unwrap_or_default();
+                    // "." means the event is about the directory itself
+                    if !name.is_empty() && name != b"." {
+                        event.name = Some(OsString::from_vec(name.to_vec()));
+                    }
+                    event.dir_fid = Some(handle);
+                }
+            }
+            FAN_EVENT_INFO_TYPE_ERROR if len >= mem::size_of::<FanotifyEventInfoError>() => {
+                let error: FanotifyEventInfoError = unsafe { std::ptr::read_unaligned(records.as_ptr() as *const FanotifyEventInfoError) };
+                event.error = Some((error.error, error.error_count));
+            }
+            FAN_EVENT_INFO_TYPE_RANGE if len >= mem::size_of::<FanotifyEventInfoRange>() => {
+                let range: FanotifyEventInfoRange = unsafe { std::ptr::read_unaligned(records.as_ptr() as *const FanotifyEventInfoRange) };
+                event.range = Some((range.offset, range.count));
+            }
+            FAN_EVENT_INFO_TYPE_MNT if len >= mem::size_of::<FanotifyEventInfoMnt>() => {
+                let mnt: FanotifyEventInfoMnt = unsafe { std::ptr::read_unaligned(records.as_ptr() as *const FanotifyEventInfoMnt) };
+                event.mnt_id = Some(mnt.mnt_id);
+            }
+            _ => {}
+        }
+        records = &records[len..];
+    }
+}
+
+/// statfs(2) filesystem id of `path`.
+pub fn fsid_of(path: &Path) -> io::Result<Fsid> {
+    let path_cstr = path_cstring(path)?;
+    let mut buf: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statfs(path_cstr.as_ptr(), &mut buf) } == -1 {
+        return Err(io::Error::from_raw_os_error(get_errno()));
+    }
+    Ok(unsafe { mem::transmute::<libc::fsid_t, Fsid>(buf.f_fsid) })
+}
+
+/// fstatfs(2) filesystem id of an open file descriptor.
+pub fn fsid_of_fd(fd: libc::c_int) -> io::Result<Fsid> {
+    let mut buf: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::fstatfs(fd, &mut buf) } == -1 {
+        return Err(io::Error::from_raw_os_error(get_errno()));
+    }
+    Ok(unsafe { mem::transmute::<libc::fsid_t, Fsid>(buf.f_fsid) })
+}
+
+/// Path of an open file descriptor via /proc/self/fd.
+pub fn fd_path(fd: libc::c_int) -> io::Result<PathBuf> {
+    std::fs::read_link(format!("/proc/self/fd/{}", fd))
+}
+
+// An open directory of a filesystem, for open_by_handle_at()
+struct MountFd {
+    fd: OwnedFd,
+    /// Set when `fd` is on a detached clone of the mount: paths read back
+    /// start at the clone's root and belong below this mount point.
+    mount_point: Option<PathBuf>,
+}
+
+/// Turns file handles back into paths using one open fd per known filesystem.
+#[derive(Default)]
+pub struct PathResolver {
+    mounts: HashMap<Fsid, MountFd>,
+}
+
+fn open_dir(dirfd: libc::c_int, path: &Path) -> io::Result<OwnedFd> {
+    let path_cstr = path_cstring(path)?;
+    let fd = unsafe { libc::openat(dirfd, path_cstr.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC) };
+    if fd == -1 {
+        return Err(io::Error::from_raw_os_error(get_errno()));
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+impl PathResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remembers the filesystem of `path` so its handles can be opened.
+    pub fn register(&mut self, path: &Path) -> io::Result<Fsid> {
+        let fsid = fsid_of(path)?;
+        if let std::collections::hash_map::Entry::Vacant(entry) = self.mounts.entry(fsid) {
+            // open_by_handle_at() rejects O_PATH descriptors, so open a directory for real
+            let dir = if path.is_dir() { path } else { path.parent().unwrap_or(path) };
+            entry.insert(MountFd { fd: open_dir(AT_FDCWD, dir)?, mount_point: None });
+        }
+        Ok(fsid)
+    }
+
+    /// Like [`PathResolver::register`] for the mount at `mount_point`, but
+    /// through a detached clone of it, so the fd does not keep the mount busy
+    /// (it keeps the filesystem alive until [`PathResolver::unregister`]).
+    /// Returns the fsid if it was not registered yet.
+    pub fn register_detached(&mut self, mount_point: &Path) -> io::Result<Option<Fsid>> {
+        let fsid = fsid_of(mount_point)?;
+        let std::collections::hash_map::Entry::Vacant(entry) = self.mounts.entry(fsid) else {
+            return Ok(None);
+        };
+        let path_cstr = path_cstring(mount_point)?;
+        let tree = unsafe { open_tree(AT_FDCWD, path_cstr.as_ptr(), OPEN_TREE_CLONE | OPEN_TREE_CLOEXEC) };
+        if tree == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        let tree = unsafe { OwnedFd::from_raw_fd(tree) };
+        // The clone lives on as long as a descriptor refers to it
+        let fd = open_dir(tree.as_raw_fd(), Path::new("."))?;
+        entry.insert(MountFd { fd, mount_point: Some(mount_point.to_path_buf()) });
+        Ok(Some(fsid))
+    }
+
+    pub fn unregister(&mut self, fsid: &Fsid) {
+        self.mounts.remove(fsid);
+    }
+
+    /// Opens the object behind `handle` if its filesystem is registered.
+    pub fn open(&self, handle: &FileHandle, flags: libc::c_int) -> io::Result<OwnedFd> {
+        let mount = self
+            .mounts
+            .get(&handle.fsid)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "filesystem not registered"))?;
+        handle.open(mount.fd.as_raw_fd(), flags)
+    }
+
+    pub fn handle_path(&self, handle: &FileHandle) -> Option<PathBuf> {
+        let fd = self.open(handle, libc::O_PATH).ok()?;
+        let path = fd_path(fd.as_raw_fd()).ok()?;
+        match self.mounts.get(&handle.fsid).and_then(|m| m.mount_point.as_ref()) {
+            Some(mount_point) => Some(mount_point.join(path.strip_prefix("/").unwrap_or(&path)).components().collect()),
+            None => Some(path),
+        }
+    }
+
+    /// Fills in `event.path` from its fd, its parent handle plus name, or its own handle.
+    pub fn resolve(&self, event: &mut Event) {
+        // Opening the handle would read the damaged inode again and raise the next error
+        if event.mask & FAN_FS_ERROR != 0 {
+            event.path = None;
+            return;
+        }
+        event.path = if let Some(fd) = &event.fd {
+            fd_path(fd.as_raw_fd()).ok()
+        } else if let (Some(dir), Some(name)) = (&event.dir_fid, &event.name) {
+            self.handle_path(dir).map(|dir| dir.join(name))
+        } else if let Some(handle) = event.fid.as_ref().or(event.dir_fid.as_ref()) {
+            self.handle_path(handle)
+        } else {
+            None
+        };
+        // Unlinked objects resolve to "/path (deleted)"
+        if let Some(stripped) = event.path.as_ref().and_then(|p| p.as_os_str().as_bytes().strip_suffix(b" (deleted)")) {
+            event.path = Some(PathBuf::from(std::ffi::OsStr::from_bytes(stripped)));
+        }
+    }
+}
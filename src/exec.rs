@@ -0,0 +1,278 @@
+// Exec inventory: every executable and shared library that was run, built
+// from FAN_OPEN_EXEC events and saved between runs, to tell which binaries
+// on a host are actually used.
+//
+// FAN_OPEN_EXEC covers what the kernel opens for exec: the program and its
+// ELF interpreter. Libraries are mapped later by ld.so with plain opens, so
+// they are taken from the executable mappings in /proc/<pid>/maps, once when
+// the event is read and again a moment later for what ld.so mapped since.
+// A process that exits before either read leaves its libraries out, and
+// libraries dlopen()ed later in a process's life are not seen at all.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::fs::File;
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::event::{Event, PathResolver};
+use crate::sys::*;
+
+/// Unsaved changes are written out at most this long after they happened.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+/// Time ld.so gets to map libraries before /proc/<pid>/maps is read again.
+const MAPS_DELAY: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecKind {
+    /// Opened for exec by the kernel (programs, interpreters).
+    Executable,
+    /// Mapped executable by a running program.
+    Library,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExecEntry {
+    pub path: PathBuf,
+    pub dev: u64,
+    pub ino: u64,
+    pub kind: ExecKind,
+    /// Seconds since the epoch.
+    pub first_seen: i64,
+    pub last_seen: i64,
+    /// Real uids of the processes that ran it.
+    pub uids: BTreeSet<u32>,
+    pub count: u64,
+}
+
+impl ExecEntry {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "event": "exec",
+            "mask": FAN_OPEN_EXEC,
+            "path": self.path,
+            "dev": self.dev,
+            "ino": self.ino,
+            "kind": self.kind,
+            "first_seen": self.first_seen,
+            "last_seen": self.last_seen,
+            "count": self.count,
+            "uids": self.uids,
+        })
+    }
+}
+
+impl std::fmt::Display for ExecEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            ExecKind::Executable => "executable",
+            ExecKind::Library => "library",
+        };
+        let uids: Vec<String> = self.uids.iter().map(u32::to_string).collect();
+        write!(
+            f,
+            "{} ({}, inode {}) run {}x by uid(s) [{}], first {}, last {}",
+            self.path.display(),
+            kind,
+            self.ino,
+            self.count,
+            uids.join(","),
+            format_local_time(self.first_seen, "%Y-%m-%d %H:%M:%S"),
+            format_local_time(self.last_seen, "%Y-%m-%d %H:%M:%S")
+        )
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct InventoryFile {
+    entries: Vec<ExecEntry>,
+}
+
+/// Persisted set of executables keyed by path and inode, so a rebuilt binary
+/// is a new entry.
+pub struct ExecInventory {
+    file: PathBuf,
+    entries: BTreeMap<(PathBuf, u64, u64), ExecEntry>,
+    /// Execs whose libraries are still to be read: (due, pid, executable).
+    maps_due: Vec<(Instant, i32, PathBuf)>,
+    dirty_since: Option<Instant>,
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Real uid from /proc/<pid>/status, if the process still exists.
+pub(crate) fn real_uid(pid: i32) -> Option<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status.lines().find_map(|line| line.strip_prefix("Uid:"))?.split_whitespace().next()?.parse().ok()
+}
+
+// File-backed executable mappings: (path, dev, ino)
+fn executable_mappings(pid: i32) -> Vec<(PathBuf, u64, u64)> {
+    let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid)).unwrap_or_default();
+    let mut mappings = BTreeSet::new();
+    for line in maps.lines() {
+        // address perms offset dev inode path
+        let fields: Vec<&str> = line.splitn(6, char::is_whitespace).collect();
+        let [_, perms, _, dev, ino, path] = fields[..] else {
+            continue;
+        };
+        let path = path.trim_start();
+        if !perms.contains('x') || !path.starts_with('/') || path.ends_with(" (deleted)") {
+            continue;
+        }
+        let Some((major, minor)) = dev.split_once(':') else {
+            continue;
+        };
+        let (Ok(major), Ok(minor), Ok(ino)) = (u32::from_str_radix(major, 16), u32::from_str_radix(minor, 16), ino.parse()) else {
+            continue;
+        };
+        mappings.insert((PathBuf::from(path), libc::makedev(major, minor), ino));
+    }
+    mappings.into_iter().collect()
+}
+
+// (dev, ino) of what the event is about, from its fd or its file handle
+fn event_inode(event: &Event, resolver: &PathResolver) -> Option<(u64, u64)> {
+    let opened;
+    let fd = match (&event.fd, &event.fid) {
+        (Some(fd), _) => fd.as_raw_fd(),
+        (None, Some(fid)) => {
+            opened = resolver.open(fid, libc::O_PATH).ok()?;
+            opened.as_raw_fd()
+        }
+        (None, None) => return None,
+    };
+    // Borrowed: the descriptor is owned by the event or by `opened`
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let meta = file.metadata().ok()?;
+    Some((meta.dev(), meta.ino()))
+}
+
+impl ExecInventory {
+    /// Loads `file`, or starts empty when it does not exist yet.
+    pub fn load(file: &Path) -> io::Result<Self> {
+        let saved: InventoryFile = match std::fs::read(file) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => InventoryFile::default(),
+            Err(e) => return Err(e),
+        };
+        let entries = saved.entries.into_iter().map(|e| ((e.path.clone(), e.dev, e.ino), e)).collect();
+        Ok(ExecInventory { file: file.to_path_buf(), entries, maps_due: Vec::new(), dirty_since: None })
+    }
+
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entries by path.
+    pub fn entries(&self) -> impl Iterator<Item = &ExecEntry> {
+        self.entries.values()
+    }
+
+    // Counts one sighting; returns the entry when it is new
+    fn see(&mut self, path: PathBuf, dev: u64, ino: u64, kind: ExecKind, uid: Option<u32>) -> Option<ExecEntry> {
+        let seen = now();
+        self.dirty_since.get_or_insert_with(Instant::now);
+        let key = (path.clone(), dev, ino);
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.last_seen = seen;
+            entry.count += 1;
+            entry.uids.extend(uid);
+            if kind == ExecKind::Executable {
+                entry.kind = kind;
+            }
+            return None;
+        }
+        let entry = ExecEntry { path, dev, ino, kind, first_seen: seen, last_seen: seen, uids: uid.into_iter().collect(), count: 1 };
+        self.entries.insert(key, entry.clone());
+        Some(entry)
+    }
+
+    /// Records a FAN_OPEN_EXEC event and the libraries the process has
+    /// mapped so far; returns the entries that are new.
+    pub fn record(&mut self, event: &Event, resolver: &PathResolver) -> Vec<ExecEntry> {
+        if event.mask & FAN_OPEN_EXEC == 0 {
+            return Vec::new();
+        }
+        let (Some(path), Some((dev, ino))) = (event.path.clone(), event_inode(event, resolver)) else {
+            return Vec::new();
+        };
+        // pid 0: the kernel hid it from an unprivileged group
+        let uid = (event.pid > 0).then(|| real_uid(event.pid)).flatten();
+        let mut new: Vec<ExecEntry> = self.see(path.clone(), dev, ino, ExecKind::Executable, uid).into_iter().collect();
+        if event.pid > 0 {
+            new.extend(self.libraries(event.pid, &path));
+            self.maps_due.push((Instant::now() + MAPS_DELAY, event.pid, path));
+        }
+        new
+    }
+
+    /// Reads the libraries of execs whose delay passed; returns new entries.
+    pub fn poll(&mut self) -> Vec<ExecEntry> {
+        let now = Instant::now();
+        let (due, waiting): (Vec<_>, Vec<_>) = self.maps_due.drain(..).partition(|(at, _, _)| *at <= now);
+        self.maps_due = waiting;
+        due.into_iter().flat_map(|(_, pid, exe)| self.libraries(pid, &exe)).collect()
+    }
+
+    // Executable mappings of `pid` other than `exe`, if it is still running it
+    fn libraries(&mut self, pid: i32, exe: &Path) -> Vec<ExecEntry> {
+        // Gone, or already running something else
+        if std::fs::read_link(format!("/proc/{}/exe", pid)).ok().as_deref() != Some(exe) {
+            return Vec::new();
+        }
+        let uid = real_uid(pid);
+        let mut new = Vec::new();
+        for (path, dev, ino) in executable_mappings(pid) {
+            if path != exe {
+                new.extend(self.see(path, dev, ino, ExecKind::Library, uid));
+            }
+        }
+        new
+    }
+
+    /// When `poll` or `save_if_due` next has work.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let maps = self.maps_due.iter().map(|(at, _, _)| *at);
+        let save = self.dirty_since.map(|since| since + SAVE_INTERVAL);
+        maps.chain(save).min().map(|at| at.saturating_duration_since(now))
+    }
+
+    /// Saves when changes are older than `SAVE_INTERVAL`.
+    pub fn save_if_due(&mut self) -> io::Result<bool> {
+        match self.dirty_since {
+            Some(since) if since.elapsed() >= SAVE_INTERVAL => self.save().map(|()| true),
+            _ => Ok(false),
+        }
+    }
+
+    /// Writes the inventory atomically (temporary file, then rename).
+    pub fn save(&mut self) -> io::Result<()> {
+        let saved = InventoryFile { entries: self.entries.values().cloned().collect() };
+        let json = serde_json::to_vec_pretty(&saved).map_err(io::Error::other)?;
+        let mut tmp = self.file.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, &self.file)?;
+        self.dirty_since = None;
+        Ok(())
+    }
+}
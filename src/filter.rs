@@ -0,0 +1,39 @@
+// Event filters shared by the console output and remote clients.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Selects events by type, path and process. Empty fields match everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventFilter {
+    /// Only events with at least one of these bits (0 = all).
+    #[serde(default)]
+    pub mask: u64,
+    /// Only events whose resolved path lies under this prefix.
+    #[serde(default)]
+    pub path_prefix: Option<PathBuf>,
+    /// Only events generated by this pid.
+    #[serde(default)]
+    pub pid: Option<i32>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, mask: u64, pid: i32, path: Option<&Path>) -> bool {
+        if self.mask != 0 && mask & self.mask == 0 {
+            return false;
+        }
+        if self.pid.is_some_and(|p| p != pid) {
+            return false;
+        }
+        match (&self.path_prefix, path) {
+            (None, _) => true,
+            (Some(prefix), Some(path)) => path.starts_with(prefix),
+            (Some(_), None) => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == EventFilter::default()
+    }
+}
@@ -0,0 +1,122 @@
+// Filesystem errors (FAN_FS_ERROR, Linux 5.16+): the filesystem reports
+// corruption the moment it finds it (ext4 and xfs call it from their error
+// paths), not at the next fsck. Only filesystem marks of an FID group get
+// these events; each carries the error code, how many errors were merged
+// into it, and the FID of the inode involved if there was one.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::event::{fsid_of, Event, Fsid};
+use crate::mark::MarkSet;
+use crate::mounts::{mount_of, MountInfo};
+use crate::sys::*;
+
+// FID handle types whose first four bytes are the inode number
+const FILEID_INO32_GEN: i32 = 1;
+const FILEID_INO32_GEN_PARENT: i32 = 2;
+
+/// A filesystem watched for errors.
+#[derive(Debug, Clone)]
+pub struct ErrorWatch {
+    pub path: PathBuf,
+    pub fsid: Fsid,
+    pub mount: MountInfo,
+}
+
+/// One FAN_FS_ERROR event, with the filesystem it happened on.
+#[derive(Debug, Clone)]
+pub struct FsErrorReport {
+    /// Positive errno, e.g. EUCLEAN for corruption, EIO.
+    pub error: i32,
+    /// Errors merged into this event since the previous one was read.
+    pub count: u32,
+    pub mount: Option<MountInfo>,
+    /// Inode involved; its path is not looked up, which would read it again.
+    pub inode: Option<u64>,
+    pub pid: i32,
+}
+
+impl FsErrorReport {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "event": "fs_error",
+            "severity": "critical",
+            "mask": FAN_FS_ERROR,
+            "error": self.error,
+            "error_text": io::Error::from_raw_os_error(self.error).to_string(),
+            "count": self.count,
+            "mount_point": self.mount.as_ref().map(|m| &m.mount_point),
+            "device": self.mount.as_ref().map(|m| &m.source),
+            "dev": self.mount.as_ref().map(MountInfo::device_number),
+            "fstype": self.mount.as_ref().map(|m| &m.fstype),
+            "inode": self.inode,
+        })
+    }
+}
+
+impl std::fmt::Display for FsErrorReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CRITICAL: {}", io::Error::from_raw_os_error(self.error))?;
+        if self.count > 1 {
+            write!(f, " x{}", self.count)?;
+        }
+        match &self.mount {
+            Some(mount) => write!(
+                f,
+                " on {} {} ({}) mounted at {}",
+                mount.fstype,
+                mount.source,
+                mount.device_number(),
+                mount.mount_point.display()
+            )?,
+            None => f.write_str(" on an unknown filesystem")?,
+        }
+        if let Some(inode) = self.inode {
+            write!(f, ", inode {}", inode)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct FsErrorMonitor {
+    watches: Vec<ErrorWatch>,
+}
+
+impl FsErrorMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    /// Adds a FAN_FS_ERROR filesystem mark for the filesystem holding `path`.
+    pub fn watch(&mut self, marks: &mut MarkSet, path: &Path) -> io::Result<&ErrorWatch> {
+        marks.add(path, FAN_MARK_FILESYSTEM, FAN_FS_ERROR, 0)?;
+        let watch = ErrorWatch { path: path.to_path_buf(), fsid: fsid_of(path)?, mount: mount_of(path)? };
+        self.watches.push(watch);
+        Ok(self.watches.last().expect("just pushed"))
+    }
+
+    /// Builds the report for a FAN_FS_ERROR event and gives the event the
+    /// mount point as its path, so action rules can match it.
+    pub fn report(&self, event: &mut Event) -> Option<FsErrorReport> {
+        if event.mask & FAN_FS_ERROR == 0 {
+            return None;
+        }
+        let (error, count) = event.error.unwrap_or((0, 1));
+        let fid = event.fid.as_ref();
+        let mount = fid.and_then(|fid| self.watches.iter().find(|w| w.fsid == fid.fsid)).map(|w| w.mount.clone());
+        let inode = fid
+            .filter(|fid| matches!(fid.handle_type, FILEID_INO32_GEN | FILEID_INO32_GEN_PARENT))
+            .and_then(|fid| fid.bytes.get(..4))
+            .map(|ino| u32::from_ne_bytes(ino.try_into().unwrap()) as u64);
+        event.path = mount.as_ref().map(|m| m.mount_point.clone());
+        Some(FsErrorReport { error, count, mount, inode, pid: event.pid })
+    }
+}
@@ -0,0 +1,307 @@
+// Hierarchical storage management on pre-content events (Linux 6.14+):
+// placeholder files are filled in from a backing store the first time they
+// are accessed, so a large artifact tree can be checked out in no time and
+// only what is used gets copied.
+//
+// A placeholder is a file of the right size, holes only, carrying the
+// `user.fanotify_demo.placeholder` xattr with its path in the backing store.
+// Each placeholder gets an inode mark for FAN_PRE_ACCESS; the access waits
+// while the whole file is written through the event fd (which raises no
+// permission events), then the xattr is removed and an ignore mark that
+// survives modification keeps the ordinary file it became quiet.
+//
+// The backing store is a directory or an uncompressed tar archive. Pre-content
+// events need filesystem support (ext4, xfs, btrfs; not tmpfs).
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::mark::path_cstring;
+use crate::perm::{Decision, PermissionGroup, Policy, Request};
+use crate::sys::*;
+
+pub const PLACEHOLDER_XATTR: &str = "user.fanotify_demo.placeholder";
+/// How long an access may wait for its file to be filled in.
+pub const FILL_DEADLINE: Duration = Duration::from_secs(60);
+
+/// A regular file of the backing store.
+#[derive(Debug, Clone)]
+pub struct BackingFile {
+    /// Relative to the backing store root.
+    pub path: PathBuf,
+    pub size: u64,
+    pub mode: u32,
+    /// Seconds since the epoch.
+    pub mtime: u64,
+    /// Where the data starts in a tar archive.
+    offset: u64,
+}
+
+pub enum Backing {
+    Dir(PathBuf),
+    Tar { archive: PathBuf, files: HashMap<PathBuf, BackingFile> },
+}
+
+// Relative, and without `..`: nothing outside the checkout
+fn is_safe(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) && path.components().next().is_some()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Backing {
+    /// A directory, or a tar archive whose regular files are indexed.
+    pub fn open(path: &Path) -> io::Result<Backing> {
+        if std::fs::metadata(path)?.is_dir() {
+            return Ok(Backing::Dir(path.to_path_buf()));
+        }
+        let mut archive = tar::Archive::new(File::open(path)?);
+        let mut files = HashMap::new();
+        for entry in archive.entries_with_seek()? {
+            let entry = entry?;
+            let header = entry.header();
+            if !header.entry_type().is_file() {
+                continue;
+            }
+            let name = entry.path()?.components().filter(|c| *c != Component::CurDir).collect::<PathBuf>();
+            if !is_safe(&name) {
+                return Err(invalid(format!("{}: unsafe path in archive: {}", path.display(), name.display())));
+            }
+            let file = BackingFile {
+                path: name.clone(),
+                size: entry.size(),
+                mode: header.mode()?,
+                mtime: header.mtime()?,
+                offset: entry.raw_file_position(),
+            };
+            files.insert(name, file);
+        }
+        Ok(Backing::Tar { archive: path.to_path_buf(), files })
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Backing::Dir(dir) => format!("directory {}", dir.display()),
+            Backing::Tar { archive, files } => format!("archive {} ({} files)", archive.display(), files.len()),
+        }
+    }
+
+    /// Every regular file, for a checkout.
+    pub fn files(&self) -> io::Result<Vec<BackingFile>> {
+        let root = match self {
+            Backing::Tar { files, .. } => return Ok(files.values().cloned().collect()),
+            Backing::Dir(root) => root,
+        };
+        let mut files = Vec::new();
+        let mut dirs = vec![root.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    dirs.push(entry.path());
+                } else if file_type.is_file() {
+                    let meta = entry.metadata()?;
+                    let path = entry.path().strip_prefix(root).expect("walked below the root").to_path_buf();
+                    let mtime = meta.modified()?.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                    files.push(BackingFile { path, size: meta.len(), mode: meta.permissions().mode(), mtime, offset: 0 });
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Copies the backing file `path` into `dst` from its start; returns its size.
+    fn copy(&self, path: &Path, dst: &File) -> io::Result<u64> {
+        if !is_safe(path) {
+            return Err(invalid(format!("unsafe placeholder source: {}", path.display())));
+        }
+        let (src, offset, size) = match self {
+            Backing::Dir(root) => {
+                let src = File::open(root.join(path))?;
+                let size = src.metadata()?.len();
+                (src, 0, size)
+            }
+            Backing::Tar { archive, files } => {
+                let file = files.get(path).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not in the archive"))?;
+                (File::open(archive)?, file.offset, file.size)
+            }
+        };
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut done = 0;
+        while done < size {
+            let want = buffer.len().min((size - done) as usize);
+            let n = src.read_at(&mut buffer[..want], offset + done)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "backing file shrank"));
+            }
+            dst.write_all_at(&buffer[..n], done)?;
+            done += n as u64;
+        }
+        Ok(size)
+    }
+}
+
+/// The backing path a placeholder is filled from, if `fd` is one.
+fn placeholder_source(fd: RawFd) -> Option<PathBuf> {
+    let name = CString::new(PLACEHOLDER_XATTR).expect("no NUL in the xattr name");
+    let mut value = vec![0u8; libc::PATH_MAX as usize];
+    let n = unsafe { libc::fgetxattr(fd, name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len()) };
+    if n < 0 {
+        return None;
+    }
+    value.truncate(n as usize);
+    Some(PathBuf::from(std::ffi::OsStr::from_bytes(&value)))
+}
+
+fn is_placeholder(path: &Path) -> bool {
+    let (Ok(path), Ok(name)) = (path_cstring(path), CString::new(PLACEHOLDER_XATTR)) else {
+        return false;
+    };
+    unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) >= 0 }
+}
+
+// Per-file fill locks by (dev, ino)
+type FillLocks = HashMap<(u64, u64), Arc<Mutex<()>>>;
+
+pub struct Hydrator {
+    root: PathBuf,
+    backing: Backing,
+    /// One fill per file at a time, keyed by (dev, ino); a second access to
+    /// the same file waits and finds it done, other files go ahead.
+    filling: Mutex<FillLocks>,
+}
+
+impl Hydrator {
+    pub fn new(root: PathBuf, backing: Backing) -> Self {
+        Hydrator { root, backing, filling: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn backing(&self) -> &Backing {
+        &self.backing
+    }
+
+    /// Creates a placeholder for every backing file missing below the root.
+    /// Returns how many were created.
+    pub fn checkout(&self) -> io::Result<usize> {
+        let name = CString::new(PLACEHOLDER_XATTR).expect("no NUL in the xattr name");
+        let mut created = 0;
+        for file in self.backing.files()? {
+            let path = self.root.join(&file.path);
+            if path.symlink_metadata().is_ok() {
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let placeholder = File::create_new(&path)?;
+            placeholder.set_len(file.size)?;
+            let source = file.path.as_os_str().as_bytes();
+            let set = unsafe {
+                libc::fsetxattr(placeholder.as_raw_fd(), name.as_ptr(), source.as_ptr() as *const libc::c_void, source.len(), 0)
+            };
+            if set == -1 {
+                return Err(io::Error::from_raw_os_error(get_errno()));
+            }
+            placeholder.set_permissions(std::fs::Permissions::from_mode(file.mode & 0o7777))?;
+            placeholder.set_modified(UNIX_EPOCH + Duration::from_secs(file.mtime))?;
+            created += 1;
+        }
+        Ok(created)
+    }
+
+    /// Placeholders below the root that are still to be filled.
+    pub fn placeholders(&self) -> io::Result<Vec<PathBuf>> {
+        let mut placeholders = Vec::new();
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    dirs.push(entry.path());
+                } else if file_type.is_file() && is_placeholder(&entry.path()) {
+                    placeholders.push(entry.path());
+                }
+            }
+        }
+        Ok(placeholders)
+    }
+
+    /// FAN_PRE_ACCESS marks on every placeholder; returns how many.
+    pub fn mark(&self, group: &mut PermissionGroup) -> io::Result<usize> {
+        let placeholders = self.placeholders()?;
+        for path in &placeholders {
+            group.marks().add(path, FAN_MARK_INODE, FAN_PRE_ACCESS, 0)?;
+        }
+        Ok(placeholders.len())
+    }
+
+    // Fills the placeholder behind `request`; None when it was filled meanwhile
+    fn fill(&self, request: &Request) -> io::Result<Option<(PathBuf, u64)>> {
+        let fd = request.file.as_raw_fd();
+        // Borrowed: the kernel's event fd stays owned by the request
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        let metadata = file.metadata()?;
+        let (key, modified) = ((metadata.dev(), metadata.ino()), metadata.modified()?);
+        let lock = self.filling.lock().unwrap().entry(key).or_default().clone();
+        let filled = {
+            let _filling = lock.lock().unwrap();
+            self.fill_locked(&file, modified)
+        };
+        // Last one out takes the lock off the table
+        let mut filling = self.filling.lock().unwrap();
+        if Arc::strong_count(&lock) == 2 {
+            filling.remove(&key);
+        }
+        filled
+    }
+
+    fn fill_locked(&self, file: &File, modified: SystemTime) -> io::Result<Option<(PathBuf, u64)>> {
+        let fd = file.as_raw_fd();
+        let Some(source) = placeholder_source(fd) else {
+            return Ok(None);
+        };
+        let size = self.backing.copy(&source, file)?;
+        file.set_len(size)?;
+        file.set_modified(modified)?;
+        let name = CString::new(PLACEHOLDER_XATTR).expect("no NUL in the xattr name");
+        if unsafe { libc::fremovexattr(fd, name.as_ptr()) } == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        Ok(Some((source, size)))
+    }
+}
+
+impl Policy for Hydrator {
+    fn quick(&self, request: &Request) -> Option<Decision> {
+        match placeholder_source(request.file.as_raw_fd()) {
+            Some(_) => None,
+            None => Some(Decision::allow("not a placeholder").cached_always()),
+        }
+    }
+
+    fn decide(&self, request: &Request) -> Decision {
+        match self.fill(request) {
+            Ok(Some((source, size))) => Decision::allow(format!("filled {} bytes from {}", size, source.display())).cached_always(),
+            Ok(None) => Decision::allow("already filled").cached_always(),
+            // Better a failed read than one that silently returns zeroes
+            Err(e) => Decision::deny(format!("cannot fill from {}: {}", self.backing.describe(), e)),
+        }
+    }
+}
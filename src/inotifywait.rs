@@ -0,0 +1,411 @@
+// inotifywait-compatible mode.
+//
+// Accepts the commonly scripted inotifywait flags and prints events in the
+// same format, so `inotifywait -m -r --format '%w%f %e'` pipelines keep
+// working on top of fanotify. Runs as `fanotify_demo inotifywait ...` or
+// through a symlink named `inotifywait`. Nothing but event lines goes to
+// stdout. The kernel merges queued events for one object into a single
+// fanotify event, so merged masks are split back into one line per event,
+// in the order inotify would have reported them.
+
+use std::ffi::{CStr, CString};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::event::parse_events;
+use crate::mark::MarkSet;
+use crate::mask::{inotify_names, mask_from_inotify_name};
+use crate::recursive::RecursiveWatch;
+use crate::sys::*;
+
+pub const USAGE: &str = "\
+Usage: fanotify_demo inotifywait [OPTIONS] <FILE>...
+
+inotifywait-compatible output on top of fanotify.
+
+Options:
+  -m, --monitor           Keep listening instead of exiting after the first event
+  -r, --recursive         Watch directories recursively
+  -e, --event <EVENT>     Only report these events (repeatable, comma-separated)
+  --format <FMT>          Print with %w %f %e %Xe %T %% instead of the default format
+  --timefmt <FMT>         strftime(3) format for %T
+  --exclude <REGEX>       Skip events whose path matches the POSIX extended regex
+  --excludei <REGEX>      Like --exclude, case-insensitive
+  -q, --quiet             Do not print the setup messages
+  -h, --help              Print this help
+";
+
+/// What `--format` gives without the flag.
+const DEFAULT_FORMAT: &str = "%w %,e %f";
+
+/// The order inotify reports the events of one operation in; a merged
+/// fanotify event is printed as one line per bit in this order.
+const EVENT_ORDER: &[u64] = &[
+    FAN_CREATE,
+    FAN_MOVED_FROM,
+    FAN_MOVED_TO,
+    FAN_OPEN,
+    FAN_ACCESS,
+    FAN_MODIFY,
+    FAN_ATTRIB,
+    FAN_CLOSE_WRITE,
+    FAN_CLOSE_NOWRITE,
+    FAN_DELETE,
+    FAN_DELETE_SELF,
+    FAN_MOVE_SELF,
+];
+
+/// Every event inotifywait reports by default.
+const ALL_EVENTS: u64 = 0xfff;
+
+#[derive(Debug, Default)]
+pub struct WaitOptions {
+    pub monitor: bool,
+    pub recursive: bool,
+    pub quiet: bool,
+    /// 0 means every event.
+    pub events: u64,
+    pub format: Option<String>,
+    pub timefmt: Option<String>,
+    /// Pattern and whether it ignores case.
+    pub exclude: Option<(String, bool)>,
+    pub paths: Vec<PathBuf>,
+    pub help: bool,
+}
+
+impl WaitOptions {
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<WaitOptions, String> {
+        let mut opts = WaitOptions::default();
+        let mut args = args.into_iter();
+        let mut clustered: Vec<String> = Vec::new();
+        while let Some(arg) = clustered.pop().or_else(|| args.next()) {
+            // "-mrq" is "-m -r -q"
+            if let Some(flags) = arg.strip_prefix('-').filter(|f| !f.starts_with('-') && f.len() > 1) {
+                clustered.extend(flags.chars().rev().map(|c| format!("-{}", c)));
+                continue;
+            }
+            match arg.as_str() {
+                "-m" | "--monitor" => opts.monitor = true,
+                "-r" | "--recursive" => opts.recursive = true,
+                "-q" | "--quiet" => opts.quiet = true,
+                "-e" | "--event" => {
+                    for name in value(&mut args, &arg)?.split(',').filter(|n| !n.trim().is_empty()) {
+                        opts.events |= mask_from_inotify_name(name)?;
+                    }
+                }
+                "--format" => opts.format = Some(value(&mut args, &arg)?),
+                "--timefmt" => opts.timefmt = Some(value(&mut args, &arg)?),
+                "--exclude" => opts.exclude = Some((value(&mut args, &arg)?, false)),
+                "--excludei" => opts.exclude = Some((value(&mut args, &arg)?, true)),
+                "-h" | "--help" => opts.help = true,
+                "--" => opts.paths.extend(args.by_ref().map(PathBuf::from)),
+                _ if arg.starts_with('-') => return Err(format!("unknown argument: {}", arg)),
+                _ => opts.paths.push(PathBuf::from(arg)),
+            }
+        }
+        if opts.paths.is_empty() && !opts.help {
+            return Err("No files specified to watch!".to_string());
+        }
+        Ok(opts)
+    }
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} requires a value", flag))
+}
+
+/// A compiled regcomp(3) pattern, the engine inotifywait itself uses.
+pub struct Regex {
+    compiled: Box<libc::regex_t>,
+}
+
+impl Regex {
+    pub fn new(pattern: &str, ignore_case: bool) -> Result<Regex, String> {
+        let pattern_cstr = CString::new(pattern).map_err(|_| format!("regex contains a NUL byte: {}", pattern))?;
+        let mut compiled: Box<libc::regex_t> = Box::new(unsafe { std::mem::zeroed() });
+        let flags = libc::REG_EXTENDED | libc::REG_NOSUB | if ignore_case { libc::REG_ICASE } else { 0 };
+        let result = unsafe { libc::regcomp(&mut *compiled, pattern_cstr.as_ptr(), flags) };
+        if result != 0 {
+            let mut message = [0 as libc::c_char; 256];
+            unsafe { libc::regerror(result, &*compiled, message.as_mut_ptr(), message.len()) };
+            let message = unsafe { CStr::from_ptr(message.as_ptr()) };
+            return Err(format!("invalid regex {}: {}", pattern, message.to_string_lossy()));
+        }
+        Ok(Regex { compiled })
+    }
+
+    pub fn is_match(&self, text: &[u8]) -> bool {
+        let Ok(text_cstr) = CString::new(text) else {
+            return false;
+        };
+        unsafe { libc::regexec(&*self.compiled, text_cstr.as_ptr(), 0, std::ptr::null_mut(), 0) == 0 }
+    }
+}
+
+impl Drop for Regex {
+    fn drop(&mut self) {
+        unsafe { libc::regfree(&mut *self.compiled) };
+    }
+}
+
+/// The current local time through strftime(3).
+fn local_time(format: &str) -> String {
+    format_local_time(unsafe { libc::time(std::ptr::null_mut()) }, format)
+}
+
+/// inotify names for one event bit; closes also carry the combined `CLOSE`.
+fn event_names(bit: u64, is_dir: bool) -> Vec<&'static str> {
+    let mut names = inotify_names(bit);
+    if bit & (FAN_CLOSE_WRITE | FAN_CLOSE_NOWRITE) != 0 {
+        names.push("CLOSE");
+    }
+    if is_dir {
+        names.push("ISDIR");
+    }
+    names
+}
+
+/// Expands `%w %f %e %Xe %T %%`; anything else is printed as is.
+pub fn format_event(format: &str, watched: &[u8], file: &[u8], events: &[&str], time: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut chars = format.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        if c != '%' {
+            let mut buf = [0; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next().map(|(_, c)| c) {
+            Some('w') => out.extend_from_slice(watched),
+            Some('f') => out.extend_from_slice(file),
+            Some('e') => out.extend_from_slice(events.join(",").as_bytes()),
+            Some('T') => out.extend_from_slice(time.as_bytes()),
+            Some('%') => out.push(b'%'),
+            // %Xe: events separated by X
+            Some(sep) if chars.peek().is_some_and(|(_, c)| *c == 'e') => {
+                chars.next();
+                out.extend_from_slice(events.join(&sep.to_string()).as_bytes());
+            }
+            Some(other) => {
+                out.push(b'%');
+                out.extend_from_slice(other.to_string().as_bytes());
+            }
+            None => out.push(b'%'),
+        }
+    }
+    out
+}
+
+/// A path given on the command line: as typed (for %w) and resolved (to match events).
+struct Watched {
+    given: PathBuf,
+    real: PathBuf,
+    is_dir: bool,
+}
+
+impl Watched {
+    /// %w and %f for an event on `path`, or None if it is not covered by this watch.
+    fn split(&self, path: &Path, recursive: bool) -> Option<(Vec<u8>, Vec<u8>)> {
+        let with_slash = |p: &Path| {
+            let mut bytes = p.as_os_str().as_bytes().to_vec();
+            if !bytes.ends_with(b"/") {
+                bytes.push(b'/');
+            }
+            bytes
+        };
+        if path == self.real {
+            let watched = if self.is_dir { with_slash(&self.given) } else { self.given.as_os_str().as_bytes().to_vec() };
+            return Some((watched, Vec::new()));
+        }
+        let parent = path.parent()?;
+        let below = parent.strip_prefix(&self.real).ok()?;
+        if !self.is_dir || (!recursive && !below.as_os_str().is_empty()) {
+            return None;
+        }
+        let name = path.file_name()?.as_bytes().to_vec();
+        Some((with_slash(&self.given.join(below)), name))
+    }
+}
+
+/// Entry point of `fanotify_demo inotifywait ...`; returns the exit status.
+pub fn main(args: Vec<String>) -> i32 {
+    let opts = match WaitOptions::parse(args) {
+        Ok(opts) if opts.help => {
+            print!("{}", USAGE);
+            return 0;
+        }
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    match run(&opts) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn run(opts: &WaitOptions) -> Result<(), String> {
+    let exclude = match &opts.exclude {
+        Some((pattern, ignore_case)) => Some(Regex::new(pattern, *ignore_case)?),
+        None => None,
+    };
+    let selected = if opts.events == 0 { ALL_EVENTS } else { opts.events };
+    let format = opts.format.as_deref().unwrap_or(DEFAULT_FORMAT);
+    if format.contains("%T") && opts.timefmt.is_none() {
+        return Err("%T is in --format string, but --timefmt was not specified.".to_string());
+    }
+
+    // Directory entry names need DFID_NAME; TARGET_FID lets -r follow renamed directories
+    let mut fanotify_fd = -1;
+    for report in [FAN_REPORT_FID | FAN_REPORT_DFID_NAME | FAN_REPORT_TARGET_FID, FAN_REPORT_FID | FAN_REPORT_DFID_NAME] {
+        fanotify_fd = unsafe { fanotify_init(FAN_CLASS_NOTIF | FAN_CLOEXEC | report, libc::O_RDONLY as u32) };
+        if fanotify_fd != -1 || get_errno() != libc::EINVAL {
+            break;
+        }
+    }
+    if fanotify_fd == -1 {
+        return Err(format!("Couldn't initialize fanotify: {}", io::Error::from_raw_os_error(get_errno())));
+    }
+    let mut marks = MarkSet::new(fanotify_fd);
+
+    if !opts.quiet {
+        match opts.recursive {
+            true => eprintln!("Setting up watches.  Beware: since -r was given, this may take a while!"),
+            false => eprintln!("Setting up watches."),
+        }
+    }
+    let mut watched = Vec::new();
+    let mut trees = Vec::new();
+    for given in &opts.paths {
+        let real = std::fs::canonicalize(given).map_err(|e| format!("Couldn't watch {}: {}", given.display(), e))?;
+        let is_dir = real.is_dir();
+        let result = match (is_dir, opts.recursive) {
+            (true, true) => {
+                let mut tree = RecursiveWatch::new(&real, selected | FAN_DELETE_SELF | FAN_MOVE_SELF);
+                let result = tree.start(&mut marks).map(|_| ()).map_err(|e| e.to_string());
+                trees.push(tree);
+                result
+            }
+            (true, false) => marks
+                .add(&real, FAN_MARK_INODE, selected | FAN_EVENT_ON_CHILD | FAN_ONDIR, FAN_MARK_ONLYDIR)
+                .map_err(|e| e.to_string()),
+            // Directory entry events only exist on directories
+            (false, _) => marks
+                .add(&real, FAN_MARK_INODE, selected & !(FAN_CREATE | FAN_DELETE | FAN_MOVE), 0)
+                .map_err(|e| e.to_string()),
+        };
+        result.map_err(|e| format!("Couldn't watch {}: {}", given.display(), e))?;
+        watched.push(Watched { given: given.clone(), real, is_dir });
+    }
+    if !opts.quiet {
+        eprintln!("Watches established.");
+    }
+
+    let mut stdout = io::stdout().lock();
+    let mut buffer = [0u8; 8192];
+    loop {
+        let n = unsafe { libc::read(fanotify_fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+        if n == -1 {
+            if get_errno() == libc::EINTR {
+                continue;
+            }
+            return Err(format!("read failed: {}", io::Error::from_raw_os_error(get_errno())));
+        }
+        for mut event in parse_events(&buffer[..n as usize]) {
+            marks.resolver().resolve(&mut event);
+            for tree in &mut trees {
+                // New directories get marked before their own events can be missed
+                let _ = tree.handle(&mut marks, &event);
+            }
+            let Some(path) = event.path.as_deref() else {
+                continue;
+            };
+            let Some((w, f)) = watched.iter().find_map(|watch| watch.split(path, opts.recursive)) else {
+                continue;
+            };
+            if exclude.as_ref().is_some_and(|re| re.is_match(&[w.as_slice(), f.as_slice()].concat())) {
+                continue;
+            }
+            let time = opts.timefmt.as_deref().map(local_time).unwrap_or_default();
+            for bit in EVENT_ORDER.iter().filter(|bit| event.mask & selected & **bit != 0) {
+                let mut line = format_event(format, &w, &f, &event_names(*bit, event.is_dir()), &time);
+                line.push(b'\n');
+                // A closed pipe (e.g. `| head -1`) ends the program quietly
+                if stdout.write_all(&line).and_then(|_| stdout.flush()).is_err() {
+                    return Ok(());
+                }
+                if !opts.monitor {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn format(fmt: &str) -> String {
+        String::from_utf8(format_event(fmt, b"/srv/", b"a b.txt", &["CLOSE_WRITE", "CLOSE"], "12:00")).unwrap()
+    }
+
+    #[test]
+    fn default_format_matches_inotifywait() {
+        assert_eq!(format(DEFAULT_FORMAT), "/srv/ CLOSE_WRITE,CLOSE a b.txt");
+    }
+
+    #[test]
+    fn expands_every_directive() {
+        assert_eq!(format("%T %w%f [%e] 100%%"), "12:00 /srv/a b.txt [CLOSE_WRITE,CLOSE] 100%");
+        assert_eq!(format("%:e|%;e"), "CLOSE_WRITE:CLOSE|CLOSE_WRITE;CLOSE");
+    }
+
+    #[test]
+    fn keeps_unknown_directives() {
+        assert_eq!(format("%x %"), "%x %");
+    }
+
+    #[test]
+    fn splits_clustered_flags() {
+        let opts = WaitOptions::parse(args("-mrq /srv")).unwrap();
+        assert!(opts.monitor && opts.recursive && opts.quiet);
+        assert_eq!(opts.paths, vec![PathBuf::from("/srv")]);
+    }
+
+    #[test]
+    fn clustered_flag_takes_its_value_from_the_next_argument() {
+        let opts = WaitOptions::parse(args("-me close_write,moved_to /srv")).unwrap();
+        assert!(opts.monitor);
+        assert_eq!(opts.events, FAN_CLOSE_WRITE | FAN_MOVED_TO);
+        assert_eq!(opts.paths, vec![PathBuf::from("/srv")]);
+    }
+
+    #[test]
+    fn long_options_and_separator() {
+        let opts = WaitOptions::parse(args("--monitor --format %w%f --excludei \\.swp$ -- -odd")).unwrap();
+        assert!(opts.monitor && !opts.recursive);
+        assert_eq!(opts.format.as_deref(), Some("%w%f"));
+        assert_eq!(opts.exclude, Some(("\\.swp$".to_string(), true)));
+        assert_eq!(opts.paths, vec![PathBuf::from("-odd")]);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(WaitOptions::parse(args("-m")).is_err());
+        assert!(WaitOptions::parse(args("-mx /srv")).unwrap_err().contains("-x"));
+        assert!(WaitOptions::parse(args("-e /srv")).is_err());
+        assert!(WaitOptions::parse(args("--format")).is_err());
+        assert!(WaitOptions::parse(args("-h")).unwrap().help);
+    }
+}
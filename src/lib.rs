@@ -0,0 +1,30 @@
+pub mod action;
+pub mod allowlist;
+pub mod canary;
+pub mod cli;
+pub mod coalesce;
+pub mod config;
+pub mod control;
+pub mod event;
+pub mod exec;
+pub mod filter;
+pub mod fserror;
+pub mod hsm;
+pub mod inotifywait;
+pub mod mark;
+pub mod mask;
+pub mod mntns;
+pub mod mounts;
+pub mod pending;
+pub mod perm;
+pub mod prompt;
+pub mod protect;
+pub mod ready;
+pub mod recursive;
+pub mod rerun;
+pub mod save;
+pub mod signal;
+pub mod source;
+pub mod sticky;
+pub mod subscribe;
+pub mod sys;
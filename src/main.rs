@@ -1,53 +1,37 @@
-use std::{fs, mem};
+use std::fs;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 use std::os::unix::fs::PermissionsExt;
 
-// fanotify constants and structures
-const FAN_CLASS_NOTIF: u32 = 0;
-const FAN_CLOEXEC: u32 = 0x00000001;
-const FAN_REPORT_FID: u32 = 0x00000200;  // Required for FAN_ATTRIB since Linux 5.1
-const FAN_REPORT_DIR_FID: u32 = 0x00000400;  // Optional: for parent directory handles
-
-const FAN_OPEN: u64 = 0x00000001;
-const FAN_CLOSE_WRITE: u64 = 0x00000008;
-const FAN_MODIFY: u64 = 0x00000002;
-const FAN_ATTRIB: u64 = 0x00000004;
-
-const FAN_MARK_ADD: u32 = 0x00000001;
-const FAN_MARK_ONLYDIR: u32 = 0x00000008;
-
-const AT_FDCWD: libc::c_int = -100;
-
-// fanotify_event_metadata structure
-#[repr(C)]
-#[derive(Debug)]
-struct FanotifyEventMetadata {
-    event_len: u32,
-    vers: u8,
-    reserved: u8,
-    metadata_len: u16,
-    mask: u64,
-    fd: i32,
-    pid: i32,
-}
-
-// System call numbers (x86_64)
-const SYS_FANOTIFY_INIT: libc::c_long = 300;
-const SYS_FANOTIFY_MARK: libc::c_long = 301;
-
-// Raw system call wrappers
-unsafe fn fanotify_init(flags: u32, event_f_flags: u32) -> libc::c_int {
-    unsafe { libc::syscall(SYS_FANOTIFY_INIT, flags, event_f_flags) as libc::c_int }
-}
-
-unsafe fn fanotify_mark(
-    fanotify_fd: libc::c_int,
-    flags: u32,
-    mask: u64,
-    dirfd: libc::c_int,
-    pathname: *const libc::c_char,
-) -> libc::c_int {
-    unsafe { libc::syscall(SYS_FANOTIFY_MARK, fanotify_fd, flags, mask, dirfd, pathname) as libc::c_int }
-}
+use fanotify_demo::action::{parse_incrontab, ActionCommand, ActionRule, ActionRunner, Pattern};
+use fanotify_demo::allowlist::{Allowlist, TrustDb, DEFAULT_DEADLINE as ALLOWLIST_DEADLINE};
+use fanotify_demo::canary::{CanaryTrap, DEFAULT_DECOYS};
+use fanotify_demo::cli::{Options, USAGE};
+use fanotify_demo::coalesce::{policies_from_list, CoalesceConfig, Coalesced, Coalescer, MonotonicClock};
+use fanotify_demo::config::{self, Config, MarkSpec};
+use fanotify_demo::control::{resolve_group, ControlServer, ControlState};
+use fanotify_demo::exec::ExecInventory;
+use fanotify_demo::mark::{group_flags, IgnoreMark, MarkSet};
+use fanotify_demo::mounts::{MountRegistry, MountTableWatch};
+use fanotify_demo::mntns::{MountFollower, MountWatcher, OWN_NAMESPACE};
+use fanotify_demo::pending::PendingWatch;
+use fanotify_demo::fserror::FsErrorMonitor;
+use fanotify_demo::hsm::{Backing, Hydrator, FILL_DEADLINE as HSM_FILL_DEADLINE};
+use fanotify_demo::perm::{PermissionDaemon, PermissionGroup, Verdict};
+use fanotify_demo::prompt::{Prompter, DEFAULT_TIMEOUT as PROMPT_TIMEOUT};
+use fanotify_demo::protect::{Exception, Protector};
+use fanotify_demo::ready::ReadyDetector;
+use fanotify_demo::{inotifywait, rerun};
+use fanotify_demo::recursive::{max_user_marks, RecursiveWatch};
+use fanotify_demo::save::{SaveDetector, SaveOutput, DEFAULT_WINDOW as DEFAULT_SAVE_WINDOW};
+use fanotify_demo::signal::SignalFd;
+use fanotify_demo::source::{unsupported_filesystem, EventSource, FanotifySource, InotifySource, PollingSource, DEFAULT_POLL_INTERVAL};
+use fanotify_demo::sticky::StickyWatch;
+use fanotify_demo::subscribe::SubscriptionServer;
+use fanotify_demo::sys::*;
 
 fn check_kernel_version() {
     println!("DEBUG: Checking kernel version and fanotify support...");
@@ -83,11 +67,512 @@ fn check_capabilities() {
     }
 }
 
-fn get_errno() -> i32 {
-    unsafe { *libc::__errno_location() }
+fn print_marks(marks: &MarkSet) {
+    println!("\n📌 === ACTIVE MARKS ({}) ===", marks.len());
+    for entry in marks.marks() {
+        println!(
+            "   {} [{}] mask=0x{:x} ignored=0x{:x}",
+            entry.path.display(),
+            entry.object,
+            entry.mask,
+            entry.ignored_mask
+        );
+    }
+    // Cross-check our bookkeeping against /proc/self/fdinfo
+    match marks.verify() {
+        Ok(mismatches) if mismatches.is_empty() => println!("✓ Kernel mark state matches the MarkSet"),
+        Ok(mismatches) => {
+            for mismatch in mismatches {
+                println!("⚠ Mark mismatch: {}", mismatch);
+            }
+        }
+        Err(e) => println!("DEBUG: ✗ Failed to read fanotify fdinfo: {}", e),
+    }
+}
+
+fn reload_config(
+    path: &Path,
+    config: &mut Config,
+    applied: &mut Vec<MarkSpec>,
+    state: &ControlState,
+    marks_supported: bool,
+    forced_polls: &[PathBuf],
+) {
+    println!("\n🔄 SIGHUP received: reloading {}", path.display());
+    // Validate everything before touching a single mark
+    let loaded = Config::load(path).and_then(|c| Ok((c.mark_specs()?, c.filter()?, c)));
+    let (new_specs, filter, new_config) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("✗ Rejected new configuration, keeping the running one: {}", e);
+            return;
+        }
+    };
+    // Polled paths were routed away from marks at startup and stay that way
+    let polled = new_specs.iter().filter(|spec| spec.ignore.is_none() && poll_reason(&spec.path, forced_polls).is_some());
+    if polled.count() > 0 || new_config.poll != config.poll {
+        println!("⚠ Polled watches and [poll] settings take effect after a restart");
+    }
+    let new_specs: Vec<MarkSpec> =
+        new_specs.into_iter().filter(|spec| spec.ignore.is_some() || poll_reason(&spec.path, forced_polls).is_none()).collect();
+    let mut changes = config::diff(applied, &new_specs);
+    if !marks_supported && !changes.is_empty() {
+        println!("⚠ Watch changes need the fanotify backend; only filters and outputs are reloaded");
+        changes.clear();
+    }
+    if let Err(e) = config::apply(&mut state.marks.lock().unwrap(), &changes) {
+        eprintln!("✗ Failed to apply new configuration, rolled back: {}", e);
+        return;
+    }
+    for change in &changes {
+        println!("   {}", change);
+    }
+    *state.filter.lock().unwrap() = filter;
+    if new_config.output.control_socket != config.output.control_socket
+        || new_config.output.control_group != config.output.control_group
+        || new_config.output.subscribe_socket != config.output.subscribe_socket
+    {
+        println!("⚠ Socket settings changed; they take effect after a restart");
+    }
+    if new_config.coalesce != config.coalesce || new_config.saves != config.saves {
+        println!("⚠ Coalescing or save detection settings changed; they take effect after a restart");
+    }
+    if new_config.actions != config.actions || new_config.action_rules != config.action_rules {
+        println!("⚠ Action rules changed; they take effect after a restart");
+    }
+    println!("✓ Configuration reloaded: {} mark change(s), no events lost", changes.len());
+    *config = new_config;
+    if marks_supported {
+        *applied = new_specs;
+    }
+}
+
+// Dirent and ATTRIB events are not allowed on mount marks
+const MOUNT_MASK: u64 = FAN_OPEN | FAN_MODIFY | FAN_CLOSE_WRITE;
+
+/// Marks installed at startup and the watches that keep them up to date.
+struct Watches {
+    marks: MarkSet,
+    fs_errors: FsErrorMonitor,
+    canaries: CanaryTrap,
+    follower: MountFollower,
+    actual_mask: u64,
+    sticky: Vec<StickyWatch>,
+    pending: Vec<PendingWatch>,
+    trees: Vec<RecursiveWatch>,
+    ready: ReadyDetector,
+    applied_specs: Vec<MarkSpec>,
+}
+
+fn setup_fanotify(
+    fanotify_fd: i32,
+    opts: &Options,
+    test_file_path: &str,
+    mask_metadata_focused: u64,
+    mask_fallback: u64,
+    action_rules: &[&ActionRule],
+    applied_specs: Vec<MarkSpec>,
+) -> Result<Watches, Box<dyn std::error::Error>> {
+    // Every mark goes through the MarkSet so we always know what is installed
+    let mut marks = MarkSet::new(fanotify_fd);
+    let test_path = Path::new(test_file_path);
+    
+    let mark_result = marks.add(test_path, FAN_MARK_INODE, mask_metadata_focused, 0);
+    
+    let actual_mask = if let Err(e) = mark_result {
+        let errno = e.raw_os_error().unwrap_or(0);
+        println!("❌ Failed to enable FAN_ATTRIB metadata monitoring: errno = {}", errno);
+        match errno {
+            libc::EINVAL => {
+                println!("💔 EINVAL - This should be FIXED now with FAN_REPORT_FID!");
+                println!("   If still failing, check kernel version (need Linux 5.1+)");
+                println!("   or file system support for file handles");
+            }
+            libc::EPERM => {
+                println!("🔒 EPERM - Need root privileges or CAP_SYS_ADMIN");
+            }
+            libc::ENOTDIR => {
+                println!("📁 ENOTDIR - File handle not supported on this filesystem");
+            }
+            _ => {
+                println!("🚫 Other error: errno {}", errno);
+            }
+        }
+        
+        println!("⚠️  FALLBACK: Attempting basic monitoring without metadata detection...");
+        if let Err(e) = marks.add(test_path, FAN_MARK_INODE, mask_fallback, 0) {
+            let errno = e.raw_os_error().unwrap_or(0);
+            eprintln!("💥 FATAL: Complete failure - cannot even monitor basic file events: errno = {}", errno);
+            unsafe { libc::close(fanotify_fd) };
+            return Err(format!("fanotify_mark failed completely with errno {}", errno).into());
+        }
+        
+        println!("✅ Fallback successful: Basic file monitoring enabled (NO metadata detection)");
+        
+        // Try to add directory monitoring for FAN_ATTRIB as additional fallback
+        println!("🔍 EXPERIMENTAL: Attempting directory-level FAN_ATTRIB monitoring...");
+        match marks.add(Path::new("/tmp"), FAN_MARK_INODE, FAN_ATTRIB, FAN_MARK_ONLYDIR) {
+            Ok(()) => {
+                println!("✨ SUCCESS: Directory-level FAN_ATTRIB monitoring enabled!");
+                println!("   This may detect some metadata changes at directory level");
+                mask_fallback | FAN_ATTRIB
+            }
+            Err(e) => {
+                println!("❌ Directory-level FAN_ATTRIB also failed: {}", e);
+                mask_fallback
+            }
+        }
+    } else {
+        println!("🎉 SUCCESS: FAN_ATTRIB metadata monitoring is ACTIVE!");
+        println!("✨ This system fully supports metadata change detection");
+        mask_metadata_focused
+    };
+
+    // Atomic saves (write temp file, rename over) replace the inode under the mark,
+    // so the test file and every --sticky path are followed by name. Names come
+    // with FAN_REPORT_DFID_NAME only; without it nothing would ever match, and
+    // --recursive could not tell which subdirectory was created
+    let names = group_flags(fanotify_fd).is_ok_and(|flags| flags & FAN_REPORT_DFID_NAME == FAN_REPORT_DFID_NAME);
+    for (flag, used) in [
+        ("--sticky", !opts.sticky.is_empty()),
+        ("--wait-for", !opts.wait_for.is_empty()),
+        ("--recursive", !opts.recursive.is_empty()),
+    ] {
+        if used && !names {
+            eprintln!("✗ {} needs FAN_REPORT_DFID_NAME (Linux 5.9+), which this kernel does not support", flag);
+            unsafe { libc::close(fanotify_fd) };
+            return Err(format!("{} is not supported without FAN_REPORT_DFID_NAME", flag).into());
+        }
+    }
+    let mut sticky = Vec::new();
+    let mut test_watch = StickyWatch::new(test_path, actual_mask);
+    let armed = match names {
+        true => test_watch.arm(&mut marks),
+        false => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "no FAN_REPORT_DFID_NAME")),
+    };
+    match armed {
+        Ok(()) => {
+            println!("📌 Sticky watch on {}: marks follow the name across atomic saves", test_file_path);
+            sticky.push(test_watch);
+        }
+        Err(e) => println!("⚠️  Sticky watch on {} unavailable ({}); an atomic save will orphan the mark", test_file_path, e),
+    }
+    for path in &opts.sticky {
+        let mut watch = StickyWatch::new(path, actual_mask);
+        if let Err(e) = watch.arm(&mut marks) {
+            eprintln!("✗ Failed to add sticky watch for {}: {}", path.display(), e);
+            unsafe { libc::close(fanotify_fd) };
+            return Err(e.into());
+        }
+        println!("📌 Sticky watch on {} (mask 0x{:x})", path.display(), actual_mask);
+        sticky.push(watch);
+    }
+
+    // Paths that may not exist yet wait on their nearest existing ancestor
+    let mut pending = Vec::new();
+    for path in &opts.wait_for {
+        let mut watch = PendingWatch::new(path, actual_mask);
+        match watch.start(&mut marks) {
+            Ok(change) => println!("⏳ {}", change),
+            Err(e) => {
+                eprintln!("✗ Failed to add pending watch for {}: {}", path.display(), e);
+                unsafe { libc::close(fanotify_fd) };
+                return Err(e.into());
+            }
+        }
+        pending.push(watch);
+    }
+
+    // Mount-wide monitoring; followed roots carry on with what is mounted below them
+    let mask_mount = MOUNT_MASK;
+    let mut follower = MountFollower::new(mask_mount);
+    for mount in &opts.mounts {
+        let added = match opts.follow_mounts {
+            true => follower.mark_root(&mut marks, mount),
+            false => marks.add(mount, FAN_MARK_MOUNT, mask_mount, 0),
+        };
+        match added {
+            Ok(()) => println!("✓ Mount mark added for {} (mask 0x{:x})", mount.display(), mask_mount),
+            Err(e) => {
+                eprintln!("✗ Failed to add mount mark for {}: {}", mount.display(), e);
+                unsafe { libc::close(fanotify_fd) };
+                return Err(e.into());
+            }
+        }
+    }
+
+    // Exec monitoring: a filesystem mark sees every exec, a mount mark is the fallback
+    for path in &opts.exec_watch {
+        let added = marks
+            .add(path, FAN_MARK_FILESYSTEM, FAN_OPEN_EXEC, 0)
+            .map(|()| "filesystem")
+            .or_else(|_| marks.add(path, FAN_MARK_MOUNT, FAN_OPEN_EXEC, 0).map(|()| "mount"));
+        match added {
+            Ok(kind) => println!("🚀 Exec monitoring on {} ({} mark)", path.display(), kind),
+            Err(e) => {
+                eprintln!("✗ Failed to add exec mark for {}: {}", path.display(), e);
+                unsafe { libc::close(fanotify_fd) };
+                return Err(e.into());
+            }
+        }
+    }
+
+    // Filesystem errors are only reported on filesystem marks
+    let mut fs_errors = FsErrorMonitor::new();
+    for path in &opts.fs_error {
+        match fs_errors.watch(&mut marks, path) {
+            Ok(watch) => println!(
+                "🚨 Filesystem error monitoring on {} ({} {} mounted at {})",
+                path.display(),
+                watch.mount.fstype,
+                watch.mount.source,
+                watch.mount.mount_point.display()
+            ),
+            Err(e) => {
+                eprintln!("✗ Failed to watch {} for filesystem errors: {}", path.display(), e);
+                unsafe { libc::close(fanotify_fd) };
+                return Err(e.into());
+            }
+        }
+    }
+
+    // Decoys nothing legitimate touches; the default set in each --canary directory
+    let mut canaries = CanaryTrap::new();
+    let decoys = opts.canary.iter().flat_map(|dir| DEFAULT_DECOYS.iter().map(|name| dir.join(name))).chain(opts.canary_files.iter().cloned());
+    for path in decoys {
+        match canaries.plant(&mut marks, &path) {
+            Ok(created) => println!("🪤 Canary {} {}", path.display(), if created { "planted" } else { "armed (already there)" }),
+            Err(e) => {
+                eprintln!("✗ Failed to plant canary {}: {}", path.display(), e);
+                unsafe { libc::close(fanotify_fd) };
+                return Err(e.into());
+            }
+        }
+    }
+
+    // Ignore marks silence hot files (databases, logs) inside the mount marks
+    let ignore = IgnoreMark {
+        surv_modify: opts.ignore_surv_modify,
+        ..IgnoreMark::new(actual_mask | mask_mount)
+    };
+    for path in &opts.ignores {
+        match marks.ignore(path, &ignore) {
+            Ok(mode) => println!(
+                "🔇 Ignoring events for {} via {}{}",
+                path.display(),
+                mode.name(),
+                if ignore.surv_modify { " (survives modify)" } else { " (until first modify)" }
+            ),
+            Err(e) => eprintln!("✗ Failed to add ignore mark for {}: {}", path.display(), e),
+        }
+    }
+
+    // Recursive trees: one inode mark per directory, kept in sync with CREATE/DELETE/MOVE
+    let recursive_mask = opts.recursive_mask()?;
+    let mut trees = Vec::new();
+    for root in &opts.recursive {
+        let mut tree = RecursiveWatch::new(root, recursive_mask);
+        match tree.start(&mut marks) {
+            Ok(dirs) => println!("🌲 Recursive watch on {}: {} director(ies) marked", root.display(), dirs),
+            Err(e) => {
+                eprintln!("✗ Failed to watch {} recursively: {}", root.display(), e);
+                unsafe { libc::close(fanotify_fd) };
+                return Err(e.into());
+            }
+        }
+        trees.push(tree);
+    }
+    if !trees.is_empty() {
+        match max_user_marks() {
+            Some(limit) => println!("DEBUG: {} mark(s) in use, fs.fanotify.max_user_marks = {}", marks.len(), limit),
+            None => println!("DEBUG: {} mark(s) in use, max_user_marks not exposed by this kernel", marks.len()),
+        }
+    }
+
+    // Drop folders: files are reported once the last writer closed them
+    let mut ready = ReadyDetector::new(opts.ready_into.as_deref());
+    for dir in &opts.ready {
+        match ready.watch(&mut marks, dir) {
+            Ok(()) => println!("📥 Reporting files in {} once their writers are done", dir.display()),
+            Err(e) => {
+                eprintln!("✗ Failed to watch {} for ready files: {}", dir.display(), e);
+                unsafe { libc::close(fanotify_fd) };
+                return Err(e.into());
+            }
+        }
+    }
+    if let Some(dir) = &opts.ready_into && !ready.dirs().is_empty() {
+        println!("📥 Ready files are moved into {}", dir.display());
+    }
+
+    for rule in action_rules {
+        match rule.mark(&mut marks) {
+            Ok(_) => println!("⚙️  Action {}", rule),
+            Err(e) => {
+                eprintln!("✗ Failed to mark the path of {}: {}", rule, e);
+                unsafe { libc::close(fanotify_fd) };
+                return Err(e.into());
+            }
+        }
+    }
+    // Watches from the configuration file; SIGHUP re-applies only the difference
+    if let Some(path) = &opts.config {
+        println!("📄 Applying configuration from {}", path.display());
+        if let Err(e) = config::apply(&mut marks, &config::diff(&[], &applied_specs)) {
+            eprintln!("✗ Failed to apply configuration: {}", e);
+            unsafe { libc::close(fanotify_fd) };
+            return Err(e.into());
+        }
+        for spec in &applied_specs {
+            println!("✓ Config {}", spec);
+        }
+    }
+
+    print_marks(&marks);
+
+    for root in follower.roots() {
+        println!("🗻 Mounts attached below {} get mount marks too", root.display());
+    }
+
+    Ok(Watches { marks, fs_errors, canaries, follower, actual_mask, sticky, pending, trees, ready, applied_specs })
+}
+
+/// The same watches with plain inotify: trees get one watch per directory,
+/// and whatever needs fanotify marks is reported and skipped.
+fn setup_inotify(
+    source: &mut InotifySource,
+    opts: &Options,
+    test_file_path: &str,
+    mask: u64,
+    action_rules: &[&ActionRule],
+    applied_specs: Vec<MarkSpec>,
+) -> Result<Watches, Box<dyn std::error::Error>> {
+    let watch = |source: &mut InotifySource, path: &Path, mask: u64, recursive: bool| {
+        source.watch(path, mask, recursive).map_err(|e| format!("failed to watch {}: {}", path.display(), e))
+    };
+    watch(source, Path::new(test_file_path), mask, false)?;
+    println!("✓ inotify watch on {} (mask 0x{:x})", test_file_path, mask);
+
+    let unsupported = [
+        ("--sticky", !opts.sticky.is_empty()),
+        ("--wait-for", !opts.wait_for.is_empty()),
+        ("--ignore", !opts.ignores.is_empty()),
+        ("--ready", !opts.ready.is_empty()),
+        ("--exec-watch", !opts.exec_watch.is_empty()),
+        ("--fs-error", !opts.fs_error.is_empty()),
+        ("--follow-mounts", opts.follow_mounts),
+        ("--canary", !opts.canary.is_empty() || !opts.canary_files.is_empty()),
+    ];
+    for (flag, _) in unsupported.iter().filter(|(_, used)| *used) {
+        println!("⚠️  {} needs fanotify marks and is ignored with the inotify backend", flag);
+    }
+
+    // Mounts become directory trees; dirent events are left out as with a mount mark
+    for mount in &opts.mounts {
+        let dirs = watch(source, mount, FAN_OPEN | FAN_MODIFY | FAN_CLOSE_WRITE, true)?;
+        println!("🌲 {} watched as a tree: {} director(ies)", mount.display(), dirs);
+    }
+    let recursive_mask = opts.recursive_mask()?;
+    for root in &opts.recursive {
+        let dirs = watch(source, root, recursive_mask, true)?;
+        println!("🌲 Recursive watch on {}: {} director(ies) watched", root.display(), dirs);
+    }
+    for rule in action_rules {
+        if let Pattern::Watched(path) = &rule.pattern {
+            watch(source, path, rule.mask, false)?;
+        }
+        println!("⚙️  Action {}", rule);
+    }
+
+    for spec in &applied_specs {
+        if spec.ignore.is_some() {
+            println!("⚠️  Config {} skipped: ignore marks need fanotify", spec);
+            continue;
+        }
+        watch(source, &spec.path, spec.mask, spec.mark_type != FAN_MARK_INODE)?;
+        println!("✓ Config {}", spec);
+    }
+    println!("DEBUG: {} inotify watch(es) in use", source.len());
+
+    Ok(Watches {
+        marks: MarkSet::new(-1),
+        fs_errors: FsErrorMonitor::new(),
+        canaries: CanaryTrap::new(),
+        follower: MountFollower::new(MOUNT_MASK),
+        actual_mask: mask,
+        sticky: Vec::new(),
+        pending: Vec::new(),
+        trees: Vec::new(),
+        ready: ReadyDetector::new(None),
+        applied_specs,
+    })
+}
+
+// Why `path` is polled instead of marked, if it is
+fn poll_reason(path: &Path, forced: &[PathBuf]) -> Option<String> {
+    if let Some(below) = forced.iter().find(|p| path.starts_with(p)) {
+        return Some(format!("below --poll {}", below.display()));
+    }
+    unsupported_filesystem(path).map(|fs| format!("{} filesystem", fs))
+}
+
+// poll() takes whole milliseconds; round up so a deadline is never polled too early
+fn poll_timeout(wait: Duration) -> libc::c_int {
+    wait.as_micros().div_ceil(1000).min(libc::c_int::MAX as u128) as libc::c_int
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|a| a == "run") {
+        return rerun::main(args[1..].to_vec());
+    }
+    // Drop-in replacement for scripts calling inotifywait
+    let invoked_as = std::env::args().next().unwrap_or_default();
+    if Path::new(&invoked_as).file_name().is_some_and(|n| n == "inotifywait") {
+        std::process::exit(inotifywait::main(args));
+    }
+    if args.first().is_some_and(|a| a == "inotifywait") {
+        std::process::exit(inotifywait::main(args[1..].to_vec()));
+    }
+    let mut opts = Options::parse(args)?;
+    if opts.help {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+
+    // The inventory is loaded before any mark exists so a corrupt file fails early
+    let mut exec_inventory = match &opts.exec_inventory {
+        Some(path) => Some(ExecInventory::load(path).map_err(|e| format!("{}: {}", path.display(), e))?),
+        None => None,
+    };
+    if opts.exec_report {
+        let Some(inventory) = &exec_inventory else {
+            return Err("--exec-report needs --exec-inventory".into());
+        };
+        println!("📦 {} executable(s) and libraries in {}", inventory.len(), inventory.file().display());
+        for entry in inventory.entries() {
+            println!("   {}", entry);
+        }
+        println!("⚠️  Libraries are read from /proc/<pid>/maps after each exec: those of processes that exited");
+        println!("   right away and those loaded later with dlopen() are missing");
+        return Ok(());
+    }
+    if exec_inventory.is_some() && opts.exec_watch.is_empty() {
+        opts.exec_watch.push(PathBuf::from("/"));
+    }
+
+    // SIGHUP is blocked before any thread exists, so only the signalfd ever sees it
+    let reload_signal = match &opts.config {
+        Some(_) => Some(SignalFd::new(&[libc::SIGHUP])?),
+        None => None,
+    };
+    // Ctrl+C and SIGTERM end the event loop, so what is pending gets flushed
+    let stop_signal = SignalFd::new(&[libc::SIGINT, libc::SIGTERM])?;
+    let mut config = match &opts.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
     println!("=== Starting fanotify filesystem monitoring program (Pure unsafe version) ===");
     
     // Check kernel and system support
@@ -113,13 +598,22 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("DEBUG: libc::O_RDONLY = {}", libc::O_RDONLY);
     
     // CRITICAL FIX: Add FAN_REPORT_FID for FAN_ATTRIB support
-    let fanotify_fd = unsafe { 
-        fanotify_init(
-            FAN_CLASS_NOTIF | FAN_CLOEXEC | FAN_REPORT_FID, 
-            libc::O_RDONLY as u32
-        ) 
-    };
-    
+    // Directory entry events only carry the entry name with FAN_REPORT_DFID_NAME (Linux 5.9+)
+    // and the moved/created object's own handle with FAN_REPORT_TARGET_FID (Linux 5.17+)
+    let report_flags = [
+        FAN_REPORT_FID | FAN_REPORT_DFID_NAME | FAN_REPORT_TARGET_FID,
+        FAN_REPORT_FID | FAN_REPORT_DFID_NAME,
+        FAN_REPORT_FID,
+    ];
+    let mut fanotify_fd = -1;
+    for report in report_flags {
+        fanotify_fd = unsafe { fanotify_init(FAN_CLASS_NOTIF | FAN_CLOEXEC | report, libc::O_RDONLY as u32) };
+        if fanotify_fd != -1 || get_errno() != libc::EINVAL {
+            println!("DEBUG: fanotify_init report flags = 0x{:x}", report);
+            break;
+        }
+        println!("DEBUG: Report flags 0x{:x} not supported by this kernel, trying fewer", report);
+    }
     if fanotify_fd == -1 {
         let errno = get_errno();
         eprintln!("✗ Failed to initialize fanotify: errno = {}", errno);
@@ -137,10 +631,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 eprintln!("Other error occurred during fanotify initialization: {}", errno);
             }
         }
-        return Err(format!("fanotify_init failed with errno {}", errno).into());
+        if errno != libc::EPERM && errno != libc::ENOSYS {
+            return Err(format!("fanotify_init failed with errno {}", errno).into());
+        }
+    }
+
+    // Without fanotify the same events come from recursive inotify watches
+    let mut inotify = None;
+    if fanotify_fd == -1 {
+        println!("⚠️  FALLBACK: fanotify is unavailable, using the inotify backend");
+        inotify = Some(InotifySource::new()?);
+    } else {
+        println!("✓ Successfully initialized fanotify, fd = {}", fanotify_fd);
     }
-    
-    println!("✓ Successfully initialized fanotify, fd = {}", fanotify_fd);
     
     // Create a test file to monitor
     println!("DEBUG: Creating test file for monitoring...");
@@ -194,86 +697,236 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("   • link/unlink - Hard link operations");
     println!("DEBUG: Attempting to enable FAN_ATTRIB for metadata monitoring...");
     
-    // Convert path to C string
-    let path_cstr = std::ffi::CString::new(test_file_path).unwrap();
-    
-    let mark_result = unsafe {
-        fanotify_mark(
-            fanotify_fd,
-            FAN_MARK_ADD,
-            mask_metadata_focused,
-            AT_FDCWD,
-            path_cstr.as_ptr(),
-        )
-    };
-    
-    let actual_mask = if mark_result == -1 {
-        let errno = get_errno();
-        println!("❌ Failed to enable FAN_ATTRIB metadata monitoring: errno = {}", errno);
-        match errno {
-            libc::EINVAL => {
-                println!("💔 EINVAL - This should be FIXED now with FAN_REPORT_FID!");
-                println!("   If still failing, check kernel version (need Linux 5.1+)");
-                println!("   or file system support for file handles");
-            }
-            libc::EPERM => {
-                println!("🔒 EPERM - Need root privileges or CAP_SYS_ADMIN");
+    // Action rules: incrontabs from the command line come after the configured rules
+    let (mut action_rules, mut action_settings) = config.actions()?;
+    for table in &opts.incrontabs {
+        let text = fs::read_to_string(table).map_err(|e| format!("{}: {}", table.display(), e))?;
+        action_rules.extend(parse_incrontab(&text, table)?);
+    }
+    if let Some(command) = &opts.on_fs_error {
+        action_rules.push(ActionRule {
+            source: "--on-fs-error".to_string(),
+            pattern: Pattern::Glob("**".to_string()),
+            mask: FAN_FS_ERROR,
+            command: ActionCommand::Shell(command.clone()),
+        });
+    }
+    if let Some(max) = opts.max_actions {
+        action_settings.max_running = max;
+    }
+
+    // Paths on filesystems fanotify cannot watch properly are polled instead
+    let poll_interval = opts.poll_interval_ms.or(config.poll.interval_ms).map_or(DEFAULT_POLL_INTERVAL, Duration::from_millis);
+    let forced_polls: Vec<PathBuf> = opts.poll.iter().chain(&config.poll.paths).cloned().collect();
+    let mut polled_roots = Vec::new();
+    let recursive_mask = opts.recursive_mask()?;
+    for (paths, mask) in [(&mut opts.mounts, FAN_OPEN | FAN_MODIFY | FAN_CLOSE_WRITE), (&mut opts.recursive, recursive_mask)] {
+        paths.retain(|path| match poll_reason(path, &forced_polls) {
+            Some(reason) => {
+                polled_roots.push((path.clone(), mask, true, reason));
+                false
             }
-            libc::ENOTDIR => {
-                println!("📁 ENOTDIR - File handle not supported on this filesystem");
+            None => true,
+        });
+    }
+    let mut specs = config.mark_specs()?;
+    specs.retain(|spec| match poll_reason(&spec.path, &forced_polls).filter(|_| spec.ignore.is_none()) {
+        Some(reason) => {
+            polled_roots.push((spec.path.clone(), spec.mask, spec.mark_type != FAN_MARK_INODE, reason));
+            false
+        }
+        None => true,
+    });
+    let mut marked_rules = Vec::new();
+    for rule in &action_rules {
+        match &rule.pattern {
+            Pattern::Watched(path) if let Some(reason) = poll_reason(path, &forced_polls) => {
+                polled_roots.push((path.clone(), rule.mask, false, reason));
+                println!("⚙️  Action {}", rule);
             }
-            _ => {
-                println!("🚫 Other error: errno {}", errno);
+            _ => marked_rules.push(rule),
+        }
+    }
+    let mut polling = PollingSource::new(poll_interval)?;
+    for (path, mask, recursive, reason) in polled_roots {
+        match polling.watch(&path, mask, recursive) {
+            Ok(count) => println!("🔁 Polling {} every {:?} ({}): {} path(s) (mask 0x{:x})", path.display(), poll_interval, reason, count, mask),
+            Err(e) => {
+                eprintln!("✗ Failed to poll {}: {}", path.display(), e);
+                return Err(e.into());
             }
         }
-        
-        println!("⚠️  FALLBACK: Attempting basic monitoring without metadata detection...");
-        let basic_result = unsafe {
-            fanotify_mark(
-                fanotify_fd,
-                FAN_MARK_ADD,
-                mask_fallback,
-                AT_FDCWD,
-                path_cstr.as_ptr(),
-            )
+    }
+
+    let Watches { marks, fs_errors, mut canaries, mut follower, actual_mask, mut sticky, mut pending, mut trees, mut ready, mut applied_specs } = match &mut inotify {
+        Some(source) => setup_inotify(source, &opts, test_file_path, mask_metadata_focused, &marked_rules, specs)?,
+        None => setup_fanotify(fanotify_fd, &opts, test_file_path, mask_metadata_focused, mask_fallback, &marked_rules, specs)?,
+    };
+    let mut actions = (!action_rules.is_empty()).then(|| {
+        println!(
+            "⚙️  Running at most {} action(s) at once, timeout {:?}, environment {:?}",
+            action_settings.max_running, action_settings.timeout, action_settings.env
+        );
+        ActionRunner::new(action_rules, action_settings)
+    });
+
+    // From here on the MarkSet is shared with the control socket
+    let state = Arc::new(ControlState::new(marks));
+    *state.filter.lock().unwrap() = config.filter()?;
+
+    let mut sources: Vec<Box<dyn EventSource>> = match inotify {
+        Some(source) => vec![Box::new(source)],
+        None => vec![Box::new(FanotifySource::new(fanotify_fd, Arc::clone(&state)))],
+    };
+    let semantics = sources[0].semantics();
+    if !polling.is_empty() {
+        sources.push(Box::new(polling));
+    }
+    for source in &sources {
+        println!("🔌 Event source: {} ({})", source.name(), source.semantics());
+    }
+
+    // Every event gets the filesystem and mount its fsid belongs to
+    let mut registry = MountRegistry::scan().unwrap_or_else(|e| {
+        eprintln!("✗ Failed to read the mount table: {}", e);
+        MountRegistry::default()
+    });
+    println!("DEBUG: {} filesystem(s) known by fsid", registry.len());
+    for fs in registry.filesystems() {
+        println!("DEBUG:   {}", fs);
+    }
+
+    // Mounts and unmounts come from a group of their own marking our mount namespace
+    let mut mount_watch = None;
+    if opts.mount_events || opts.follow_mounts {
+        let watcher = MountWatcher::new(Path::new(OWN_NAMESPACE)).map_err(|e| format!("mount events unavailable: {}", e))?;
+        println!("🗻 Watching mounts in {} ({} mount(s) now)", watcher.namespace().display(), watcher.len());
+        mount_watch = Some(watcher);
+    }
+    // Without mount events the registry follows the mount table by polling it
+    let table_watch = match mount_watch {
+        Some(_) => None,
+        None => MountTableWatch::open()
+            .inspect_err(|e| eprintln!("✗ Failed to watch the mount table, new mounts stay unknown: {}", e))
+            .ok(),
+    };
+
+    // Allowlisting answers FAN_OPEN_EXEC_PERM on its own group and thread
+    if opts.allowlist.is_some() || opts.allowlist_learn.is_some() {
+        let db = match &opts.allowlist {
+            Some(path) => TrustDb::load(path)?,
+            None => TrustDb::default(),
         };
-        
-        if basic_result == -1 {
-            let errno = get_errno();
-            eprintln!("💥 FATAL: Complete failure - cannot even monitor basic file events: errno = {}", errno);
-            unsafe { libc::close(fanotify_fd) };
-            return Err(format!("fanotify_mark failed completely with errno {}", errno).into());
+        let fallback = Verdict::parse_fail_mode(opts.allowlist_fail.as_deref().unwrap_or("closed"))
+            .map_err(|e| format!("--allowlist-fail: {}", e))?;
+        let deadline = opts.allowlist_deadline_ms.map_or(ALLOWLIST_DEADLINE, Duration::from_millis);
+        let allowlist = Allowlist::new(db, opts.allowlist_learn.as_deref())?;
+        let mut group = PermissionGroup::new(false).map_err(|e| format!("permission events unavailable: {}", e))?;
+        let watch = match opts.allowlist_watch.is_empty() {
+            true => vec![PathBuf::from("/")],
+            false => opts.allowlist_watch.clone(),
+        };
+        for path in &watch {
+            let kind = group.mark_filesystem(path, FAN_OPEN_EXEC_PERM).map_err(|e| format!("{}: {}", path.display(), e))?;
+            println!("🛡️  Allowlisting execs on {} ({} mark)", path.display(), kind);
         }
-        
-        println!("✅ Fallback successful: Basic file monitoring enabled (NO metadata detection)");
-        
-        // Try to add directory monitoring for FAN_ATTRIB as additional fallback
-        println!("🔍 EXPERIMENTAL: Attempting directory-level FAN_ATTRIB monitoring...");
-        let dir_path_cstr = std::ffi::CString::new("/tmp").unwrap();
-        let dir_result = unsafe {
-            fanotify_mark(
-                fanotify_fd,
-                FAN_MARK_ADD | FAN_MARK_ONLYDIR,
-                FAN_ATTRIB,
-                AT_FDCWD,
-                dir_path_cstr.as_ptr(),
-            )
+        match &opts.allowlist_learn {
+            Some(learn) => println!("🛡️  LEARNING mode: nothing is denied, would-be denials go to {}", learn.display()),
+            None => println!("🛡️  ENFORCING: {} trusted file(s)", allowlist.trusted()),
+        }
+        println!(
+            "🛡️  Decisions taking longer than {:?} fail {}",
+            deadline,
+            if fallback == Verdict::Allow { "open" } else { "closed" }
+        );
+        PermissionDaemon::new(group, Arc::new(allowlist), deadline, fallback)?.spawn()?;
+    }
+
+    // Protected paths answer FAN_OPEN_PERM on a group of their own, audited when allowed
+    if !opts.protect.is_empty() {
+        let exceptions = opts
+            .protect_allow
+            .iter()
+            .map(|rule| Exception::parse(rule).map_err(|e| format!("--protect-allow: {}", e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let protector = Protector::new(opts.protect.clone(), exceptions);
+        let mut group = PermissionGroup::new(true).map_err(|e| format!("permission events unavailable: {}", e))?;
+        let marks = protector.mark(&mut group)?;
+        for path in protector.paths() {
+            println!("🔒 Protecting {} against writes", path.display());
+        }
+        println!("DEBUG: {} FAN_OPEN_PERM mark(s) for protected paths", marks);
+        for exception in protector.exceptions() {
+            println!("🔒 Exception: {}", exception);
+        }
+        if !group.audit() {
+            println!("⚠️  FAN_ENABLE_AUDIT refused (needs CAP_AUDIT_WRITE): denials are only logged here");
+        }
+        PermissionDaemon::new(group, Arc::new(protector), ALLOWLIST_DEADLINE, Verdict::Deny)?.spawn()?;
+    }
+
+    // Interactive decisions: the operator answers opens and execs below --prompt paths
+    if !opts.prompt.is_empty() {
+        let default = match opts.prompt_default.as_deref().unwrap_or("deny") {
+            "allow" => Verdict::Allow,
+            "deny" => Verdict::Deny,
+            other => return Err(format!("--prompt-default: expected allow or deny, got {}", other).into()),
         };
-        
-        if dir_result == 0 {
-            println!("✨ SUCCESS: Directory-level FAN_ATTRIB monitoring enabled!");
-            println!("   This may detect some metadata changes at directory level");
-            mask_fallback | FAN_ATTRIB
-        } else {
-            println!("❌ Directory-level FAN_ATTRIB also failed: errno = {}", get_errno());
-            mask_fallback
+        let timeout = opts.prompt_timeout_secs.map_or(PROMPT_TIMEOUT, Duration::from_secs);
+        let prompter = Prompter::new(opts.prompt_rules.as_deref(), timeout, default)?;
+        let mut group = PermissionGroup::new(false).map_err(|e| format!("permission events unavailable: {}", e))?;
+        for path in &opts.prompt {
+            let marks = group.mark_tree(path, FAN_OPEN_PERM | FAN_OPEN_EXEC_PERM)?;
+            println!("❓ Prompting for opens and execs below {} ({} mark(s))", path.display(), marks);
         }
-    } else {
-        println!("🎉 SUCCESS: FAN_ATTRIB metadata monitoring is ACTIVE!");
-        println!("✨ This system fully supports metadata change detection");
-        mask_metadata_focused
+        println!("❓ {} stored rule(s); unanswered prompts {} after {:?}", prompter.rules(), default, timeout);
+        PermissionDaemon::new(group, Arc::new(prompter), timeout, default)?.spawn()?;
+    }
+
+    // Placeholders are filled in on FAN_PRE_ACCESS by a pre-content group
+    if let Some(root) = &opts.hsm {
+        let backing = opts.hsm_backing.as_deref().ok_or("--hsm needs --hsm-backing")?;
+        let backing = Backing::open(backing).map_err(|e| format!("{}: {}", backing.display(), e))?;
+        let hydrator = Hydrator::new(root.clone(), backing);
+        println!("💧 HSM: {} backed by {}", root.display(), hydrator.backing().describe());
+        if opts.hsm_checkout {
+            let created = hydrator.checkout().map_err(|e| format!("checkout into {}: {}", root.display(), e))?;
+            println!("💧 Checked out {} placeholder(s)", created);
+        }
+        let mut group = PermissionGroup::pre_content().map_err(|e| format!("pre-content events unavailable: {}", e))?;
+        let marks = hydrator.mark(&mut group).map_err(|e| match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP | libc::EINVAL) => format!("{}: filesystem without pre-content events: {}", root.display(), e),
+            _ => format!("{}: {}", root.display(), e),
+        })?;
+        println!("💧 {} placeholder(s) to fill on first access", marks);
+        PermissionDaemon::new(group, Arc::new(hydrator), HSM_FILL_DEADLINE, Verdict::Deny)?.spawn()?;
+    }
+
+    // Command line flags win over the configuration file
+    let control_socket = opts.control_socket.clone().or_else(|| config.output.control_socket.clone());
+    let control_group = opts.control_group.clone().or_else(|| config.output.control_group.clone());
+    let subscribe_socket = opts.subscribe_socket.clone().or_else(|| config.output.subscribe_socket.clone());
+    if control_socket.is_some() && !semantics.marks {
+        println!("⚠️  Control socket commands that change marks fail with the inotify backend");
+    }
+    if let Some(socket_path) = &control_socket {
+        let admin_gid = control_group.as_deref().map(resolve_group).transpose()?;
+        let server = ControlServer::bind(socket_path, Arc::clone(&state), admin_gid)?;
+        println!("🎛️  Control socket listening on {} (admin gid: {:?})", server.path().display(), admin_gid);
+        server.spawn()?;
+    }
+
+    // One read loop, many consumers: fan events out to socket subscribers
+    let subscriptions = match &subscribe_socket {
+        Some(socket_path) => {
+            let gid = control_group.as_deref().map(resolve_group).transpose()?;
+            let server = SubscriptionServer::start(socket_path, gid)?;
+            println!("📡 Subscription socket listening on {}", server.path().display());
+            Some(server)
+        }
+        None => None,
     };
-    
+
     println!("\n🎯 === METADATA MONITORING STATUS ===");
     if actual_mask & FAN_ATTRIB != 0 {
         println!("✅ METADATA MONITORING: ✨ FULLY ACTIVE ✨");
@@ -329,62 +982,339 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("⚠️  READY: Waiting for file events (metadata changes will be missed)...\n");
     }
 
+    // Optional coalescing stage; command line flags win over [coalesce]
+    let mut coalesce = config.coalesce()?;
+    if let Some(ms) = opts.coalesce_ms {
+        let window = Duration::from_millis(ms);
+        let policies = coalesce.take().map(|c| c.policies).unwrap_or_default();
+        coalesce = Some(CoalesceConfig { policies, ..CoalesceConfig::new(window) });
+    }
+    if let Some(list) = &opts.coalesce_policies {
+        let Some(coalesce) = &mut coalesce else {
+            return Err("--coalesce-policy needs --coalesce or a [coalesce] window".into());
+        };
+        // Listed first, so they take precedence over the configuration file
+        let mut policies = policies_from_list(list)?;
+        policies.append(&mut coalesce.policies);
+        coalesce.policies = policies;
+    }
+    let mut coalescer = coalesce.map(|c| {
+        println!("🧮 Coalescing events per object: window {:?}, max delay {:?}", c.window, c.max_delay);
+        Coalescer::new(c, MonotonicClock::new())
+    });
+
+    let mut saves = (opts.detect_saves || config.saves.enabled).then(|| {
+        let window = config.saves.window_ms.map_or(DEFAULT_SAVE_WINDOW, Duration::from_millis);
+        println!("💾 Detecting editor save sequences (window {:?})", window);
+        SaveDetector::new(window, MonotonicClock::new())
+    });
+
     let mut event_count = 0;
-    println!("DEBUG: Entering event loop, waiting for fanotify events...");
-    
-    // Event buffer
-    const BUF_SIZE: usize = 4096;
-    let mut buffer = [0u8; BUF_SIZE];
+    let names: Vec<&str> = sources.iter().map(|source| source.name()).collect();
+    println!("DEBUG: Entering event loop, waiting for {} events...", names.join(" and "));
     
-    loop {
-        println!("DEBUG: Calling read() on fanotify fd...");
-        let bytes_read = unsafe {
-            libc::read(fanotify_fd, buffer.as_mut_ptr() as *mut libc::c_void, BUF_SIZE)
-        };
-        
-        if bytes_read == -1 {
+    let mut stopping = false;
+    'events: loop {
+        if stopping {
+            break;
+        }
+        // Wait for events, a SIGHUP reload request, a stop signal or the next coalescing deadline
+        let deadlines = [
+            coalescer.as_ref().and_then(Coalescer::next_deadline),
+            saves.as_ref().and_then(SaveDetector::next_deadline),
+            actions.as_ref().and_then(ActionRunner::next_deadline),
+            exec_inventory.as_ref().and_then(ExecInventory::next_deadline),
+        ];
+        let timeout = deadlines.into_iter().flatten().min().map_or(-1, poll_timeout);
+        let mut fds: Vec<libc::pollfd> =
+            sources.iter().map(|source| libc::pollfd { fd: source.fd(), events: libc::POLLIN, revents: 0 }).collect();
+        if let Some(signals) = &reload_signal {
+            fds.push(libc::pollfd { fd: signals.as_raw_fd(), events: libc::POLLIN, revents: 0 });
+        }
+        let mount_fd = mount_watch.as_ref().map(|watcher| {
+            fds.push(libc::pollfd { fd: watcher.fd(), events: libc::POLLIN, revents: 0 });
+            fds.len() - 1
+        });
+        let table_fd = table_watch.as_ref().map(|table| {
+            fds.push(libc::pollfd { fd: table.fd(), events: libc::POLLPRI, revents: 0 });
+            fds.len() - 1
+        });
+        let stop_fd = fds.len();
+        fds.push(libc::pollfd { fd: stop_signal.as_raw_fd(), events: libc::POLLIN, revents: 0 });
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } == -1 {
             let errno = get_errno();
-            match errno {
-                libc::EINTR => {
-                    println!("DEBUG: EINTR - Interrupted system call, this is normal");
-                    continue;
+            if errno == libc::EINTR {
+                continue;
+            }
+            eprintln!("✗ poll() failed: errno = {}", errno);
+            break;
+        }
+        if let (Some(signals), Some(config_path)) = (&reload_signal, &opts.config)
+            && fds[sources.len()].revents & libc::POLLIN != 0
+        {
+            while let Some(signal) = signals.read() {
+                if signal == libc::SIGHUP {
+                    reload_config(config_path, &mut config, &mut applied_specs, &state, semantics.marks, &forced_polls);
                 }
-                libc::EAGAIN => {
-                    println!("DEBUG: EAGAIN - No events available right now");
+            }
+        }
+        if let (Some(watcher), Some(index)) = (&mut mount_watch, mount_fd)
+            && fds[index].revents & libc::POLLIN != 0
+        {
+            match watcher.read() {
+                Ok(changes) => {
+                    for change in changes {
+                        println!("\n🗻 [{}] {}", if change.attached() { "MNT_ATTACH" } else { "MNT_DETACH" }, change);
+                        if let Some(server) = &subscriptions {
+                            server.publish_with(change.mask, 0, change.mount_point(), || change.to_json());
+                        }
+                        match follower.handle(&mut state.marks.lock().unwrap(), &change) {
+                            Ok(Some(followed)) => println!("🗻 {}", followed),
+                            Ok(None) => {}
+                            Err(e) => eprintln!("✗ Failed to follow {}: {}", change, e),
+                        }
+                    }
+                    if let Err(e) = registry.mounts_changed() {
+                        eprintln!("✗ Failed to read the mount table: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("✗ Error reading mount events: {}", e),
+            }
+        }
+        if let Some(index) = table_fd
+            && fds[index].revents & (libc::POLLPRI | libc::POLLERR) != 0
+        {
+            println!("DEBUG: Mount table changed, rescanning filesystems");
+            if let Err(e) = registry.mounts_changed() {
+                eprintln!("✗ Failed to read the mount table: {}", e);
+            }
+        }
+        if fds[stop_fd].revents & libc::POLLIN != 0 {
+            while let Some(signal) = stop_signal.read() {
+                println!("\n🛑 Received {}, flushing pending events and stopping", if signal == libc::SIGINT { "SIGINT" } else { "SIGTERM" });
+                stopping = true;
+            }
+        }
+        let readable: Vec<bool> = fds.iter().take(sources.len()).map(|fd| fd.revents & libc::POLLIN != 0).collect();
+
+        // Nothing to read when only a coalescing deadline passed
+        let mut events = Vec::new();
+        for (source, _) in sources.iter_mut().zip(&readable).filter(|(_, readable)| **readable) {
+            println!("DEBUG: Reading events from {}...", source.name());
+            match source.read() {
+                Ok(read) => events.extend(read),
+                Err(e) => match e.raw_os_error() {
+                    Some(libc::EINTR) => println!("DEBUG: EINTR - Interrupted system call, this is normal"),
+                    Some(libc::EAGAIN) => println!("DEBUG: EAGAIN - No events available right now"),
+                    _ => {
+                        eprintln!("✗ Error reading {} events: {}", source.name(), e);
+                        break 'events;
+                    }
+                },
+            }
+            state.stats.reads.fetch_add(1, Ordering::Relaxed);
+        }
+
+        for event in &mut events {
+            registry.attach(event);
+        }
+        if !canaries.is_empty() {
+            for alert in canaries.check(&events) {
+                println!("\n🪤 [CANARY] {}", alert);
+                let process = &alert.process;
+                if !process.ancestors.is_empty() {
+                    let ancestry: Vec<String> = process.ancestors.iter().map(|(pid, comm)| format!("{} ({})", comm, pid)).collect();
+                    println!("   ancestry: {}", ancestry.join(" ← "));
+                }
+                if !process.cmdline.is_empty() {
+                    println!("   cmdline:  {}", process.cmdline.join(" "));
+                }
+                if let Some(cwd) = &process.cwd {
+                    println!("   cwd:      {}", cwd.display());
+                }
+                if let Some(server) = &subscriptions {
+                    server.publish_with(alert.mask, process.pid, Some(&alert.decoy), || alert.to_json());
+                }
+            }
+        }
+        if !fs_errors.is_empty() {
+            for event in &mut events {
+                let Some(report) = fs_errors.report(event) else {
                     continue;
+                };
+                println!("\n🚨 [FS_ERROR] {}", report);
+                if let Some(server) = &subscriptions {
+                    server.publish_with(FAN_FS_ERROR, report.pid, event.path.as_deref(), || report.to_json());
+                }
+            }
+        }
+        if !sticky.is_empty() {
+            let mut marks = state.marks.lock().unwrap();
+            for event in &events {
+                for change in sticky.iter_mut().filter_map(|watch| watch.handle(&mut marks, event)) {
+                    println!("♻️  {}", change);
+                    if let Some(server) = &subscriptions {
+                        server.publish_with(change.mask(), change.pid(), Some(change.path()), || change.to_json());
+                    }
                 }
-                _ => {
-                    eprintln!("✗ Error reading fanotify events: errno = {}", errno);
-                    break;
+            }
+        }
+        if !pending.is_empty() {
+            let mut marks = state.marks.lock().unwrap();
+            for event in &events {
+                for change in pending.iter_mut().flat_map(|watch| watch.handle(&mut marks, event)) {
+                    println!("⏳ {}", change);
+                    if let Some(server) = &subscriptions {
+                        server.publish_with(change.mask(), event.pid, Some(change.path()), || change.to_json());
+                    }
                 }
             }
         }
-        
-        if bytes_read == 0 {
-            println!("DEBUG: read() returned 0, continuing...");
-            continue;
+        if !trees.is_empty() {
+            let mut marks = state.marks.lock().unwrap();
+            for event in &events {
+                for tree in &mut trees {
+                    match tree.handle(&mut marks, event) {
+                        Ok(Some(change)) => println!("🌲 {}: {}", tree.root().display(), change),
+                        Ok(None) => {}
+                        Err(e) => eprintln!("✗ Recursive watch on {}: {}", tree.root().display(), e),
+                    }
+                }
+            }
+        }
+        if !ready.dirs().is_empty() {
+            for event in &events {
+                match ready.handle(event) {
+                    Some(Ok(file)) => {
+                        println!("\n📥 [READY] {}", file);
+                        if let Some(server) = &subscriptions {
+                            server.publish_with(FAN_CLOSE_WRITE, file.pid, Some(&file.path), || file.to_json());
+                        }
+                    }
+                    Some(Err(e)) => eprintln!("✗ Ready file: {}", e),
+                    None => {}
+                }
+            }
+        }
+        if let Some(runner) = &mut actions {
+            for event in &events {
+                runner.handle(event);
+            }
+            for result in runner.poll() {
+                if result.success() {
+                    println!("\n⚙️  [ACTION] {}", result);
+                } else {
+                    println!("\n⚠️  [ACTION] {}", result);
+                }
+                for (stream, output) in [("stdout", &result.stdout), ("stderr", &result.stderr)] {
+                    for line in output.lines() {
+                        println!("   {}: {}", stream, line);
+                    }
+                }
+                if let Some(server) = &subscriptions {
+                    server.publish_with(result.mask, result.pid, Some(&result.path), || result.to_json());
+                }
+            }
+        }
+        if let Some(inventory) = &mut exec_inventory {
+            let mut new = Vec::new();
+            let marks = state.marks.lock().unwrap();
+            for event in &events {
+                new.extend(inventory.record(event, marks.resolver()));
+            }
+            drop(marks);
+            new.extend(inventory.poll());
+            for entry in new {
+                println!("\n📦 [EXEC] New in inventory: {}", entry);
+                if let Some(server) = &subscriptions {
+                    server.publish_with(FAN_OPEN_EXEC, 0, Some(&entry.path), || entry.to_json());
+                }
+            }
+            if let Err(e) = inventory.save_if_due() {
+                eprintln!("✗ Failed to save exec inventory {}: {}", inventory.file().display(), e);
+            }
         }
         
-        println!("DEBUG: Read {} bytes from fanotify", bytes_read);
-        
-        // Parse events from buffer
-        let mut offset = 0;
-        while offset < bytes_read as usize {
-            if offset + mem::size_of::<FanotifyEventMetadata>() > bytes_read as usize {
-                println!("DEBUG: Incomplete event data, breaking");
-                break;
+        state.stats.events.fetch_add(events.len() as u64, Ordering::Relaxed);
+
+        // Replace editor save sequences with single "saved" records
+        let mut raw = Vec::new();
+        let mut saved = Vec::new();
+        let outputs: Vec<SaveOutput> = match &mut saves {
+            Some(detector) => {
+                let mut outputs: Vec<SaveOutput> = events.into_iter().flat_map(|event| detector.push(event)).collect();
+                outputs.extend(detector.expire());
+                outputs
             }
-            
-            let event: &FanotifyEventMetadata = unsafe {
-                &*(buffer.as_ptr().add(offset) as *const FanotifyEventMetadata)
-            };
-            
+            None => events.into_iter().map(SaveOutput::Raw).collect(),
+        };
+        for output in outputs {
+            match output {
+                SaveOutput::Raw(event) => raw.push(event),
+                SaveOutput::Saved(save) => saved.push(save),
+            }
+        }
+        for save in saved {
+            if let Some(server) = &subscriptions {
+                server.publish_with(save.mask, save.pid, Some(&save.path), || save.to_json());
+            }
+            if config.output.console && state.filter.lock().unwrap().matches(save.mask, save.pid, Some(&save.path)) {
+                println!("\n💾 [SAVED] {}", save);
+            }
+        }
+
+        // Merge bursts for the same object into single records
+        let mut records = Vec::new();
+        for event in raw {
+            match &mut coalescer {
+                Some(coalescer) => records.extend(coalescer.push(event)),
+                None => records.push(Coalesced::single(event, Duration::ZERO)),
+            }
+        }
+        // On the way out everything still merging is flushed, not just what expired
+        if let Some(coalescer) = &mut coalescer {
+            records.extend(if stopping { coalescer.drain() } else { coalescer.expire() });
+        }
+
+        for record in records {
+            // Subscribers apply their own filters, so they see every record
+            if let Some(server) = &subscriptions {
+                match &coalescer {
+                    Some(_) => server.publish_with(record.event.mask, record.event.pid, record.event.path.as_deref(), || {
+                        record.to_json()
+                    }),
+                    None => server.publish(&record.event),
+                }
+            }
+            let Coalesced { event, count, pids, first, last } = record;
+
+            // Apply the filters set over the control socket before printing anything
+            if !state.filter.lock().unwrap().matches(event.mask, event.pid, event.path.as_deref()) {
+                state.stats.filtered.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            if !config.output.console {
+                continue;
+            }
+
             event_count += 1;
             println!("\n=== EVENT #{} ===", event_count);
+            if count > 1 {
+                println!("🧮 Coalesced {} raw events from pid(s) {:?} over {} ms", count, pids, (last - first).as_millis());
+            }
             println!("DEBUG: Raw event: {:?}", event);
             println!("DEBUG: Event mask: 0x{:x}", event.mask);
             println!("DEBUG: Event PID: {}", event.pid);
-            println!("DEBUG: Event FD: {}", event.fd);
+            if config.enrich.process {
+                let comm = fs::read_to_string(format!("/proc/{}/comm", event.pid)).unwrap_or_default();
+                let exe = fs::read_link(format!("/proc/{}/exe", event.pid)).unwrap_or_default();
+                println!("👤 Process: {} ({})", comm.trim(), exe.display());
+            }
+            if let Some(fs) = &event.fs {
+                let mount = fs.mount_for(event.path.as_deref());
+                println!("🗄️  Filesystem: {} (mount {} at {})", fs, mount.mount_id, mount.mount_point.display());
+            }
+            println!("DEBUG: Event FD: {}", event.raw_fd());
             
             // Decode individual mask flags with METADATA EMPHASIS
             println!("🎯 METADATA FOCUS - Mask flag analysis:");
@@ -393,25 +1323,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             println!("  📝 FAN_MODIFY: {}", event.mask & FAN_MODIFY != 0);
             println!("  💾 FAN_CLOSE_WRITE: {}", event.mask & FAN_CLOSE_WRITE != 0);
             
-            // Get file path
-            let path_info = if event.fd >= 0 {
-                println!("DEBUG: Event has file descriptor: {}", event.fd);
-                let link = format!("/proc/self/fd/{}", event.fd);
-                println!("DEBUG: Attempting to resolve path via: {}", link);
-                match fs::read_link(&link) {
-                    Ok(path) => {
-                        let path_str = path.display().to_string();
-                        println!("DEBUG: ✓ Resolved path: {}", path_str);
-                        format!("path={}", path_str)
-                    },
-                    Err(e) => {
-                        println!("DEBUG: ✗ Failed to resolve path for fd {}: {}", event.fd, e);
-                        format!("fd={}", event.fd)
-                    },
+            // Get file path (from the event fd or the FID info records)
+            let path_info = match &event.path {
+                Some(path) => {
+                    println!("DEBUG: ✓ Resolved path: {}", path.display());
+                    format!("path={}", path.display())
+                }
+                None if event.raw_fd() >= 0 => {
+                    println!("DEBUG: ✗ Failed to resolve path for fd {}", event.raw_fd());
+                    format!("fd={}", event.raw_fd())
+                }
+                None => {
+                    println!("DEBUG: ⚠ No file descriptor or resolvable file handle in event");
+                    "path=unknown".to_string()
                 }
-            } else {
-                println!("DEBUG: ⚠ Invalid file descriptor in event: {}", event.fd);
-                "path=unknown".to_string()
             };
 
             // Print event summary with METADATA PRIORITY
@@ -444,6 +1369,31 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("� [CLOSE_WRITE] pid={} {} - Writable file was closed", event.pid, path_info);
                 event_types.push("CLOSE_WRITE");
             }
+            if event.mask & FAN_OPEN_EXEC != 0 {
+                println!("� [OPEN_EXEC] pid={} {} - File was opened for execution", event.pid, path_info);
+                event_types.push("OPEN_EXEC");
+            }
+            let kind = if event.is_dir() { "Directory" } else { "File" };
+            if event.mask & FAN_CREATE != 0 {
+                println!("� [CREATE] pid={} {} - {} was created", event.pid, path_info, kind);
+                event_types.push("CREATE");
+            }
+            if event.mask & FAN_DELETE != 0 {
+                println!("� [DELETE] pid={} {} - {} was deleted", event.pid, path_info, kind);
+                event_types.push("DELETE");
+            }
+            if event.mask & FAN_MOVED_FROM != 0 {
+                println!("� [MOVED_FROM] pid={} {} - {} was moved away", event.pid, path_info, kind);
+                event_types.push("MOVED_FROM");
+            }
+            if event.mask & FAN_MOVED_TO != 0 {
+                println!("� [MOVED_TO] pid={} {} - {} was moved here", event.pid, path_info, kind);
+                event_types.push("MOVED_TO");
+            }
+            if event.mask & FAN_FS_ERROR != 0 {
+                println!("🚨 [FS_ERROR] pid={} {} - Filesystem reported an error", event.pid, path_info);
+                event_types.push("FS_ERROR");
+            }
             
             if event_types.is_empty() {
                 println!("❓ [UNKNOWN] pid={} {} - Unrecognized event type (mask: 0x{:x})", event.pid, path_info, event.mask);
@@ -454,7 +1404,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             println!("📊 Event types detected: {}", event_types.join(", "));
             
             // Try to get current file status for comparison
-            if let Ok(path_str) = path_info.strip_prefix("path=").ok_or("") {
+            if let Some(path_str) = path_info.strip_prefix("path=").filter(|_| config.enrich.file_status) {
                 match std::fs::metadata(path_str) {
                     Ok(metadata) => {
                         println!("📁 Current file status:");
@@ -469,19 +1419,27 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
             
             // Close the event file descriptor
-            if event.fd >= 0 {
-                unsafe { libc::close(event.fd) };
-                println!("DEBUG: Closed event file descriptor {}", event.fd);
+            if let Some(fd) = event.fd {
+                println!("DEBUG: Closed event file descriptor {}", fd.as_raw_fd());
+                drop(fd);
             }
             
             println!("==========================================");
-            
-            // Move to next event
-            offset += event.event_len as usize;
         }
     }
     
     // Clean up
+    if let Some(socket_path) = &control_socket {
+        let _ = fs::remove_file(socket_path);
+    }
+    if let Some(server) = &subscriptions {
+        let _ = fs::remove_file(server.path());
+    }
+    if let Some(inventory) = &mut exec_inventory
+        && let Err(e) = inventory.save()
+    {
+        eprintln!("✗ Failed to save exec inventory {}: {}", inventory.file().display(), e);
+    }
     unsafe { libc::close(fanotify_fd) };
     println!("DEBUG: Closed fanotify file descriptor");
     
@@ -0,0 +1,587 @@
+// Safe helpers around fanotify_mark(2): ignore marks and the MarkSet manager.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::event::PathResolver;
+use crate::sys::*;
+
+/// Converts a path into the C string expected by fanotify_mark(2).
+pub fn path_cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))
+}
+
+/// Calls fanotify_mark(2) on `path` relative to the current directory.
+pub fn mark_path(fanotify_fd: libc::c_int, flags: u32, mask: u64, path: &Path) -> io::Result<()> {
+    let path_cstr = path_cstring(path)?;
+    let result = unsafe { fanotify_mark(fanotify_fd, flags, mask, AT_FDCWD, path_cstr.as_ptr()) };
+    if result == -1 {
+        return Err(io::Error::from_raw_os_error(get_errno()));
+    }
+    Ok(())
+}
+
+/// Which kernel interface an ignore mark was installed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IgnoreMode {
+    /// `FAN_MARK_IGNORE` (Linux 6.0+): the mask honours `FAN_ONDIR` and
+    /// `FAN_EVENT_ON_CHILD`, so a directory can silence its children.
+    Ignore,
+    /// Legacy `FAN_MARK_IGNORED_MASK`, used when the kernel rejects `FAN_MARK_IGNORE`.
+    IgnoredMask,
+}
+
+impl IgnoreMode {
+    fn flags(self, surv_modify: bool) -> u32 {
+        let base = match self {
+            IgnoreMode::Ignore => FAN_MARK_IGNORE,
+            IgnoreMode::IgnoredMask => FAN_MARK_IGNORED_MASK,
+        };
+        if surv_modify { base | FAN_MARK_IGNORED_SURV_MODIFY } else { base }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            IgnoreMode::Ignore => "FAN_MARK_IGNORE",
+            IgnoreMode::IgnoredMask => "FAN_MARK_IGNORED_MASK",
+        }
+    }
+}
+
+/// Events for `path` that should be dropped even when another mark (typically
+/// a mount or filesystem mark) would report them.
+#[derive(Debug, Clone)]
+pub struct IgnoreMark {
+    /// Events to silence.
+    pub mask: u64,
+    /// `FAN_MARK_INODE`, `FAN_MARK_MOUNT` or `FAN_MARK_FILESYSTEM`.
+    pub mark_type: u32,
+    /// Keep ignoring after the file is modified. Without this flag the kernel
+    /// clears the ignore mask on the first write, which defeats the purpose for
+    /// databases and logs.
+    pub surv_modify: bool,
+}
+
+impl IgnoreMark {
+    pub fn new(mask: u64) -> Self {
+        IgnoreMark { mask, mark_type: FAN_MARK_INODE, surv_modify: true }
+    }
+
+    // FAN_MARK_IGNORE refuses directories without FAN_ONDIR (EISDIR); for a
+    // directory we also silence its direct children.
+    fn mask_for(&self, path: &Path, mode: IgnoreMode) -> u64 {
+        match mode {
+            IgnoreMode::Ignore if self.mark_type == FAN_MARK_INODE && path.is_dir() => {
+                self.mask | FAN_ONDIR | FAN_EVENT_ON_CHILD
+            }
+            IgnoreMode::Ignore => self.mask,
+            IgnoreMode::IgnoredMask => self.mask & !(FAN_ONDIR | FAN_EVENT_ON_CHILD),
+        }
+    }
+
+    /// Installs the ignore mark, preferring `FAN_MARK_IGNORE` and falling back
+    /// to `FAN_MARK_IGNORED_MASK` on kernels that do not know it.
+    pub fn add(&self, fanotify_fd: libc::c_int, path: &Path) -> io::Result<IgnoreMode> {
+        match self.add_with(fanotify_fd, path, IgnoreMode::Ignore) {
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                self.add_with(fanotify_fd, path, IgnoreMode::IgnoredMask)?;
+                Ok(IgnoreMode::IgnoredMask)
+            }
+            Err(e) => Err(e),
+            Ok(()) => Ok(IgnoreMode::Ignore),
+        }
+    }
+
+    /// Installs the ignore mark with an explicit interface.
+    pub fn add_with(&self, fanotify_fd: libc::c_int, path: &Path, mode: IgnoreMode) -> io::Result<()> {
+        let flags = FAN_MARK_ADD | self.mark_type | mode.flags(self.surv_modify);
+        mark_path(fanotify_fd, flags, self.mask_for(path, mode), path)
+    }
+
+    /// Removes the ignore mask bits previously installed with `mode`.
+    pub fn remove(&self, fanotify_fd: libc::c_int, path: &Path, mode: IgnoreMode) -> io::Result<()> {
+        let flags = FAN_MARK_REMOVE | self.mark_type | mode.flags(false);
+        mark_path(fanotify_fd, flags, self.mask_for(path, mode), path)
+    }
+}
+
+/// Identity of a marked object as the kernel sees it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MarkObject {
+    Inode { dev: u64, ino: u64 },
+    Mount { mnt_id: u64 },
+    Filesystem { dev: u64 },
+}
+
+impl MarkObject {
+    /// Resolves the object a mark of `mark_type` on `path` would attach to.
+    pub fn of(path: &Path, mark_type: u32, dont_follow: bool) -> io::Result<MarkObject> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = if dont_follow { path.symlink_metadata()? } else { path.metadata()? };
+        match mark_type {
+            FAN_MARK_MOUNT => Ok(MarkObject::Mount { mnt_id: mount_id(path, dont_follow)? }),
+            FAN_MARK_FILESYSTEM => Ok(MarkObject::Filesystem { dev: metadata.dev() }),
+            FAN_MARK_INODE => Ok(MarkObject::Inode { dev: metadata.dev(), ino: metadata.ino() }),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    pub fn mark_type(&self) -> u32 {
+        match self {
+            MarkObject::Inode { .. } => FAN_MARK_INODE,
+            MarkObject::Mount { .. } => FAN_MARK_MOUNT,
+            MarkObject::Filesystem { .. } => FAN_MARK_FILESYSTEM,
+        }
+    }
+}
+
+impl std::fmt::Display for MarkObject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MarkObject::Inode { dev, ino } => {
+                write!(f, "inode {}:{}/{}", libc::major(*dev), libc::minor(*dev), ino)
+            }
+            MarkObject::Mount { mnt_id } => write!(f, "mount {}", mnt_id),
+            MarkObject::Filesystem { dev } => {
+                write!(f, "filesystem {}:{}", libc::major(*dev), libc::minor(*dev))
+            }
+        }
+    }
+}
+
+/// Mount id of the mount containing `path`, as shown in /proc/self/mountinfo
+/// and in fanotify fdinfo.
+pub fn mount_id(path: &Path, dont_follow: bool) -> io::Result<u64> {
+    let path_cstr = path_cstring(path)?;
+    let flags = if dont_follow { libc::AT_SYMLINK_NOFOLLOW } else { 0 };
+    let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::statx(AT_FDCWD, path_cstr.as_ptr(), flags, libc::STATX_MNT_ID, &mut stx) };
+    if result == -1 {
+        return Err(io::Error::from_raw_os_error(get_errno()));
+    }
+    if stx.stx_mask & libc::STATX_MNT_ID == 0 {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "statx did not report a mount id"));
+    }
+    Ok(stx.stx_mnt_id)
+}
+
+/// A mark the monitor believes is installed.
+#[derive(Debug, Clone)]
+pub struct MarkEntry {
+    /// Path the mark was requested through (first one, if several alias the same object).
+    pub path: PathBuf,
+    pub object: MarkObject,
+    pub mask: u64,
+    pub ignored_mask: u64,
+    /// How many watches asked for each bit of `mask`, by bit.
+    pub refs: HashMap<u64, u32>,
+}
+
+// The single bits set in `mask`
+fn bits(mask: u64) -> impl Iterator<Item = u64> {
+    (0..64).map(|n| 1u64 << n).filter(move |bit| mask & bit != 0)
+}
+
+impl MarkEntry {
+    /// Takes one reference on every bit of `mask`.
+    pub fn acquire(&mut self, mask: u64) {
+        for bit in bits(mask) {
+            *self.refs.entry(bit).or_insert(0) += 1;
+        }
+        self.mask |= mask;
+    }
+
+    /// Bits of `mask` that nobody holds any more once one reference on each
+    /// is released. Bits without a reference count as free.
+    pub fn freed_by(&self, mask: u64) -> u64 {
+        bits(mask & self.mask).filter(|bit| self.refs.get(bit).is_none_or(|n| *n <= 1)).fold(0, |m, bit| m | bit)
+    }
+
+    /// Releases one reference on every bit of `mask`, clearing the bits that
+    /// reach zero. Returns those bits.
+    pub fn release(&mut self, mask: u64) -> u64 {
+        let freed = self.freed_by(mask);
+        for bit in bits(mask & self.mask) {
+            if let Some(n) = self.refs.get_mut(&bit) {
+                *n = n.saturating_sub(1);
+            }
+        }
+        self.refs.retain(|_, n| *n > 0);
+        self.mask &= !freed;
+        freed
+    }
+}
+
+/// A mark as reported by /proc/self/fdinfo/<fanotify fd>.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelMark {
+    pub object: MarkObject,
+    pub mflags: u32,
+    pub mask: u64,
+    pub ignored_mask: u64,
+}
+
+/// Difference between [`MarkSet`] and the kernel's view.
+#[derive(Debug, Clone)]
+pub enum MarkMismatch {
+    /// Tracked by the monitor but not present in the kernel (e.g. the inode was evicted).
+    MissingInKernel(MarkEntry),
+    /// Present in the kernel but not tracked by the monitor.
+    UnknownToMonitor(KernelMark),
+    /// Both sides know the mark but disagree on its masks.
+    MaskDiffers { entry: MarkEntry, kernel: KernelMark },
+}
+
+impl std::fmt::Display for MarkMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MarkMismatch::MissingInKernel(entry) => {
+                write!(f, "{} ({}) is not installed in the kernel", entry.object, entry.path.display())
+            }
+            MarkMismatch::UnknownToMonitor(kernel) => {
+                write!(f, "{} is installed but not tracked (mask 0x{:x})", kernel.object, kernel.mask)
+            }
+            MarkMismatch::MaskDiffers { entry, kernel } => write!(
+                f,
+                "{} ({}) expected mask 0x{:x}/ignored 0x{:x}, kernel has 0x{:x}/0x{:x}",
+                entry.object,
+                entry.path.display(),
+                entry.mask,
+                entry.ignored_mask,
+                kernel.mask,
+                kernel.ignored_mask
+            ),
+        }
+    }
+}
+
+/// Book-keeping for every mark placed on one fanotify group.
+///
+/// All changes go through the set so it always knows which objects are
+/// marked; [`MarkSet::verify`] cross-checks that belief against the kernel.
+pub struct MarkSet {
+    fanotify_fd: libc::c_int,
+    marks: HashMap<MarkObject, MarkEntry>,
+    resolver: PathResolver,
+}
+
+impl MarkSet {
+    pub fn new(fanotify_fd: libc::c_int) -> Self {
+        MarkSet { fanotify_fd, marks: HashMap::new(), resolver: PathResolver::new() }
+    }
+
+    /// Resolver knowing every filesystem that has been marked.
+    pub fn resolver(&self) -> &PathResolver {
+        &self.resolver
+    }
+
+    pub fn resolver_mut(&mut self) -> &mut PathResolver {
+        &mut self.resolver
+    }
+
+    pub fn fanotify_fd(&self) -> libc::c_int {
+        self.fanotify_fd
+    }
+
+    pub fn len(&self) -> usize {
+        self.marks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.marks.is_empty()
+    }
+
+    /// Tracked marks, ordered by path for stable listings.
+    pub fn marks(&self) -> Vec<&MarkEntry> {
+        let mut marks: Vec<_> = self.marks.values().collect();
+        marks.sort_by(|a, b| a.path.cmp(&b.path));
+        marks
+    }
+
+    pub fn get(&self, path: &Path, mark_type: u32) -> Option<&MarkEntry> {
+        let object = MarkObject::of(path, mark_type, false).ok()?;
+        self.marks.get(&object)
+    }
+
+    /// Adds `mask` to the mark of `mark_type` on `path`. `extra_flags` may carry
+    /// `FAN_MARK_ONLYDIR` or `FAN_MARK_DONT_FOLLOW`.
+    pub fn add(&mut self, path: &Path, mark_type: u32, mask: u64, extra_flags: u32) -> io::Result<()> {
+        let object = MarkObject::of(path, mark_type, extra_flags & FAN_MARK_DONT_FOLLOW != 0)?;
+        mark_path(self.fanotify_fd, FAN_MARK_ADD | mark_type | extra_flags, mask, path)?;
+        // Best effort: without it FID events on this filesystem stay pathless
+        let _ = self.resolver.register(path);
+        self.entry(path, object).acquire(mask);
+        Ok(())
+    }
+
+    /// Adds an ignore mark and records the ignored bits.
+    pub fn ignore(&mut self, path: &Path, ignore: &IgnoreMark) -> io::Result<IgnoreMode> {
+        let object = MarkObject::of(path, ignore.mark_type, false)?;
+        let mode = ignore.add(self.fanotify_fd, path)?;
+        self.entry(path, object).ignored_mask |= ignore.mask_for(path, mode);
+        Ok(mode)
+    }
+
+    /// Releases one reference on each bit in `mask`. Only bits nobody else
+    /// added are removed from the kernel; the mark disappears once both masks
+    /// are empty.
+    pub fn remove(&mut self, path: &Path, mark_type: u32, mask: u64) -> io::Result<()> {
+        let object = MarkObject::of(path, mark_type, false)?;
+        let freed = self.marks.get(&object).map_or(mask, |entry| entry.freed_by(mask));
+        if freed != 0 {
+            mark_path(self.fanotify_fd, FAN_MARK_REMOVE | mark_type, freed, path)?;
+        }
+        if let Some(entry) = self.marks.get_mut(&object) {
+            entry.release(mask);
+            if entry.mask == 0 && entry.ignored_mask == 0 {
+                self.marks.remove(&object);
+            }
+        }
+        Ok(())
+    }
+
+    /// Releases one reference on every event bit of a tracked mark and drops
+    /// its ignore mask. Bits other watches still hold stay installed.
+    pub fn remove_object(&mut self, object: &MarkObject) -> io::Result<()> {
+        let Some(entry) = self.marks.get(object).cloned() else {
+            return Ok(());
+        };
+        let mark_type = object.mark_type();
+        let freed = entry.freed_by(entry.mask);
+        if freed != 0 {
+            mark_path(self.fanotify_fd, FAN_MARK_REMOVE | mark_type, freed, &entry.path)?;
+        }
+        if entry.ignored_mask != 0 {
+            let flags = FAN_MARK_REMOVE | mark_type | FAN_MARK_IGNORE;
+            if let Err(e) = mark_path(self.fanotify_fd, flags, entry.ignored_mask, &entry.path) {
+                if e.raw_os_error() != Some(libc::EINVAL) {
+                    return Err(e);
+                }
+                let flags = FAN_MARK_REMOVE | mark_type | FAN_MARK_IGNORED_MASK;
+                mark_path(self.fanotify_fd, flags, entry.ignored_mask, &entry.path)?;
+            }
+        }
+        if let Some(entry) = self.marks.get_mut(object) {
+            entry.release(entry.mask);
+            entry.ignored_mask = 0;
+            if entry.mask == 0 {
+                self.marks.remove(object);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes an ignore mark previously added with [`MarkSet::ignore`].
+    pub fn unignore(&mut self, path: &Path, ignore: &IgnoreMark) -> io::Result<()> {
+        let object = MarkObject::of(path, ignore.mark_type, false)?;
+        let mode = match ignore.remove(self.fanotify_fd, path, IgnoreMode::Ignore) {
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                ignore.remove(self.fanotify_fd, path, IgnoreMode::IgnoredMask)?;
+                IgnoreMode::IgnoredMask
+            }
+            Err(e) => return Err(e),
+            Ok(()) => IgnoreMode::Ignore,
+        };
+        if let Some(entry) = self.marks.get_mut(&object) {
+            entry.ignored_mask &= !ignore.mask_for(path, mode);
+            if entry.mask == 0 && entry.ignored_mask == 0 {
+                self.marks.remove(&object);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the event mask on `path` with `mask`: takes a reference on the
+    /// new bits and releases one on the dropped ones.
+    pub fn update(&mut self, path: &Path, mark_type: u32, mask: u64) -> io::Result<()> {
+        let object = MarkObject::of(path, mark_type, false)?;
+        let old = self.marks.get(&object).map_or(0, |entry| entry.mask);
+        if mask & !old != 0 {
+            self.add(path, mark_type, mask & !old, 0)?;
+        }
+        if old & !mask != 0 {
+            self.remove(path, mark_type, old & !mask)?;
+        }
+        Ok(())
+    }
+
+    /// Drops every mark of `mark_type` with a single `FAN_MARK_FLUSH`.
+    pub fn flush(&mut self, mark_type: u32) -> io::Result<()> {
+        let result = unsafe {
+            fanotify_mark(self.fanotify_fd, FAN_MARK_FLUSH | mark_type, 0, AT_FDCWD, std::ptr::null())
+        };
+        if result == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        self.marks.retain(|object, _| object.mark_type() != mark_type);
+        Ok(())
+    }
+
+    /// Flushes inode, mount and filesystem marks.
+    pub fn flush_all(&mut self) -> io::Result<()> {
+        for mark_type in [FAN_MARK_INODE, FAN_MARK_MOUNT, FAN_MARK_FILESYSTEM] {
+            self.flush(mark_type)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets a mark without touching the kernel, e.g. after the kernel
+    /// dropped it because the inode was deleted.
+    pub fn forget(&mut self, object: &MarkObject) -> Option<MarkEntry> {
+        self.marks.remove(object)
+    }
+
+    /// Compares the tracked marks with /proc/self/fdinfo/<fd>.
+    pub fn verify(&self) -> io::Result<Vec<MarkMismatch>> {
+        let kernel = read_kernel_marks(self.fanotify_fd)?;
+        let mut mismatches = Vec::new();
+        for entry in self.marks() {
+            match kernel.iter().find(|k| k.object == entry.object) {
+                None => mismatches.push(MarkMismatch::MissingInKernel(entry.clone())),
+                // With FAN_REPORT_DIR_FID the kernel adds FAN_EVENT_ON_CHILD to inode marks on its own
+                Some(k) if k.mask & !FAN_EVENT_ON_CHILD != entry.mask & !FAN_EVENT_ON_CHILD
+                    || k.ignored_mask != entry.ignored_mask =>
+                {
+                    mismatches.push(MarkMismatch::MaskDiffers { entry: entry.clone(), kernel: k.clone() });
+                }
+                Some(_) => {}
+            }
+        }
+        for k in kernel {
+            if !self.marks.contains_key(&k.object) {
+                mismatches.push(MarkMismatch::UnknownToMonitor(k));
+            }
+        }
+        Ok(mismatches)
+    }
+
+    fn entry(&mut self, path: &Path, object: MarkObject) -> &mut MarkEntry {
+        self.marks.entry(object).or_insert_with(|| MarkEntry {
+            path: path.to_path_buf(),
+            object,
+            mask: 0,
+            ignored_mask: 0,
+            refs: HashMap::new(),
+        })
+    }
+}
+
+/// Parses the `fanotify ino:` / `fanotify mnt_id:` / `fanotify sdev:` lines of
+/// /proc/self/fdinfo/<fd>. All numbers on those lines are printed in hex.
+pub fn read_kernel_marks(fanotify_fd: libc::c_int) -> io::Result<Vec<KernelMark>> {
+    let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fanotify_fd))?;
+    Ok(fdinfo.lines().filter_map(parse_fdinfo_mark).collect())
+}
+
+/// fanotify_init(2) flags of the group, from the header line of its fdinfo.
+pub fn group_flags(fanotify_fd: libc::c_int) -> io::Result<u32> {
+    let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fanotify_fd))?;
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("fanotify flags:"))
+        .and_then(|rest| u32::from_str_radix(rest.split_whitespace().next()?, 16).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no fanotify flags in fdinfo"))
+}
+
+fn parse_fdinfo_mark(line: &str) -> Option<KernelMark> {
+    let rest = line.strip_prefix("fanotify ")?;
+    let mut fields = HashMap::new();
+    for field in rest.split_whitespace() {
+        let (key, value) = field.split_once(':')?;
+        fields.insert(key, value);
+    }
+    let hex = |key: &str| fields.get(key).and_then(|v| u64::from_str_radix(v, 16).ok());
+    // sdev uses the kernel's internal MKDEV encoding (major << 20 | minor)
+    let dev = |sdev: u64| libc::makedev((sdev >> 20) as u32, (sdev & 0xfffff) as u32);
+    let object = if let Some(ino) = hex("ino") {
+        MarkObject::Inode { dev: dev(hex("sdev")?), ino }
+    } else if let Some(mnt_id) = hex("mnt_id") {
+        MarkObject::Mount { mnt_id }
+    } else if let Some(sdev) = hex("sdev") {
+        MarkObject::Filesystem { dev: dev(sdev) }
+    } else {
+        // The group header line ("fanotify flags:...") carries no mark
+        return None;
+    };
+    Some(KernelMark {
+        object,
+        mflags: hex("mflags")? as u32,
+        mask: hex("mask")?,
+        ignored_mask: hex("ignored_mask")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> MarkEntry {
+        MarkEntry {
+            path: PathBuf::from("/tmp"),
+            object: MarkObject::Inode { dev: 0, ino: 1 },
+            mask: 0,
+            ignored_mask: 0,
+            refs: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn parses_inode_mark() {
+        let line = "fanotify ino:1a2b sdev:800003 mflags:0 mask:4000003a ignored_mask:0 fhandle-bytes:8 fhandle-type:1 f_handle:2b1a000000000000";
+        let mark = parse_fdinfo_mark(line).unwrap();
+        assert_eq!(mark.object, MarkObject::Inode { dev: libc::makedev(8, 3), ino: 0x1a2b });
+        assert_eq!(mark.mask, 0x4000003a);
+        assert_eq!(mark.ignored_mask, 0);
+        assert_eq!(mark.mflags, 0);
+    }
+
+    #[test]
+    fn parses_mount_and_filesystem_marks() {
+        let mount = parse_fdinfo_mark("fanotify mnt_id:1c mflags:0 mask:8 ignored_mask:2").unwrap();
+        assert_eq!(mount.object, MarkObject::Mount { mnt_id: 0x1c });
+        assert_eq!(mount.ignored_mask, 2);
+        let fs = parse_fdinfo_mark("fanotify sdev:1000001 mflags:40 mask:1 ignored_mask:0").unwrap();
+        assert_eq!(fs.object, MarkObject::Filesystem { dev: libc::makedev(16, 1) });
+        assert_eq!(fs.mflags, 0x40);
+    }
+
+    #[test]
+    fn skips_non_mark_lines() {
+        assert!(parse_fdinfo_mark("fanotify flags:10 event-flags:0").is_none());
+        assert!(parse_fdinfo_mark("pos:\t0").is_none());
+        assert!(parse_fdinfo_mark("fanotify ino:12 sdev:800003 mflags:0").is_none());
+    }
+
+    #[test]
+    fn bits_stay_until_last_reference() {
+        let mut entry = entry();
+        entry.acquire(FAN_OPEN | FAN_MODIFY);
+        entry.acquire(FAN_MODIFY);
+        assert_eq!(entry.freed_by(FAN_OPEN | FAN_MODIFY), FAN_OPEN);
+        assert_eq!(entry.release(FAN_OPEN | FAN_MODIFY), FAN_OPEN);
+        assert_eq!(entry.mask, FAN_MODIFY);
+        assert_eq!(entry.release(FAN_MODIFY), FAN_MODIFY);
+        assert_eq!(entry.mask, 0);
+        assert!(entry.refs.is_empty());
+    }
+
+    #[test]
+    fn releasing_unheld_bits_frees_nothing_else() {
+        let mut entry = entry();
+        entry.acquire(FAN_OPEN);
+        assert_eq!(entry.release(FAN_CLOSE_WRITE), 0);
+        assert_eq!(entry.mask, FAN_OPEN);
+    }
+
+    #[test]
+    fn unknown_mark_type_is_rejected() {
+        let err = MarkObject::of(Path::new("/"), 0x400, false).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        assert!(matches!(MarkObject::of(Path::new("/"), FAN_MARK_INODE, false), Ok(MarkObject::Inode { .. })));
+    }
+}
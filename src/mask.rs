@@ -0,0 +1,124 @@
+// Conversion between fanotify event masks and human readable names.
+
+use crate::sys::*;
+
+/// Lower-case names accepted on the command line, in configs and over the
+/// control socket.
+pub const EVENT_NAMES: &[(&str, u64)] = &[
+    ("access", FAN_ACCESS),
+    ("modify", FAN_MODIFY),
+    ("attrib", FAN_ATTRIB),
+    ("close_write", FAN_CLOSE_WRITE),
+    ("close_nowrite", FAN_CLOSE_NOWRITE),
+    ("open", FAN_OPEN),
+    ("moved_from", FAN_MOVED_FROM),
+    ("moved_to", FAN_MOVED_TO),
+    ("create", FAN_CREATE),
+    ("delete", FAN_DELETE),
+    ("delete_self", FAN_DELETE_SELF),
+    ("move_self", FAN_MOVE_SELF),
+    ("open_exec", FAN_OPEN_EXEC),
+    ("fs_error", FAN_FS_ERROR),
+    ("open_perm", FAN_OPEN_PERM),
+    ("access_perm", FAN_ACCESS_PERM),
+    ("open_exec_perm", FAN_OPEN_EXEC_PERM),
+    ("pre_access", FAN_PRE_ACCESS),
+    ("mnt_attach", FAN_MNT_ATTACH),
+    ("mnt_detach", FAN_MNT_DETACH),
+    ("ondir", FAN_ONDIR),
+    ("event_on_child", FAN_EVENT_ON_CHILD),
+];
+
+/// Parses a single event name (case-insensitive, with or without `FAN_`).
+pub fn mask_from_name(name: &str) -> Result<u64, String> {
+    let lower = name.trim().to_ascii_lowercase();
+    let lower = lower.strip_prefix("fan_").unwrap_or(&lower);
+    EVENT_NAMES
+        .iter()
+        .find(|(n, _)| *n == lower)
+        .map(|(_, bit)| *bit)
+        .ok_or_else(|| format!("unknown event name: {}", name))
+}
+
+/// Parses a comma separated list such as `open,modify,close_write`.
+pub fn mask_from_list(list: &str) -> Result<u64, String> {
+    list.split(',').filter(|s| !s.trim().is_empty()).try_fold(0, |mask, name| Ok(mask | mask_from_name(name)?))
+}
+
+/// Names of the bits set in `mask`; unknown bits are shown as hex.
+pub fn mask_names(mask: u64) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut known = 0;
+    for (name, bit) in EVENT_NAMES {
+        if mask & bit != 0 {
+            names.push(name.to_string());
+            known |= bit;
+        }
+    }
+    if mask & !known != 0 {
+        names.push(format!("0x{:x}", mask & !known));
+    }
+    names
+}
+
+/// Parses `inode`, `mount` or `filesystem` into a `FAN_MARK_*` type.
+pub fn mark_type_from_name(name: &str) -> Result<u32, String> {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "inode" | "" => Ok(FAN_MARK_INODE),
+        "mount" => Ok(FAN_MARK_MOUNT),
+        "filesystem" | "fs" => Ok(FAN_MARK_FILESYSTEM),
+        other => Err(format!("unknown mark type: {}", other)),
+    }
+}
+
+pub fn mark_type_name(mark_type: u32) -> &'static str {
+    match mark_type & FAN_MARK_TYPE_MASK {
+        FAN_MARK_MOUNT => "mount",
+        FAN_MARK_FILESYSTEM => "filesystem",
+        FAN_MARK_MNTNS => "mntns",
+        _ => "inode",
+    }
+}
+
+/// inotify spellings of the events that exist in both APIs (the bits are the
+/// same), used by incrontab files and inotifywait-style output.
+pub const INOTIFY_NAMES: &[(&str, u64)] = &[
+    ("ACCESS", FAN_ACCESS),
+    ("MODIFY", FAN_MODIFY),
+    ("ATTRIB", FAN_ATTRIB),
+    ("CLOSE_WRITE", FAN_CLOSE_WRITE),
+    ("CLOSE_NOWRITE", FAN_CLOSE_NOWRITE),
+    ("OPEN", FAN_OPEN),
+    ("MOVED_FROM", FAN_MOVED_FROM),
+    ("MOVED_TO", FAN_MOVED_TO),
+    ("CREATE", FAN_CREATE),
+    ("DELETE", FAN_DELETE),
+    ("DELETE_SELF", FAN_DELETE_SELF),
+    ("MOVE_SELF", FAN_MOVE_SELF),
+    ("ISDIR", FAN_ONDIR),
+];
+
+/// inotify's combined names.
+const INOTIFY_GROUPS: &[(&str, u64)] = &[
+    ("CLOSE", FAN_CLOSE_WRITE | FAN_CLOSE_NOWRITE),
+    ("MOVE", FAN_MOVE),
+    ("ALL_EVENTS", 0xfff),
+];
+
+/// Parses an inotify event name such as `IN_CLOSE_WRITE` or `close` (prefix
+/// and case optional). `ISDIR` is not an event and is rejected.
+pub fn mask_from_inotify_name(name: &str) -> Result<u64, String> {
+    let upper = name.trim().to_ascii_uppercase();
+    let upper = upper.strip_prefix("IN_").unwrap_or(&upper);
+    INOTIFY_NAMES
+        .iter()
+        .chain(INOTIFY_GROUPS)
+        .find(|(n, bit)| *n == upper && *bit != FAN_ONDIR)
+        .map(|(_, bit)| *bit)
+        .ok_or_else(|| format!("unknown inotify event: {}", name))
+}
+
+/// inotify names (without `IN_`) of the bits set in `mask`, `ISDIR` last.
+pub fn inotify_names(mask: u64) -> Vec<&'static str> {
+    INOTIFY_NAMES.iter().filter(|(_, bit)| mask & bit != 0).map(|(n, _)| *n).collect()
+}
@@ -0,0 +1,247 @@
+// Mount and unmount events (Linux 6.15+): a mark on a mount namespace
+// reports every mount attached to or detached from it, with the mount's
+// unique id in an info record and no file descriptor. FAN_REPORT_MNT cannot
+// be combined with FID reporting, so these events need a group of their own.
+//
+// Ids are resolved with statmount(2) while the mount exists; detached mounts
+// are described from what was known about them. Without statmount the
+// difference between two reads of /proc/self/mountinfo stands in.
+//
+// A `MountFollower` keeps mount marks on whatever gets mounted below some
+// roots, so watching carries on across remounts.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::event::{parse_events, Fsid};
+use crate::mark::{mark_path, MarkObject, MarkSet};
+use crate::mounts::{list_mounts, mount_of, read_mountinfo, statmount, MountInfo};
+use crate::sys::*;
+
+/// Our own mount namespace.
+pub const OWN_NAMESPACE: &str = "/proc/self/ns/mnt";
+
+/// A mount attached to or detached from the watched namespace.
+#[derive(Debug, Clone)]
+pub struct MountChange {
+    /// FAN_MNT_ATTACH or FAN_MNT_DETACH.
+    pub mask: u64,
+    pub unique_id: u64,
+    /// None when the mount was gone before it could be looked up.
+    pub mount: Option<MountInfo>,
+}
+
+impl MountChange {
+    pub fn attached(&self) -> bool {
+        self.mask & FAN_MNT_ATTACH != 0
+    }
+
+    pub fn mount_point(&self) -> Option<&Path> {
+        self.mount.as_ref().map(|m| m.mount_point.as_path())
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "event": if self.attached() { "mnt_attach" } else { "mnt_detach" },
+            "mask": self.mask,
+            "mnt_id": self.unique_id,
+            "mount_point": self.mount_point(),
+            "source": self.mount.as_ref().map(|m| &m.source),
+            "fstype": self.mount.as_ref().map(|m| &m.fstype),
+            "dev": self.mount.as_ref().map(MountInfo::device_number),
+        })
+    }
+}
+
+impl std::fmt::Display for MountChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.mount {
+            Some(mount) => write!(
+                f,
+                "{} {} ({}) {} {}",
+                mount.fstype,
+                mount.source,
+                mount.device_number(),
+                if self.attached() { "mounted at" } else { "unmounted from" },
+                mount.mount_point.display()
+            ),
+            None => write!(f, "mount {:#x} (already gone)", self.unique_id),
+        }
+    }
+}
+
+fn first(mounts: &mut Vec<MountInfo>) -> Option<MountInfo> {
+    (!mounts.is_empty()).then(|| mounts.remove(0))
+}
+
+pub struct MountWatcher {
+    fd: OwnedFd,
+    namespace: PathBuf,
+    /// Mounts by unique id, so a detached one can still be described.
+    known: HashMap<u64, MountInfo>,
+    /// Last /proc/self/mountinfo, for kernels without statmount.
+    table: Vec<MountInfo>,
+    buffer: Vec<u8>,
+}
+
+impl MountWatcher {
+    /// Watches the mount namespace `namespace` (an nsfs path such as
+    /// /proc/<pid>/ns/mnt). Needs CAP_SYS_ADMIN in its user namespace.
+    pub fn new(namespace: &Path) -> io::Result<Self> {
+        let fd = unsafe { fanotify_init(FAN_CLASS_NOTIF | FAN_CLOEXEC | FAN_NONBLOCK | FAN_REPORT_MNT, libc::O_RDONLY as u32) };
+        if fd == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        mark_path(fd.as_raw_fd(), FAN_MARK_ADD | FAN_MARK_MNTNS, FAN_MNT_ATTACH | FAN_MNT_DETACH, namespace)?;
+        let known = list_mounts()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|id| statmount(id).ok().map(|mount| (id, mount)))
+            .collect();
+        Ok(MountWatcher {
+            fd,
+            namespace: namespace.to_path_buf(),
+            known,
+            table: read_mountinfo().unwrap_or_default(),
+            buffer: vec![0u8; 4096],
+        })
+    }
+
+    pub fn namespace(&self) -> &Path {
+        &self.namespace
+    }
+
+    /// Mounts known right now.
+    pub fn len(&self) -> usize {
+        self.known.len().max(self.table.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+
+    /// Every queued change, oldest first.
+    pub fn read(&mut self) -> io::Result<Vec<MountChange>> {
+        let mut events = Vec::new();
+        loop {
+            let n = unsafe { libc::read(self.fd(), self.buffer.as_mut_ptr() as *mut libc::c_void, self.buffer.len()) };
+            if n == -1 {
+                match get_errno() {
+                    libc::EAGAIN => break,
+                    libc::EINTR => continue,
+                    errno => return Err(io::Error::from_raw_os_error(errno)),
+                }
+            }
+            events.extend(parse_events(&self.buffer[..n as usize]));
+        }
+
+        // What appeared and vanished since the last read, in case statmount cannot tell
+        let table = read_mountinfo().unwrap_or_default();
+        let mut appeared: Vec<MountInfo> =
+            table.iter().filter(|m| !self.table.iter().any(|old| old.mount_id == m.mount_id)).cloned().collect();
+        let mut vanished: Vec<MountInfo> =
+            self.table.iter().filter(|old| !table.iter().any(|m| m.mount_id == old.mount_id)).cloned().collect();
+        self.table = table;
+
+        let mut changes = Vec::new();
+        for event in events {
+            let Some(unique_id) = event.mnt_id else {
+                continue;
+            };
+            let mount = if event.mask & FAN_MNT_ATTACH != 0 {
+                let mount = match statmount(unique_id) {
+                    Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => first(&mut appeared),
+                    result => result.ok(),
+                };
+                if let Some(mount) = &mount {
+                    self.known.insert(unique_id, mount.clone());
+                }
+                mount
+            } else {
+                self.known.remove(&unique_id).or_else(|| first(&mut vanished))
+            };
+            changes.push(MountChange { mask: event.mask & (FAN_MNT_ATTACH | FAN_MNT_DETACH), unique_id, mount });
+        }
+        Ok(changes)
+    }
+}
+
+/// What a [`MountFollower`] did about a change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Followed {
+    Marked { mount_point: PathBuf, mask: u64 },
+    /// The kernel dropped the mark together with the mount.
+    Dropped { mount_point: PathBuf },
+}
+
+impl std::fmt::Display for Followed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Followed::Marked { mount_point, mask } => write!(f, "Mount mark added for {} (mask 0x{:x})", mount_point.display(), mask),
+            Followed::Dropped { mount_point } => write!(f, "Mount mark for {} went away with the mount", mount_point.display()),
+        }
+    }
+}
+
+/// Adds mount marks to mounts attached below its roots.
+pub struct MountFollower {
+    roots: Vec<PathBuf>,
+    mask: u64,
+    /// Filesystems registered for path resolution by us, by mount id.
+    registered: HashMap<u64, Fsid>,
+}
+
+impl MountFollower {
+    pub fn new(mask: u64) -> Self {
+        MountFollower { roots: Vec::new(), mask, registered: HashMap::new() }
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Adds the mount mark on `root`, resolving handles of a root that is a
+    /// mount point through a detached clone so it can still be unmounted.
+    pub fn mark_root(&mut self, marks: &mut MarkSet, root: &Path) -> io::Result<()> {
+        let canonical = std::fs::canonicalize(root)?;
+        let mount = mount_of(&canonical)?;
+        if mount.mount_point == canonical {
+            self.register(marks, &mount);
+        }
+        marks.add(root, FAN_MARK_MOUNT, self.mask, 0)?;
+        self.roots.push(canonical);
+        Ok(())
+    }
+
+    // Before the mark, so the MarkSet does not pin the mount with a plain fd
+    fn register(&mut self, marks: &mut MarkSet, mount: &MountInfo) {
+        if let Ok(Some(fsid)) = marks.resolver_mut().register_detached(&mount.mount_point) {
+            self.registered.insert(mount.mount_id, fsid);
+        }
+    }
+
+    pub fn handle(&mut self, marks: &mut MarkSet, change: &MountChange) -> io::Result<Option<Followed>> {
+        let Some(mount) = change.mount.as_ref().filter(|m| self.roots.iter().any(|root| m.mount_point.starts_with(root))) else {
+            return Ok(None);
+        };
+        if !change.attached() {
+            if let Some(fsid) = self.registered.remove(&mount.mount_id) {
+                marks.resolver_mut().unregister(&fsid);
+            }
+            let forgotten = marks.forget(&MarkObject::Mount { mnt_id: mount.mount_id });
+            return Ok(forgotten.map(|entry| Followed::Dropped { mount_point: entry.path }));
+        }
+        self.register(marks, mount);
+        marks.add(&mount.mount_point, FAN_MARK_MOUNT, self.mask, 0)?;
+        Ok(Some(Followed::Marked { mount_point: mount.mount_point.clone(), mask: self.mask }))
+    }
+}
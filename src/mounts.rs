@@ -0,0 +1,413 @@
+// The mount table as /proc/self/mountinfo shows it, or as statmount(2) and
+// listmount(2) report it one mount at a time (Linux 6.8+), and the registry
+// of filesystems by fsid built from it.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::fs::File;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use crate::event::{fsid_of, fsid_of_fd, Event, Fsid};
+use crate::mark::mount_id;
+use crate::sys::*;
+
+/// One line of /proc/self/mountinfo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    pub mount_id: u64,
+    pub parent_id: u64,
+    /// st_dev of the filesystem.
+    pub dev: u64,
+    /// Directory of the filesystem that is mounted (not `/` for bind mounts and subvolumes).
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    pub fstype: String,
+    /// Device or other source, e.g. `/dev/sda1`, `tmpfs`, `overlay`.
+    pub source: String,
+    pub super_options: String,
+    /// 64-bit id that is never reused, when statmount(2) provided it.
+    pub unique_id: Option<u64>,
+}
+
+impl MountInfo {
+    /// Device as `major:minor`.
+    pub fn device_number(&self) -> String {
+        format!("{}:{}", libc::major(self.dev), libc::minor(self.dev))
+    }
+}
+
+// Spaces, tabs, newlines and backslashes appear as \ooo
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|o| u8::from_str_radix(std::str::from_utf8(o).ok()?, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                out.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Parses mountinfo text; malformed lines are skipped.
+pub fn parse_mountinfo(text: &str) -> Vec<MountInfo> {
+    let mut mounts = Vec::new();
+    for line in text.lines() {
+        // id parent major:minor root mount_point options [optional...] - fstype source super_options
+        let Some((left, right)) = line.split_once(" - ") else {
+            continue;
+        };
+        let left: Vec<&str> = left.split(' ').collect();
+        let right: Vec<&str> = right.split(' ').collect();
+        let ([id, parent, dev, root, mount_point, ..], [fstype, source, super_options, ..]) = (&left[..], &right[..]) else {
+            continue;
+        };
+        let Some((major, minor)) = dev.split_once(':') else {
+            continue;
+        };
+        let (Ok(mount_id), Ok(parent_id), Ok(major), Ok(minor)) = (id.parse(), parent.parse(), major.parse(), minor.parse()) else {
+            continue;
+        };
+        mounts.push(MountInfo {
+            mount_id,
+            parent_id,
+            dev: libc::makedev(major, minor),
+            root: PathBuf::from(unescape(root)),
+            mount_point: PathBuf::from(unescape(mount_point)),
+            fstype: unescape(fstype),
+            source: unescape(source),
+            super_options: super_options.to_string(),
+            unique_id: None,
+        });
+    }
+    mounts
+}
+
+pub fn read_mountinfo() -> io::Result<Vec<MountInfo>> {
+    Ok(parse_mountinfo(&std::fs::read_to_string("/proc/self/mountinfo")?))
+}
+
+/// /proc/self/mountinfo held open: poll(2) reports POLLPRI on it whenever a
+/// mount is added to or removed from our namespace. Works on any kernel,
+/// unlike mount events, but does not say what changed.
+pub struct MountTableWatch(File);
+
+impl MountTableWatch {
+    pub fn open() -> io::Result<Self> {
+        Ok(MountTableWatch(File::open("/proc/self/mountinfo")?))
+    }
+
+    /// Poll with POLLPRI.
+    pub fn fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// The mount `path` is on.
+pub fn mount_of(path: &Path) -> io::Result<MountInfo> {
+    let id = mount_id(path, false)?;
+    read_mountinfo()?
+        .into_iter()
+        .find(|mount| mount.mount_id == id)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("mount {} not in /proc/self/mountinfo", id)))
+}
+
+// struct statmount: fixed part, then the strings its u32 fields point into
+const STATMOUNT_STR: usize = 512;
+const STATMOUNT_MASK: u64 =
+    STATMOUNT_SB_BASIC | STATMOUNT_MNT_BASIC | STATMOUNT_MNT_ROOT | STATMOUNT_MNT_POINT | STATMOUNT_FS_TYPE | STATMOUNT_SB_SOURCE;
+
+/// The mount with unique id `unique_id`. ENOENT once it is gone, ENOSYS
+/// before Linux 6.8; super options are not filled in.
+pub fn statmount(unique_id: u64) -> io::Result<MountInfo> {
+    match statmount_with(unique_id, STATMOUNT_MASK) {
+        // Before Linux 6.14 the source cannot be asked for
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => statmount_with(unique_id, STATMOUNT_MASK & !STATMOUNT_SB_SOURCE),
+        result => result,
+    }
+}
+
+fn statmount_with(unique_id: u64, mask: u64) -> io::Result<MountInfo> {
+    let req = MntIdReq { size: std::mem::size_of::<MntIdReq>() as u32, mnt_id: unique_id, param: mask, ..Default::default() };
+    let mut buf = vec![0u8; 4096];
+    while unsafe { crate::sys::statmount(&req, buf.as_mut_ptr(), buf.len()) } == -1 {
+        match get_errno() {
+            libc::EOVERFLOW if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            errno => return Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+    Ok(parse_statmount(&buf))
+}
+
+fn parse_statmount(buf: &[u8]) -> MountInfo {
+    let u32_at = |at: usize| u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap());
+    let u64_at = |at: usize| u64::from_ne_bytes(buf[at..at + 8].try_into().unwrap());
+    let mask = u64_at(8);
+    let string = |bit: u64, at: usize| {
+        if mask & bit == 0 {
+            return String::new();
+        }
+        let start = STATMOUNT_STR + u32_at(at) as usize;
+        let bytes = buf.get(start..).unwrap_or_default();
+        String::from_utf8_lossy(bytes.split(|b| *b == 0).next().unwrap_or_default()).into_owned()
+    };
+    MountInfo {
+        mount_id: u32_at(56) as u64,
+        parent_id: u32_at(60) as u64,
+        dev: libc::makedev(u32_at(16), u32_at(20)),
+        root: PathBuf::from(string(STATMOUNT_MNT_ROOT, 104)),
+        mount_point: PathBuf::from(string(STATMOUNT_MNT_POINT, 108)),
+        fstype: string(STATMOUNT_FS_TYPE, 36),
+        source: match string(STATMOUNT_SB_SOURCE, 124) {
+            source if source.is_empty() => "none".to_string(),
+            source => source,
+        },
+        super_options: String::new(),
+        unique_id: Some(u64_at(40)),
+    }
+}
+
+/// Unique ids of every mount in our namespace, via listmount(2).
+pub fn list_mounts() -> io::Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    let mut batch = [0u64; 256];
+    loop {
+        let req = MntIdReq {
+            size: std::mem::size_of::<MntIdReq>() as u32,
+            mnt_id: LSMT_ROOT,
+            param: ids.last().copied().unwrap_or(0),
+            ..Default::default()
+        };
+        let n = unsafe { listmount(&req, batch.as_mut_ptr(), batch.len()) };
+        if n == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        ids.extend_from_slice(&batch[..n as usize]);
+        if (n as usize) < batch.len() {
+            return Ok(ids);
+        }
+    }
+}
+
+/// A mounted filesystem as the fsid of FID events names it. Every btrfs
+/// subvolume has an fsid of its own, so it is a filesystem here too.
+#[derive(Debug, Clone)]
+pub struct Filesystem {
+    pub fsid: Fsid,
+    pub dev: u64,
+    pub fstype: String,
+    pub source: String,
+    /// Every mount of it; the first is where it is mounted, the others are
+    /// bind mounts and further mounts of the same filesystem.
+    pub mounts: Vec<MountInfo>,
+    /// btrfs subvolume, from the `subvol=` option or the directory it starts at.
+    pub subvolume: Option<String>,
+    /// overlayfs upper directory followed by the lower ones.
+    pub layers: Vec<PathBuf>,
+}
+
+impl Filesystem {
+    fn new(fsid: Fsid, mut mounts: Vec<MountInfo>) -> Self {
+        // Where the whole filesystem is mounted comes first, then the shortest path
+        mounts.sort_by_key(|m| (m.root != Path::new("/"), m.mount_point.as_os_str().len()));
+        let first = &mounts[0];
+        let option = |name: &str| {
+            first.super_options.split(',').find_map(|o| o.strip_prefix(name)?.strip_prefix('=')).map(unescape)
+        };
+        let subvolume = match first.fstype.as_str() {
+            "btrfs" => option("subvol"),
+            _ => None,
+        };
+        let layers = match first.fstype.as_str() {
+            "overlay" => option("upperdir")
+                .into_iter()
+                .chain(option("lowerdir").into_iter().flat_map(|dirs| dirs.split(':').map(String::from).collect::<Vec<_>>()))
+                .map(PathBuf::from)
+                .collect(),
+            _ => Vec::new(),
+        };
+        Filesystem { fsid, dev: first.dev, fstype: first.fstype.clone(), source: first.source.clone(), mounts, subvolume, layers }
+    }
+
+    pub fn mount_point(&self) -> &Path {
+        &self.mounts[0].mount_point
+    }
+
+    /// Bind-mount and other aliases of [`Filesystem::mount_point`].
+    pub fn aliases(&self) -> impl Iterator<Item = &Path> {
+        self.mounts[1..].iter().map(|m| m.mount_point.as_path())
+    }
+
+    /// The mount `path` was seen through: the deepest one containing it.
+    pub fn mount_for(&self, path: Option<&Path>) -> &MountInfo {
+        path.and_then(|path| self.mounts.iter().filter(|m| path.starts_with(&m.mount_point)).max_by_key(|m| m.mount_point.as_os_str().len()))
+            .unwrap_or(&self.mounts[0])
+    }
+
+    pub fn fsid_string(&self) -> String {
+        format!("{:08x}:{:08x}", self.fsid[0] as u32, self.fsid[1] as u32)
+    }
+
+    pub fn to_json(&self, path: Option<&Path>) -> Value {
+        let mount = self.mount_for(path);
+        json!({
+            "fsid": self.fsid_string(),
+            "mount_id": mount.mount_id,
+            "mount_point": mount.mount_point,
+            "aliases": self.mounts.iter().filter(|m| m.mount_id != mount.mount_id).map(|m| &m.mount_point).collect::<Vec<_>>(),
+            "fstype": self.fstype,
+            "source": self.source,
+            "dev": mount.device_number(),
+            "subvolume": self.subvolume,
+            "layers": self.layers,
+        })
+    }
+}
+
+impl std::fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} ({}) fsid {} at {}", self.fstype, self.source, self.mounts[0].device_number(), self.fsid_string(), self.mount_point().display())?;
+        if let Some(subvolume) = &self.subvolume {
+            write!(f, ", subvolume {}", subvolume)?;
+        }
+        if let Some((upper, lower)) = self.layers.split_first() {
+            write!(f, ", upper {} over {} layer(s)", upper.display(), lower.len())?;
+        }
+        let aliases: Vec<String> = self.aliases().map(|p| p.display().to_string()).collect();
+        if !aliases.is_empty() {
+            write!(f, ", also at {}", aliases.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Filesystems by fsid, from /proc/self/mountinfo and statfs(2) of every
+/// mount point. Two filesystems can share an fsid (overlayfs without a uuid
+/// reports its upper layer's), so lookups may need the event path.
+#[derive(Default)]
+pub struct MountRegistry {
+    filesystems: HashMap<Fsid, Vec<Arc<Filesystem>>>,
+    /// Looked for and not found; not rescanned for again until the mounts change.
+    missing: HashSet<Fsid>,
+}
+
+impl MountRegistry {
+    pub fn scan() -> io::Result<Self> {
+        let mut registry = MountRegistry::default();
+        registry.rescan()?;
+        Ok(registry)
+    }
+
+    /// Rebuilds the registry after mounts changed; fsids not found before
+    /// are looked for again.
+    pub fn mounts_changed(&mut self) -> io::Result<()> {
+        self.missing.clear();
+        self.rescan()
+    }
+
+    fn rescan(&mut self) -> io::Result<()> {
+        let mut groups: HashMap<(Fsid, u64), Vec<MountInfo>> = HashMap::new();
+        let mut order = Vec::new();
+        for mount in read_mountinfo()? {
+            // Mounts hidden by a later mount on the same point cannot be statfs()ed
+            if mount_id(&mount.mount_point, true).ok() != Some(mount.mount_id) {
+                continue;
+            }
+            let Ok(fsid) = fsid_of(&mount.mount_point) else {
+                continue;
+            };
+            let key = (fsid, mount.dev);
+            if !groups.contains_key(&key) {
+                order.push(key);
+            }
+            groups.entry(key).or_default().push(mount);
+        }
+        self.filesystems.clear();
+        for key in order {
+            let filesystem = Filesystem::new(key.0, groups.remove(&key).expect("grouped above"));
+            self.filesystems.entry(key.0).or_default().push(Arc::new(filesystem));
+        }
+        Ok(())
+    }
+
+    /// Number of filesystems.
+    pub fn len(&self) -> usize {
+        self.filesystems.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filesystems.is_empty()
+    }
+
+    pub fn filesystems(&self) -> impl Iterator<Item = &Arc<Filesystem>> {
+        self.filesystems.values().flatten()
+    }
+
+    /// The filesystem with `fsid`; `path` picks one when several share it.
+    pub fn lookup(&self, fsid: &Fsid, path: Option<&Path>) -> Option<Arc<Filesystem>> {
+        let candidates = self.filesystems.get(fsid)?;
+        let matching = |fs: &&Arc<Filesystem>| path.is_some_and(|path| fs.mounts.iter().any(|m| path.starts_with(&m.mount_point)));
+        candidates
+            .iter()
+            .filter(matching)
+            .max_by_key(|fs| fs.mount_for(path).mount_point.as_os_str().len())
+            .or(candidates.first())
+            .cloned()
+    }
+
+    /// Attaches the filesystem of `event`, from its file handles, its fd or
+    /// its path. Unknown fsids are learned from the path (a btrfs subvolume
+    /// that is not mounted by itself) or by one rescan, after which they are
+    /// not looked for again until [`MountRegistry::mounts_changed`].
+    pub fn attach(&mut self, event: &mut Event) {
+        let fsid = match event.fid.as_ref().or(event.dir_fid.as_ref()) {
+            Some(handle) => Some(handle.fsid),
+            None => match &event.fd {
+                Some(fd) => fsid_of_fd(fd.as_raw_fd()).ok(),
+                None => event.path.as_deref().and_then(|path| fsid_of(path).ok()),
+            },
+        };
+        let Some(fsid) = fsid else {
+            return;
+        };
+        if !self.filesystems.contains_key(&fsid) && !self.missing.contains(&fsid) {
+            if let Some(path) = event.path.as_deref()
+                && let Some(filesystem) = learn(fsid, path)
+            {
+                self.filesystems.entry(fsid).or_default().push(Arc::new(filesystem));
+            } else if self.rescan().is_err() || !self.filesystems.contains_key(&fsid) {
+                self.missing.insert(fsid);
+            }
+        }
+        event.fs = self.lookup(&fsid, event.path.as_deref());
+    }
+}
+
+// A filesystem without a mount of its own: a btrfs subvolume below a mounted one
+fn learn(fsid: Fsid, path: &Path) -> Option<Filesystem> {
+    let existing = path.ancestors().find(|p| p.symlink_metadata().is_ok())?;
+    if fsid_of(existing).ok()? != fsid {
+        return None;
+    }
+    // The subvolume starts at the topmost directory with its fsid
+    let top = existing.ancestors().take_while(|p| fsid_of(p).ok() == Some(fsid)).last()?;
+    let mount = mount_of(top).ok()?;
+    let mut filesystem = Filesystem::new(fsid, vec![mount]);
+    // Subvolumes have an st_dev of their own, mountinfo shows the one of the volume
+    filesystem.dev = std::fs::metadata(top).map_or(filesystem.dev, |m| m.dev());
+    filesystem.subvolume = Some(top.display().to_string());
+    Some(filesystem)
+}
@@ -0,0 +1,257 @@
+// Watches on paths that do not exist yet.
+//
+// The nearest existing ancestor (the "anchor") is marked for directory entry
+// events, together with the directories above it down from where the watch
+// started. Whenever a component on the way to the target appears the anchor
+// moves down; once the target's parent exists the target is followed by a
+// sticky watch, and when directories on the way are removed or renamed the
+// anchor climbs back up to the nearest one still there.
+//
+// The target is kept resolved through its longest existing prefix, so a
+// symlinked ancestor (/var/run -> /run) is watched where the kernel reports
+// it, also when the link appears only later.
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::event::{fd_path, Event};
+use crate::mark::{path_cstring, MarkObject, MarkSet};
+use crate::mask::mask_names;
+use crate::sticky::{StickyChange, StickyWatch, PARENT_MASK};
+use crate::sys::*;
+
+/// Events on the anchor directory: new components, and the anchor itself going away.
+pub const ANCHOR_MASK: u64 = PARENT_MASK | FAN_DELETE_SELF | FAN_MOVE_SELF | FAN_ONDIR;
+
+#[derive(Debug, Clone)]
+pub enum PendingChange {
+    /// The target does not exist; entry events in `anchor` are watched.
+    Waiting { target: PathBuf, anchor: PathBuf },
+    /// The target exists and now carries the real mark.
+    Appeared { target: PathBuf, object: MarkObject },
+    /// The target was replaced or removed while it existed.
+    Target(StickyChange),
+}
+
+impl PendingChange {
+    pub fn path(&self) -> &Path {
+        match self {
+            PendingChange::Waiting { target, .. } | PendingChange::Appeared { target, .. } => target,
+            PendingChange::Target(change) => change.path(),
+        }
+    }
+
+    /// Event bits that describe the change, for filtering like regular events.
+    pub fn mask(&self) -> u64 {
+        match self {
+            PendingChange::Waiting { .. } => FAN_DELETE | FAN_MOVED_FROM,
+            PendingChange::Appeared { .. } => FAN_CREATE | FAN_MOVED_TO,
+            PendingChange::Target(change) => change.mask(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            PendingChange::Waiting { target, anchor } => json!({
+                "mask": self.mask(),
+                "events": mask_names(self.mask()),
+                "event": "waiting",
+                "path": target,
+                "anchor": anchor,
+            }),
+            PendingChange::Appeared { target, object } => json!({
+                "mask": self.mask(),
+                "events": mask_names(self.mask()),
+                "event": "appeared",
+                "path": target,
+                "new": object.to_string(),
+            }),
+            PendingChange::Target(change) => change.to_json(),
+        }
+    }
+}
+
+impl std::fmt::Display for PendingChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PendingChange::Waiting { target, anchor } => {
+                write!(f, "{} does not exist, waiting in {}", target.display(), anchor.display())
+            }
+            PendingChange::Appeared { target, object } => {
+                write!(f, "{} appeared ({}), now watching it directly", target.display(), object)
+            }
+            PendingChange::Target(change) => change.fmt(f),
+        }
+    }
+}
+
+struct Anchor {
+    path: PathBuf,
+    object: MarkObject,
+    // Finds the directory again if it is renamed away
+    held: OwnedFd,
+}
+
+impl Anchor {
+    fn mark(marks: &mut MarkSet, path: &Path) -> io::Result<Anchor> {
+        let path_cstr = path_cstring(path)?;
+        let fd = unsafe { libc::open(path_cstr.as_ptr(), libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        let held = unsafe { OwnedFd::from_raw_fd(fd) };
+        let object = MarkObject::of(path, FAN_MARK_INODE, false)?;
+        marks.add(path, FAN_MARK_INODE, ANCHOR_MASK, FAN_MARK_ONLYDIR)?;
+        Ok(Anchor { path: path.to_path_buf(), object, held })
+    }
+
+    fn is_current(&self) -> bool {
+        MarkObject::of(&self.path, FAN_MARK_INODE, false).ok() == Some(self.object)
+    }
+
+    fn release(self, marks: &mut MarkSet) {
+        let current = fd_path(self.held.as_raw_fd()).ok();
+        let linked = current.filter(|p| !p.as_os_str().as_bytes().ends_with(b" (deleted)"));
+        // MarkSet keeps the bits other watches on this directory hold
+        let removed = linked.is_some_and(|path| marks.remove(&path, FAN_MARK_INODE, ANCHOR_MASK).is_ok());
+        if !removed {
+            // Deleted: the kernel drops the mark with the inode
+            marks.forget(&self.object);
+        }
+    }
+}
+
+pub struct PendingWatch {
+    target: StickyWatch,
+    // Every existing directory from the highest one seen down to the
+    // deepest existing ancestor, so renames anywhere on the way are noticed
+    anchors: Vec<Anchor>,
+}
+
+impl PendingWatch {
+    pub fn new(target: &Path, mask: u64) -> Self {
+        PendingWatch { target: StickyWatch::new(target, mask), anchors: Vec::new() }
+    }
+
+    pub fn target(&self) -> &Path {
+        self.target.path()
+    }
+
+    /// Directory currently waited in.
+    pub fn anchor(&self) -> Option<&Path> {
+        self.anchors.last().map(|a| a.path.as_path())
+    }
+
+    /// Anchors the watch and marks the target if it already exists.
+    pub fn start(&mut self, marks: &mut MarkSet) -> io::Result<PendingChange> {
+        if !self.target().is_absolute() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "pending watches need an absolute path"));
+        }
+        self.refresh(marks)?;
+        Ok(match self.target.object() {
+            Some(object) => PendingChange::Appeared { target: self.target().to_path_buf(), object },
+            None => self.waiting(),
+        })
+    }
+
+    /// Follows the path through an event and reports what changed.
+    pub fn handle(&mut self, marks: &mut MarkSet, event: &Event) -> Vec<PendingChange> {
+        let mut changes = Vec::new();
+        let armed = self.target.object().is_some();
+        match self.target.handle(marks, event) {
+            Some(StickyChange::Replaced { path, old: None, new, .. }) => {
+                changes.push(PendingChange::Appeared { target: path, object: new });
+            }
+            Some(change) => changes.push(PendingChange::Target(change)),
+            None => {}
+        }
+
+        // A directory on the way appeared or vanished, or some watched directory went away
+        let on_the_way = event.is_dir()
+            && event.mask & PARENT_MASK != 0
+            && event.path.as_deref().is_some_and(|p| self.target().starts_with(p));
+        if on_the_way || event.mask & (FAN_DELETE_SELF | FAN_MOVE_SELF) != 0 {
+            let before = self.anchor().map(Path::to_path_buf);
+            if let Err(e) = self.refresh(marks) {
+                println!("DEBUG: ✗ Failed to re-anchor pending watch on {}: {}", self.target().display(), e);
+            }
+            match self.target.object() {
+                Some(object) if !armed && changes.is_empty() => {
+                    changes.push(PendingChange::Appeared { target: self.target().to_path_buf(), object });
+                }
+                None if self.anchor() != before.as_deref() => changes.push(self.waiting()),
+                _ => {}
+            }
+        }
+        changes
+    }
+
+    fn waiting(&self) -> PendingChange {
+        let anchor = self.anchor().unwrap_or(Path::new("/")).to_path_buf();
+        PendingChange::Waiting { target: self.target().to_path_buf(), anchor }
+    }
+
+    // Re-marks the chain of existing ancestors and arms or disarms the target to match.
+    fn refresh(&mut self, marks: &mut MarkSet) -> io::Result<()> {
+        if self.target.object().is_none() {
+            self.target.reresolve();
+        }
+        let target = self.target().to_path_buf();
+        let Some(deepest) = target.ancestors().skip(1).find(|p| p.is_dir()) else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no existing ancestor"));
+        };
+        // The chain never starts below where it started; it may start higher
+        // if directories above it were removed
+        let top = match self.anchors.first() {
+            Some(first) if !deepest.starts_with(&first.path) => deepest,
+            Some(first) => first.path.as_path(),
+            None => deepest,
+        }
+        .to_path_buf();
+        let wanted: Vec<&Path> = deepest.ancestors().take_while(|p| p.starts_with(&top)).collect();
+
+        let (keep, stale): (Vec<Anchor>, Vec<Anchor>) = std::mem::take(&mut self.anchors)
+            .into_iter()
+            .partition(|a| wanted.contains(&a.path.as_path()) && a.is_current());
+        for anchor in stale {
+            anchor.release(marks);
+        }
+        self.anchors = keep;
+        for path in wanted.into_iter().rev() {
+            if !self.anchors.iter().any(|a| a.path == path) {
+                let anchor = Anchor::mark(marks, path)?;
+                self.anchors.push(anchor);
+            }
+        }
+        self.anchors.sort_by_key(|a| a.path.components().count());
+
+        let parent_exists = target.parent() == Some(deepest);
+        match self.target.object() {
+            None if parent_exists && target.exists() => self.target.arm(marks)?,
+            // The target moved away together with one of its directories
+            Some(_) if !parent_exists || !target.exists() => self.target.disarm(marks),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symlinked_ancestors_are_resolved() {
+        let dir = std::env::temp_dir().join(format!("pending-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("run")).unwrap();
+        std::os::unix::fs::symlink(dir.join("run"), dir.join("var-run")).unwrap();
+        let real = dir.canonicalize().unwrap();
+        let watch = PendingWatch::new(&dir.join("var-run/app/../daemon/daemon.pid"), FAN_MODIFY);
+        assert_eq!(watch.target(), real.join("run/daemon/daemon.pid"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
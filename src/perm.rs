@@ -0,0 +1,381 @@
+// Permission events: a second fanotify group of class CONTENT (permission
+// events cannot be combined with FID reporting), and a daemon thread that
+// answers them.
+//
+// Every permission event holds the accessing process until it is answered,
+// so the daemon never lets a slow decision stall the others: a `Policy`
+// first gets a chance to answer from memory, otherwise it decides on a worker
+// thread, and requests still unanswered at their deadline get the fallback
+// verdict.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::event::{fd_path, parse_events};
+use crate::exec::real_uid;
+use crate::mark::{IgnoreMark, MarkSet};
+use crate::mask::mask_names;
+use crate::sys::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Deny,
+}
+
+impl Verdict {
+    fn response(self) -> u32 {
+        match self {
+            Verdict::Allow => FAN_ALLOW,
+            Verdict::Deny => FAN_DENY,
+        }
+    }
+
+    /// `open` or `closed`, as in "fail open".
+    pub fn parse_fail_mode(mode: &str) -> Result<Verdict, String> {
+        match mode {
+            "open" => Ok(Verdict::Allow),
+            "closed" => Ok(Verdict::Deny),
+            _ => Err(format!("expected open or closed, got {}", mode)),
+        }
+    }
+}
+
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Verdict::Allow => "ALLOW",
+            Verdict::Deny => "DENY",
+        })
+    }
+}
+
+/// How long an allowed file goes without asking again (an ignore mark).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cache {
+    /// The kernel clears the mark on the first write, so a changed file is checked again.
+    UntilModified,
+    Always,
+}
+
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub verdict: Verdict,
+    pub reason: String,
+    pub cache: Option<Cache>,
+    /// Have the kernel write an audit record for the decision.
+    pub audit: bool,
+}
+
+impl Decision {
+    pub fn allow(reason: impl Into<String>) -> Self {
+        Decision { verdict: Verdict::Allow, reason: reason.into(), cache: None, audit: false }
+    }
+
+    pub fn deny(reason: impl Into<String>) -> Self {
+        Decision { verdict: Verdict::Deny, reason: reason.into(), cache: None, audit: false }
+    }
+
+    pub fn cached(self) -> Self {
+        Decision { cache: Some(Cache::UntilModified), ..self }
+    }
+
+    pub fn cached_always(self) -> Self {
+        Decision { cache: Some(Cache::Always), ..self }
+    }
+
+    pub fn audited(self) -> Self {
+        Decision { audit: true, ..self }
+    }
+}
+
+/// One permission event waiting for an answer.
+#[derive(Debug)]
+pub struct Request {
+    pub id: u64,
+    pub mask: u64,
+    /// Thread that caused the event (`FAN_REPORT_TID`); /proc/<pid> works for it.
+    pub pid: i32,
+    /// Real uid of the process, if it could be read.
+    pub uid: Option<u32>,
+    /// Executable of the process.
+    pub exe: Option<PathBuf>,
+    pub path: PathBuf,
+    /// Bytes about to be accessed, for pre-content events: (offset, count).
+    pub range: Option<(u64, u64)>,
+    /// The file being opened, as handed over by the kernel.
+    pub file: OwnedFd,
+}
+
+impl std::fmt::Display for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] pid={}", mask_names(self.mask).join(","), self.pid)?;
+        if let Some(uid) = self.uid {
+            write!(f, " uid={}", uid)?;
+        }
+        if let Some(exe) = &self.exe {
+            write!(f, " exe={}", exe.display())?;
+        }
+        write!(f, " path={}", self.path.display())?;
+        if let Some((offset, count)) = self.range {
+            write!(f, " range={}+{}", offset, count)?;
+        }
+        Ok(())
+    }
+}
+
+pub trait Policy: Send + Sync + 'static {
+    /// Answers without blocking (from a cache), or `None` to have `decide`
+    /// called on a worker thread.
+    fn quick(&self, request: &Request) -> Option<Decision>;
+
+    /// May block, e.g. to hash the file; the daemon answers the fallback
+    /// verdict if this misses the deadline.
+    fn decide(&self, request: &Request) -> Decision;
+}
+
+/// A fanotify group delivering permission events with open file descriptors.
+pub struct PermissionGroup {
+    fd: OwnedFd,
+    marks: MarkSet,
+    audit: bool,
+}
+
+impl PermissionGroup {
+    /// With `audit`, asks for `FAN_ENABLE_AUDIT` and goes without it when the
+    /// kernel or missing CAP_AUDIT_WRITE refuse; see `audit()`.
+    pub fn new(audit: bool) -> io::Result<Self> {
+        PermissionGroup::init(FAN_CLASS_CONTENT, libc::O_RDONLY, audit)
+    }
+
+    /// A group that also gets pre-content events (Linux 6.14+), with event
+    /// fds open for writing so the listener can fill the file in.
+    pub fn pre_content() -> io::Result<Self> {
+        PermissionGroup::init(FAN_CLASS_PRE_CONTENT, libc::O_RDWR, false)
+    }
+
+    fn init(class: u32, access: libc::c_int, audit: bool) -> io::Result<Self> {
+        let init = |flags: u32| {
+            let flags = class | FAN_CLOEXEC | FAN_REPORT_TID | flags;
+            let fd = unsafe { fanotify_init(flags, (access | libc::O_LARGEFILE) as u32) };
+            match fd {
+                -1 => Err(io::Error::from_raw_os_error(get_errno())),
+                fd => Ok(fd),
+            }
+        };
+        let (fd, audit) = match audit {
+            true => match init(FAN_ENABLE_AUDIT) {
+                Ok(fd) => (fd, true),
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EPERM | libc::EINVAL)) => (init(0)?, false),
+                Err(e) => return Err(e),
+            },
+            false => (init(0)?, false),
+        };
+        Ok(PermissionGroup { fd: unsafe { OwnedFd::from_raw_fd(fd) }, marks: MarkSet::new(fd), audit })
+    }
+
+    /// Whether decisions can carry `FAN_AUDIT`.
+    pub fn audit(&self) -> bool {
+        self.audit
+    }
+
+    pub fn marks(&mut self) -> &mut MarkSet {
+        &mut self.marks
+    }
+
+    /// Marks the filesystem holding `path`, or its mount when filesystem
+    /// marks are refused. Returns the mark type used.
+    pub fn mark_filesystem(&mut self, path: &Path, mask: u64) -> io::Result<&'static str> {
+        match self.marks.add(path, FAN_MARK_FILESYSTEM, mask, 0) {
+            Ok(()) => Ok("filesystem"),
+            Err(_) => self.marks.add(path, FAN_MARK_MOUNT, mask, 0).map(|()| "mount"),
+        }
+    }
+
+    /// Inode marks for `mask` on a file, or on a directory and the
+    /// directories below it for their children. Returns the marks added.
+    pub fn mark_tree(&mut self, path: &Path, mask: u64) -> io::Result<usize> {
+        let meta = std::fs::metadata(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        if !meta.is_dir() {
+            self.marks.add(path, FAN_MARK_INODE, mask, 0)?;
+            return Ok(1);
+        }
+        let mut added = 0;
+        let mut dirs = vec![path.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            self.marks.add(&dir, FAN_MARK_INODE, mask | FAN_EVENT_ON_CHILD, 0)?;
+            added += 1;
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            // file_type() does not follow symlinks, so links out of the tree stay unmarked
+            dirs.extend(entries.flatten().filter(|e| e.file_type().is_ok_and(|t| t.is_dir())).map(|e| e.path()));
+        }
+        Ok(added)
+    }
+
+    fn respond(&self, file: RawFd, verdict: Verdict, audit: bool) -> io::Result<()> {
+        let audit = if audit && self.audit { FAN_AUDIT } else { 0 };
+        let response = FanotifyResponse { fd: file, response: verdict.response() | audit };
+        let size = std::mem::size_of::<FanotifyResponse>();
+        let written = unsafe { libc::write(self.fd.as_raw_fd(), &response as *const _ as *const libc::c_void, size) };
+        if written == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        Ok(())
+    }
+}
+
+struct Pending {
+    request: Arc<Request>,
+    deadline: Instant,
+}
+
+/// Answers the permission events of a group with a `Policy`.
+pub struct PermissionDaemon {
+    group: PermissionGroup,
+    policy: Arc<dyn Policy>,
+    deadline: Duration,
+    fallback: Verdict,
+    /// Woken by workers when a decision is ready.
+    wake: OwnedFd,
+}
+
+impl PermissionDaemon {
+    pub fn new(group: PermissionGroup, policy: Arc<dyn Policy>, deadline: Duration, fallback: Verdict) -> io::Result<Self> {
+        let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if wake == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        Ok(PermissionDaemon { group, policy, deadline, fallback, wake: unsafe { OwnedFd::from_raw_fd(wake) } })
+    }
+
+    /// Runs the daemon on its own thread for the rest of the process.
+    pub fn spawn(self) -> io::Result<()> {
+        std::thread::Builder::new().name("permissions".to_string()).spawn(move || self.run())?;
+        Ok(())
+    }
+
+    fn finish(&mut self, request: &Request, decision: &Decision) {
+        if let Err(e) = self.group.respond(request.file.as_raw_fd(), decision.verdict, decision.audit) {
+            eprintln!("✗ Failed to answer permission event for {}: {}", request.path.display(), e);
+            return;
+        }
+        let icon = match decision.verdict {
+            Verdict::Allow => "🛡️ ",
+            Verdict::Deny => "⛔",
+        };
+        let audited = if decision.audit && self.group.audit { " (audited)" } else { "" };
+        println!("\n{} [{}] {}: {}{}", icon, decision.verdict, request, decision.reason, audited);
+        if let Some(cache) = decision.cache
+            && decision.verdict == Verdict::Allow
+        {
+            let ignore = IgnoreMark { surv_modify: cache == Cache::Always, ..IgnoreMark::new(request.mask) };
+            let path = PathBuf::from(format!("/proc/self/fd/{}", request.file.as_raw_fd()));
+            if let Err(e) = ignore.add(self.group.fd.as_raw_fd(), &path) {
+                println!("DEBUG: ✗ Failed to cache decision for {}: {}", request.path.display(), e);
+            }
+        }
+    }
+
+    fn run(mut self) {
+        let (done_tx, done_rx) = mpsc::channel::<(u64, Decision)>();
+        let mut pending: HashMap<u64, Pending> = HashMap::new();
+        let mut next_id = 0u64;
+        let mut buffer = vec![0u8; 4096];
+        loop {
+            let now = Instant::now();
+            let timeout = pending.values().map(|p| p.deadline.saturating_duration_since(now)).min();
+            let timeout = timeout.map_or(-1, |wait| wait.as_micros().div_ceil(1000).min(i32::MAX as u128) as libc::c_int);
+            let mut fds = [
+                libc::pollfd { fd: self.group.fd.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+                libc::pollfd { fd: self.wake.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            ];
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } == -1 {
+                if get_errno() == libc::EINTR {
+                    continue;
+                }
+                eprintln!("✗ poll() on the permission group failed: errno = {}", get_errno());
+                return;
+            }
+
+            if fds[0].revents & libc::POLLIN != 0 {
+                let n = unsafe { libc::read(self.group.fd.as_raw_fd(), buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+                if n == -1 {
+                    if get_errno() != libc::EINTR {
+                        eprintln!("✗ Error reading permission events: errno = {}", get_errno());
+                        return;
+                    }
+                    continue;
+                }
+                for event in parse_events(&buffer[..n as usize]) {
+                    let Some(file) = event.fd else {
+                        continue;
+                    };
+                    next_id += 1;
+                    let request = Request {
+                        id: next_id,
+                        mask: event.mask & FAN_ALL_PERM_EVENTS,
+                        pid: event.pid,
+                        uid: real_uid(event.pid),
+                        exe: std::fs::read_link(format!("/proc/{}/exe", event.pid)).ok(),
+                        path: fd_path(file.as_raw_fd()).unwrap_or_default(),
+                        range: event.range,
+                        file,
+                    };
+                    // Our own opens (hashing, config) must never wait on ourselves
+                    if Path::new(&format!("/proc/self/task/{}", request.pid)).exists() {
+                        let _ = self.group.respond(request.file.as_raw_fd(), Verdict::Allow, false);
+                        continue;
+                    }
+                    if let Some(decision) = self.policy.quick(&request) {
+                        self.finish(&request, &decision);
+                        continue;
+                    }
+                    let request = Arc::new(request);
+                    let (policy, worker_request, done, wake) =
+                        (Arc::clone(&self.policy), Arc::clone(&request), done_tx.clone(), self.wake.as_raw_fd());
+                    std::thread::spawn(move || {
+                        let decision = policy.decide(&worker_request);
+                        let _ = done.send((worker_request.id, decision));
+                        let one = 1u64;
+                        unsafe { libc::write(wake, &one as *const u64 as *const libc::c_void, 8) };
+                    });
+                    pending.insert(request.id, Pending { request, deadline: Instant::now() + self.deadline });
+                }
+            }
+
+            if fds[1].revents & libc::POLLIN != 0 {
+                let mut count = 0u64;
+                unsafe { libc::read(self.wake.as_raw_fd(), &mut count as *mut u64 as *mut libc::c_void, 8) };
+            }
+            while let Ok((id, decision)) = done_rx.try_recv() {
+                match pending.remove(&id) {
+                    Some(p) => self.finish(&p.request, &decision),
+                    None => println!("DEBUG: Decision for request #{} arrived after its deadline", id),
+                }
+            }
+
+            let now = Instant::now();
+            let expired: Vec<u64> = pending.iter().filter(|(_, p)| p.deadline <= now).map(|(id, _)| *id).collect();
+            for id in expired {
+                let p = pending.remove(&id).expect("expired request is pending");
+                let decision = Decision {
+                    verdict: self.fallback,
+                    reason: format!("no decision within {:?}, failing {}", self.deadline, match self.fallback {
+                        Verdict::Allow => "open",
+                        Verdict::Deny => "closed",
+                    }),
+                    cache: None,
+                    audit: true,
+                };
+                self.finish(&p.request, &decision);
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+// Interactive permission decisions: each permission event the rules do not
+// cover is shown in the terminal and the operator allows or denies it, once
+// or always. "Always" answers become rules, kept in a file across runs.
+//
+// Questions are asked one at a time by a terminal thread while the daemon
+// keeps answering everything else. A question not answered before its
+// deadline gets the default verdict from the daemon, so nothing hangs behind
+// the prompt.
+//
+// Rules file, one per line, tab separated:
+//     allow<TAB>write<TAB>/usr/bin/vim<TAB>/etc/hosts
+// The executable is `-` when it could not be read.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::perm::{Decision, Policy, Request, Verdict};
+use crate::protect::{is_write, open_flags};
+use crate::sys::*;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What a rule applies to: (access, executable, path).
+type RuleKey = (String, Option<PathBuf>, PathBuf);
+
+/// Kind of access a request asks for: exec, read, write, or open when the
+/// open flags are unknown.
+fn access(request: &Request) -> String {
+    let access = if request.mask & FAN_OPEN_EXEC_PERM != 0 {
+        "exec"
+    } else if request.mask & FAN_ACCESS_PERM != 0 {
+        "read"
+    } else {
+        match open_flags(request.pid) {
+            Ok(flags) if is_write(flags) => "write",
+            Ok(_) => "read",
+            Err(_) => "open",
+        }
+    };
+    access.to_string()
+}
+
+fn decision(verdict: Verdict, reason: impl Into<String>) -> Decision {
+    match verdict {
+        Verdict::Allow => Decision::allow(reason),
+        Verdict::Deny => Decision::deny(reason),
+    }
+}
+
+fn key(request: &Request) -> RuleKey {
+    (access(request), request.exe.clone(), request.path.clone())
+}
+
+struct Rules {
+    verdicts: HashMap<RuleKey, Verdict>,
+    /// Where new rules are appended.
+    file: Option<File>,
+}
+
+impl Rules {
+    fn load(path: Option<&Path>) -> Result<Self, String> {
+        let mut verdicts = HashMap::new();
+        let Some(path) = path else {
+            return Ok(Rules { verdicts, file: None });
+        };
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        for (n, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [verdict, access, exe, file] = fields[..] else {
+                return Err(format!("{}:{}: expected `<allow|deny>\\t<access>\\t<exe>\\t<path>`", path.display(), n + 1));
+            };
+            let verdict = match verdict {
+                "allow" => Verdict::Allow,
+                "deny" => Verdict::Deny,
+                _ => return Err(format!("{}:{}: expected allow or deny, got {}", path.display(), n + 1, verdict)),
+            };
+            let exe = (exe != "-").then(|| PathBuf::from(exe));
+            verdicts.insert((access.to_string(), exe, PathBuf::from(file)), verdict);
+        }
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Rules { verdicts, file: Some(file) })
+    }
+
+    fn decision(&self, key: &RuleKey) -> Option<Decision> {
+        let verdict = *self.verdicts.get(key)?;
+        Some(decision(verdict, format!("rule: {} {}", verdict.to_string().to_lowercase(), key.0)))
+    }
+
+    fn store(&mut self, key: RuleKey, verdict: Verdict) {
+        if let Some(file) = &mut self.file {
+            let exe = key.1.as_ref().map_or("-".into(), |exe| exe.display().to_string());
+            let line = format!("{}\t{}\t{}\t{}", verdict.to_string().to_lowercase(), key.0, exe, key.2.display());
+            if let Err(e) = writeln!(file, "{}", line) {
+                eprintln!("✗ Failed to store prompt rule: {}", e);
+            }
+        }
+        self.verdicts.insert(key, verdict);
+    }
+}
+
+struct Question {
+    summary: String,
+    key: RuleKey,
+    deadline: Instant,
+    reply: Sender<Decision>,
+}
+
+/// Asks the operator about every request no rule covers.
+pub struct Prompter {
+    rules: Arc<Mutex<Rules>>,
+    questions: Sender<Question>,
+    timeout: Duration,
+    default: Verdict,
+}
+
+impl Prompter {
+    /// Loads the rules and starts the terminal thread. `timeout` should be
+    /// the daemon deadline, `default` its fallback.
+    pub fn new(rules: Option<&Path>, timeout: Duration, default: Verdict) -> Result<Self, String> {
+        let rules = Arc::new(Mutex::new(Rules::load(rules)?));
+        let (questions, asked) = mpsc::channel();
+        let terminal_rules = Arc::clone(&rules);
+        std::thread::Builder::new()
+            .name("prompt".to_string())
+            .spawn(move || terminal(terminal_rules, asked, default))
+            .map_err(|e| format!("cannot start the prompt: {}", e))?;
+        Ok(Prompter { rules, questions, timeout, default })
+    }
+
+    pub fn rules(&self) -> usize {
+        self.rules.lock().unwrap().verdicts.len()
+    }
+}
+
+impl Policy for Prompter {
+    fn quick(&self, request: &Request) -> Option<Decision> {
+        self.rules.lock().unwrap().decision(&key(request))
+    }
+
+    fn decide(&self, request: &Request) -> Decision {
+        let key = key(request);
+        let comm = std::fs::read_to_string(format!("/proc/{}/comm", request.pid)).unwrap_or_default();
+        let summary = format!("{} comm={} wants to {}", request, comm.trim_end(), key.0);
+        let (reply, answer) = mpsc::channel();
+        let question = Question { summary, key, deadline: Instant::now() + self.timeout, reply };
+        if self.questions.send(question).is_err() {
+            return decision(self.default, "the prompt is gone");
+        }
+        answer.recv_timeout(self.timeout).unwrap_or_else(|_| decision(self.default, "no answer"))
+    }
+}
+
+enum Input {
+    Line(String),
+    Timeout,
+    Closed,
+}
+
+/// Lines from stdin with a timeout; read directly from fd 0 so poll() sees
+/// everything not yet consumed.
+struct TerminalInput {
+    pending: Vec<u8>,
+    closed: bool,
+}
+
+impl TerminalInput {
+    fn line(&mut self, timeout: Duration) -> Input {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.pending.drain(..=end).collect();
+                return Input::Line(String::from_utf8_lossy(&line).trim().to_string());
+            }
+            if self.closed {
+                return Input::Closed;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Input::Timeout;
+            }
+            let mut fds = [libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 }];
+            let wait = remaining.as_micros().div_ceil(1000).min(i32::MAX as u128) as libc::c_int;
+            match unsafe { libc::poll(fds.as_mut_ptr(), 1, wait) } {
+                -1 if get_errno() == libc::EINTR => continue,
+                -1 => self.closed = true,
+                0 => return Input::Timeout,
+                _ => {
+                    let mut buffer = [0u8; 256];
+                    let n = unsafe { libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+                    match n {
+                        -1 if get_errno() == libc::EINTR => {}
+                        n if n <= 0 => self.closed = true,
+                        n => self.pending.extend_from_slice(&buffer[..n as usize]),
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The terminal thread: one question at a time, in arrival order
+fn terminal(rules: Arc<Mutex<Rules>>, questions: Receiver<Question>, default: Verdict) {
+    let mut input = TerminalInput { pending: Vec::new(), closed: false };
+    for question in questions {
+        if question.deadline <= Instant::now() {
+            continue;
+        }
+        // An earlier "always" answer may cover questions queued behind it
+        if let Some(decision) = rules.lock().unwrap().decision(&question.key) {
+            let _ = question.reply.send(decision);
+            continue;
+        }
+        if input.closed {
+            let _ = question.reply.send(decision(default, "no terminal"));
+            continue;
+        }
+        let remaining = question.deadline.saturating_duration_since(Instant::now());
+        println!("\n❓ [PERMISSION] {}", question.summary);
+        println!("   [a] allow once  [A] allow always  [d] deny once  [D] deny always  ({} in {}s)", default, remaining.as_secs());
+        let _ = io::stdout().flush();
+        let answer = loop {
+            let remaining = question.deadline.saturating_duration_since(Instant::now());
+            let (verdict, always) = match input.line(remaining) {
+                Input::Line(answer) => match answer.as_str() {
+                    "a" => (Verdict::Allow, false),
+                    "A" => (Verdict::Allow, true),
+                    "d" => (Verdict::Deny, false),
+                    "D" => (Verdict::Deny, true),
+                    _ => {
+                        println!("   Answer a, A, d or D");
+                        continue;
+                    }
+                },
+                Input::Timeout => {
+                    println!("⏱️  No answer, {} by default", default);
+                    break None;
+                }
+                Input::Closed => {
+                    println!("⚠️  Terminal input closed: {} by default from now on", default);
+                    break Some(decision(default, "no terminal"));
+                }
+            };
+            let reason = if always {
+                rules.lock().unwrap().store(question.key.clone(), verdict);
+                "always, at the prompt (rule stored)"
+            } else {
+                "once, at the prompt"
+            };
+            break Some(decision(verdict, reason));
+        };
+        // Unanswered questions get the daemon's fallback at their deadline
+        if let Some(answer) = answer {
+            let _ = question.reply.send(answer);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+// Protected paths: FAN_OPEN_PERM events on them are denied when the open
+// asks for write access, unless the process matches an exception rule.
+//
+// The event fd does not tell: fcntl(F_GETFL) on it reports the listener's
+// own event_f_flags (O_RDONLY here), never the flags of the open being
+// decided. The opening thread is blocked inside its open call, though, so its
+// arguments are read from /proc/<tid>/syscall; the group reports thread ids
+// (FAN_REPORT_TID) for that. Opens whose flags cannot be found that way
+// (io_uring, an exited thread) count as writes.
+//
+// Only opens are permission events: unlink and rename of protected files are
+// not blocked, and directories created after startup are not marked.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use crate::perm::{Decision, PermissionGroup, Policy, Request};
+use crate::sys::*;
+
+/// Who may write to protected paths anyway.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exception {
+    Exe(PathBuf),
+    Uid(u32),
+}
+
+impl Exception {
+    /// `exe:<absolute path>` or `uid:<number>`.
+    pub fn parse(rule: &str) -> Result<Exception, String> {
+        match rule.split_once(':') {
+            Some(("exe", path)) if path.starts_with('/') => Ok(Exception::Exe(PathBuf::from(path))),
+            Some(("uid", uid)) => uid.parse().map(Exception::Uid).map_err(|_| format!("not a uid: {}", uid)),
+            _ => Err(format!("expected exe:<absolute path> or uid:<number>, got {}", rule)),
+        }
+    }
+
+    fn matches(&self, request: &Request) -> bool {
+        match self {
+            Exception::Exe(exe) => request.exe.as_deref() == Some(exe.as_path()),
+            Exception::Uid(uid) => request.uid == Some(*uid),
+        }
+    }
+}
+
+impl std::fmt::Display for Exception {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Exception::Exe(exe) => write!(f, "exe:{}", exe.display()),
+            Exception::Uid(uid) => write!(f, "uid:{}", uid),
+        }
+    }
+}
+
+/// Open flags of the open call `tid` is blocked in; Err names what is unknown.
+pub(crate) fn open_flags(tid: i32) -> Result<i32, String> {
+    let syscall = std::fs::read_to_string(format!("/proc/{}/syscall", tid)).map_err(|e| format!("syscall unreadable: {}", e))?;
+    // "<nr> <arg0> ... <arg5> <sp> <pc>", or "running"
+    let fields: Vec<i64> = syscall
+        .split_whitespace()
+        .map(|field| match field.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).map(|v| v as i64).ok(),
+            None => field.parse().ok(),
+        })
+        .collect::<Option<_>>()
+        .filter(|fields: &Vec<i64>| fields.len() >= 7)
+        .ok_or("not in a system call")?;
+    let args = &fields[1..7];
+    // Numbers differ between architectures; open(2) and creat(2) only exist
+    // on the older ones, aarch64 and riscv64 go through openat(2)
+    match fields[0] as libc::c_long {
+        #[cfg(any(target_arch = "x86_64", target_arch = "x86", target_arch = "arm", target_arch = "powerpc64", target_arch = "s390x"))]
+        libc::SYS_open => Ok(args[1] as i32),
+        libc::SYS_openat | libc::SYS_open_by_handle_at => Ok(args[2] as i32),
+        #[cfg(any(target_arch = "x86_64", target_arch = "x86", target_arch = "arm", target_arch = "powerpc64", target_arch = "s390x"))]
+        libc::SYS_creat => Ok(libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC),
+        libc::SYS_execve | libc::SYS_execveat => Ok(libc::O_RDONLY),
+        libc::SYS_openat2 => {
+            // struct open_how starts with the u64 flags
+            let mem = File::open(format!("/proc/{}/mem", tid)).map_err(|e| format!("memory unreadable: {}", e))?;
+            let mut flags = [0u8; 8];
+            mem.read_exact_at(&mut flags, args[1] as u64).map_err(|e| format!("open_how unreadable: {}", e))?;
+            Ok(u64::from_ne_bytes(flags) as i32)
+        }
+        nr => Err(format!("system call {}", nr)),
+    }
+}
+
+pub(crate) fn is_write(flags: i32) -> bool {
+    flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0
+}
+
+pub struct Protector {
+    paths: Vec<PathBuf>,
+    exceptions: Vec<Exception>,
+}
+
+impl Protector {
+    pub fn new(paths: Vec<PathBuf>, exceptions: Vec<Exception>) -> Self {
+        Protector { paths, exceptions }
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    pub fn exceptions(&self) -> &[Exception] {
+        &self.exceptions
+    }
+
+    /// Adds the FAN_OPEN_PERM marks; returns how many.
+    pub fn mark(&self, group: &mut PermissionGroup) -> io::Result<usize> {
+        self.paths.iter().map(|path| group.mark_tree(path, FAN_OPEN_PERM)).sum()
+    }
+
+    fn protecting(&self, path: &Path) -> Option<&Path> {
+        self.paths.iter().find(|p| path.starts_with(p)).map(PathBuf::as_path)
+    }
+}
+
+impl Policy for Protector {
+    fn quick(&self, request: &Request) -> Option<Decision> {
+        if request.mask & FAN_OPEN_PERM == 0 {
+            return Some(Decision::allow("not an open"));
+        }
+        let Some(protected) = self.protecting(&request.path) else {
+            return Some(Decision::allow("not protected"));
+        };
+        let intent = match open_flags(request.pid) {
+            Ok(flags) if !is_write(flags) => return Some(Decision::allow("read-only open")),
+            Ok(flags) => format!("write open (flags {:#o})", flags),
+            Err(unknown) => format!("open flags unknown ({}), treated as a write", unknown),
+        };
+        if let Some(exception) = self.exceptions.iter().find(|e| e.matches(request)) {
+            return Some(Decision::allow(format!("{} allowed by exception {}", intent, exception)));
+        }
+        Some(Decision::deny(format!("{} of protected {}", intent, protected.display())).audited())
+    }
+
+    fn decide(&self, request: &Request) -> Decision {
+        self.quick(request).expect("protection is always decided quickly")
+    }
+}
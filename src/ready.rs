@@ -0,0 +1,204 @@
+// "File ready" detection for drop folders.
+//
+// A file is ready when a writer closes it (FAN_CLOSE_WRITE) and no other
+// process still has it open for writing. Writers are learned from OPEN
+// events (the opener's /proc/<pid>/fdinfo shows the access mode) and checked
+// again at each close, which is cheap and keeps busy files quiet. A file
+// that passes costs one scan of every /proc/*/fd before it is reported, since
+// opens from before the monitor started, through inherited or passed file
+// descriptors, or dropped with an overflowing queue were never seen.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::event::Event;
+use crate::mark::{path_cstring, MarkSet};
+use crate::sys::*;
+
+/// Events a drop folder is marked for.
+pub const READY_MASK: u64 = FAN_OPEN | FAN_CLOSE_WRITE | FAN_EVENT_ON_CHILD;
+
+/// A file whose last writer has gone.
+#[derive(Debug, Clone)]
+pub struct Ready {
+    pub path: PathBuf,
+    pub pid: i32,
+    pub size: u64,
+    /// Where the file was moved for processing, if it was.
+    pub moved_to: Option<PathBuf>,
+}
+
+impl Ready {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "event": "ready",
+            "mask": FAN_CLOSE_WRITE,
+            "pid": self.pid,
+            "path": self.path,
+            "size": self.size,
+            "moved_to": self.moved_to,
+        })
+    }
+}
+
+impl std::fmt::Display for Ready {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is ready ({} bytes, last writer pid {})", self.path.display(), self.size, self.pid)?;
+        if let Some(to) = &self.moved_to {
+            write!(f, ", moved to {}", to.display())?;
+        }
+        Ok(())
+    }
+}
+
+type FileKey = (u64, u64);
+
+fn file_key(path: &Path) -> io::Result<FileKey> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+/// Whether `fd` of `pid` is open with O_WRONLY or O_RDWR, from /proc/<pid>/fdinfo.
+fn fd_is_writable(pid: &str, fd: &str) -> bool {
+    let Ok(fdinfo) = std::fs::read_to_string(format!("/proc/{}/fdinfo/{}", pid, fd)) else {
+        return false;
+    };
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .and_then(|flags| u32::from_str_radix(flags.trim(), 8).ok())
+        .is_some_and(|flags| flags as i32 & libc::O_ACCMODE != libc::O_RDONLY)
+}
+
+/// Which of `pids` hold `key` open for writing.
+fn writers_of(key: FileKey, pids: impl Iterator<Item = String>) -> HashSet<i32> {
+    let mut writers = HashSet::new();
+    for pid in pids {
+        let Ok(fds) = std::fs::read_dir(format!("/proc/{}/fd", pid)) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            // metadata() follows the magic link to the open file itself
+            let Ok(metadata) = fd.path().metadata() else {
+                continue;
+            };
+            if (metadata.dev(), metadata.ino()) == key && fd_is_writable(&pid, &fd.file_name().to_string_lossy()) {
+                if let Ok(pid) = pid.parse() {
+                    writers.insert(pid);
+                }
+                break;
+            }
+        }
+    }
+    writers
+}
+
+fn all_pids() -> impl Iterator<Item = String> {
+    let own = std::process::id().to_string();
+    std::fs::read_dir("/proc")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(move |name| name.bytes().all(|b| b.is_ascii_digit()) && *name != own)
+}
+
+pub struct ReadyDetector {
+    dirs: Vec<PathBuf>,
+    processing_dir: Option<PathBuf>,
+    /// Writers seen opening each file, pruned whenever one closes.
+    writers: HashMap<FileKey, HashSet<i32>>,
+}
+
+// Files opened only for reading keep an empty entry; past this many they are dropped
+const TRACKED_FILES: usize = 4096;
+
+impl ReadyDetector {
+    pub fn new(processing_dir: Option<&Path>) -> Self {
+        ReadyDetector { dirs: Vec::new(), processing_dir: processing_dir.map(Path::to_path_buf), writers: HashMap::new() }
+    }
+
+    /// Marks `dir` so files written into it are checked for readiness.
+    pub fn watch(&mut self, marks: &mut MarkSet, dir: &Path) -> io::Result<()> {
+        if let Some(processing) = &self.processing_dir {
+            // rename(2) is only atomic within one filesystem
+            if std::fs::metadata(processing)?.dev() != std::fs::metadata(dir)?.dev() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is on another filesystem than {}", processing.display(), dir.display()),
+                ));
+            }
+        }
+        marks.add(dir, FAN_MARK_INODE, READY_MASK, FAN_MARK_ONLYDIR)?;
+        self.dirs.push(dir.to_path_buf());
+        Ok(())
+    }
+
+    pub fn dirs(&self) -> &[PathBuf] {
+        &self.dirs
+    }
+
+    /// Tracks writers on OPEN and checks readiness on CLOSE_WRITE.
+    pub fn handle(&mut self, event: &Event) -> Option<io::Result<Ready>> {
+        let path = event.path.as_deref().filter(|p| !event.is_dir() && self.dirs.iter().any(|d| p.parent() == Some(d)))?;
+        let key = file_key(path).ok()?;
+
+        if event.mask & FAN_OPEN != 0 {
+            if self.writers.len() >= TRACKED_FILES {
+                self.writers.retain(|_, writers| !writers.is_empty());
+            }
+            let writers = self.writers.entry(key).or_default();
+            // Merged with its close: the opener is done with it already
+            if event.mask & FAN_CLOSE_WRITE == 0 {
+                if !writers_of(key, std::iter::once(event.pid.to_string())).is_empty() {
+                    writers.insert(event.pid);
+                }
+                return None;
+            }
+        }
+        if event.mask & FAN_CLOSE_WRITE == 0 {
+            return None;
+        }
+
+        // Writers we saw open the file
+        let known = self.writers.remove(&key);
+        let still_writing = writers_of(key, known.into_iter().flatten().map(|pid| pid.to_string()));
+        if !still_writing.is_empty() {
+            self.writers.insert(key, still_writing);
+            return None;
+        }
+        // Writers whose opens we never saw; once per file about to be reported
+        let unseen = writers_of(key, all_pids());
+        if !unseen.is_empty() {
+            self.writers.insert(key, unseen);
+            return None;
+        }
+
+        let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        let mut ready = Ready { path: path.to_path_buf(), pid: event.pid, size, moved_to: None };
+        if let Some(processing) = &self.processing_dir {
+            let target = processing.join(path.file_name()?);
+            if let Err(e) = rename_noreplace(path, &target) {
+                return Some(Err(io::Error::new(e.kind(), format!("moving {} to {}: {}", path.display(), target.display(), e))));
+            }
+            ready.moved_to = Some(target);
+        }
+        Some(Ok(ready))
+    }
+}
+
+/// rename(2) that fails with EEXIST instead of replacing an existing file.
+fn rename_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    let from_cstr = path_cstring(from)?;
+    let to_cstr = path_cstring(to)?;
+    let result =
+        unsafe { libc::renameat2(AT_FDCWD, from_cstr.as_ptr(), AT_FDCWD, to_cstr.as_ptr(), libc::RENAME_NOREPLACE) };
+    if result == -1 {
+        return Err(io::Error::from_raw_os_error(get_errno()));
+    }
+    Ok(())
+}
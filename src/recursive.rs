@@ -0,0 +1,209 @@
+// Recursive directory tree watching with inode marks.
+//
+// For callers that may not place mount or filesystem marks: every directory
+// in the tree gets its own inode mark with FAN_EVENT_ON_CHILD, directories
+// created (or moved in) later are marked as soon as their event arrives, and
+// marks are dropped again when a directory is deleted or moved out.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::event::Event;
+use crate::mark::{MarkObject, MarkSet};
+use crate::sys::*;
+
+/// Events every directory mark needs to keep the tree in sync.
+pub const TREE_MASK: u64 = FAN_CREATE | FAN_DELETE | FAN_MOVE | FAN_ONDIR | FAN_EVENT_ON_CHILD;
+
+const MAX_USER_MARKS: &str = "/proc/sys/fs/fanotify/max_user_marks";
+
+/// The fs.fanotify.max_user_marks sysctl, if the kernel exposes it (5.13+).
+pub fn max_user_marks() -> Option<u64> {
+    std::fs::read_to_string(MAX_USER_MARKS).ok()?.trim().parse().ok()
+}
+
+#[derive(Debug)]
+pub enum WatchError {
+    /// fanotify_mark() failed with ENOSPC: the per-user mark limit is exhausted.
+    MarkLimit { limit: Option<u64>, marked: usize, path: PathBuf },
+    Io { path: PathBuf, source: io::Error },
+}
+
+impl std::fmt::Display for WatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchError::MarkLimit { limit, marked, path } => {
+                write!(f, "mark limit reached at {} after {} marks", path.display(), marked)?;
+                match limit {
+                    Some(limit) => write!(f, " (fs.fanotify.max_user_marks = {}; raise it in {})", limit, MAX_USER_MARKS),
+                    None => write!(f, " (the kernel's per-user fanotify mark limit)"),
+                }
+            }
+            WatchError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+        }
+    }
+}
+
+impl std::error::Error for WatchError {}
+
+/// What a directory event did to the watched tree.
+#[derive(Debug)]
+pub enum TreeChange {
+    Added { path: PathBuf, dirs: usize },
+    Removed { path: PathBuf, dirs: usize },
+    Moved { from: PathBuf, to: PathBuf, dirs: usize },
+}
+
+impl std::fmt::Display for TreeChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TreeChange::Added { path, dirs } => write!(f, "marked {} new director(ies) under {}", dirs, path.display()),
+            TreeChange::Removed { path, dirs } => write!(f, "dropped {} director(ies) under {}", dirs, path.display()),
+            TreeChange::Moved { from, to, dirs } => {
+                write!(f, "{} director(ies) moved from {} to {}", dirs, from.display(), to.display())
+            }
+        }
+    }
+}
+
+pub struct RecursiveWatch {
+    root: PathBuf,
+    mask: u64,
+    dirs: BTreeMap<PathBuf, MarkObject>,
+}
+
+impl RecursiveWatch {
+    /// `events` are reported for every file in the tree in addition to [`TREE_MASK`].
+    pub fn new(root: &Path, events: u64) -> Self {
+        RecursiveWatch { root: root.to_path_buf(), mask: events | TREE_MASK, dirs: BTreeMap::new() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    /// Number of directories currently marked.
+    pub fn len(&self) -> usize {
+        self.dirs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirs.is_empty()
+    }
+
+    /// Walks the tree and marks every directory.
+    pub fn start(&mut self, marks: &mut MarkSet) -> Result<usize, WatchError> {
+        let root = self.root.clone();
+        self.mark_tree(marks, &root)
+    }
+
+    /// Keeps the marks in sync with a directory event. Events outside the
+    /// tree or about plain files return `None`.
+    pub fn handle(&mut self, marks: &mut MarkSet, event: &Event) -> Result<Option<TreeChange>, WatchError> {
+        // Without a name the event path is the parent, not the entry
+        if !event.is_dir() || event.name.is_none() {
+            return Ok(None);
+        }
+        let Some(path) = event.path.as_deref().filter(|p| p.starts_with(&self.root)) else {
+            return Ok(None);
+        };
+
+        if event.mask & FAN_MOVED_FROM != 0 {
+            // With FAN_REPORT_TARGET_FID the FID record identifies the directory itself, so
+            // we can find where it went. Without it the subtree is forgotten and re-marked
+            // by the matching MOVED_TO if it stayed inside the root.
+            let to = event.fid.as_ref().and_then(|fid| marks.resolver().handle_path(fid));
+            return Ok(Some(match to {
+                Some(to) if to.starts_with(&self.root) => {
+                    let dirs = self.rekey(path, &to);
+                    TreeChange::Moved { from: path.to_path_buf(), to, dirs }
+                }
+                to => TreeChange::Removed { path: path.to_path_buf(), dirs: self.unmark_tree(marks, path, to.as_deref()) },
+            }));
+        }
+        if event.mask & (FAN_CREATE | FAN_MOVED_TO) != 0 {
+            let dirs = self.mark_tree(marks, path)?;
+            return Ok((dirs > 0).then(|| TreeChange::Added { path: path.to_path_buf(), dirs }));
+        }
+        if event.mask & FAN_DELETE != 0 {
+            // The kernel drops marks of deleted inodes on its own
+            let dirs = self.unmark_tree(marks, path, None);
+            return Ok((dirs > 0).then(|| TreeChange::Removed { path: path.to_path_buf(), dirs }));
+        }
+        Ok(None)
+    }
+
+    // Marks `top` before listing it, so subdirectories created meanwhile are
+    // either seen by the listing or reported by the new mark.
+    fn mark_tree(&mut self, marks: &mut MarkSet, top: &Path) -> Result<usize, WatchError> {
+        let limit = max_user_marks();
+        let mut added = 0;
+        let mut stack = vec![top.to_path_buf()];
+        while let Some(dir) = stack.pop() {
+            if !self.dirs.contains_key(&dir) {
+                let object = match MarkObject::of(&dir, FAN_MARK_INODE, true) {
+                    Ok(object) => object,
+                    // Removed before we got to it
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(WatchError::Io { path: dir, source: e }),
+                };
+                let flags = FAN_MARK_ONLYDIR | FAN_MARK_DONT_FOLLOW;
+                match marks.add(&dir, FAN_MARK_INODE, self.mask, flags) {
+                    Ok(()) => {}
+                    Err(e) if e.raw_os_error() == Some(libc::ENOSPC) => {
+                        return Err(WatchError::MarkLimit { limit, marked: marks.len(), path: dir });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(WatchError::Io { path: dir, source: e }),
+                }
+                self.dirs.insert(dir.clone(), object);
+                added += 1;
+            }
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(WatchError::Io { path: dir, source: e }),
+            };
+            for entry in entries.flatten() {
+                // file_type() does not follow symlinks, so linked trees are not entered
+                if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                    stack.push(entry.path());
+                }
+            }
+        }
+        Ok(added)
+    }
+
+    fn subtree(&self, top: &Path) -> Vec<PathBuf> {
+        self.dirs.range(top.to_path_buf()..).map(|(p, _)| p).take_while(|p| p.starts_with(top)).cloned().collect()
+    }
+
+    // Forgets `top` and everything below it. When the tree still exists at
+    // `now_at` (moved out of the root), its marks are removed from the kernel.
+    fn unmark_tree(&mut self, marks: &mut MarkSet, top: &Path, now_at: Option<&Path>) -> usize {
+        let subtree = self.subtree(top);
+        for dir in &subtree {
+            let object = self.dirs.remove(dir).expect("subtree entries are tracked");
+            let current = now_at.map(|to| to.join(dir.strip_prefix(top).unwrap_or(Path::new(""))));
+            let removed = current.is_some_and(|current| marks.remove(&current, FAN_MARK_INODE, self.mask).is_ok());
+            if !removed {
+                marks.forget(&object);
+            }
+        }
+        subtree.len()
+    }
+
+    fn rekey(&mut self, from: &Path, to: &Path) -> usize {
+        let subtree = self.subtree(from);
+        for dir in &subtree {
+            let object = self.dirs.remove(dir).expect("subtree entries are tracked");
+            self.dirs.insert(to.join(dir.strip_prefix(from).unwrap_or(Path::new(""))), object);
+        }
+        subtree.len()
+    }
+}
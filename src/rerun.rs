@@ -0,0 +1,463 @@
+// `run` subcommand: rerun a command whenever files under the watched roots change.
+//
+// Changes come from mount marks (one per root, so a large tree costs no
+// per-directory watches) plus, where the kernel allows it, a filesystem
+// mark for creations, deletions and renames, which mount marks cannot
+// report. Paths outside the roots or matched by ignore files are dropped,
+// bursts are debounced, and the command runs in its own process group so a
+// restart takes down everything it started.
+
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::action::glob_match;
+use crate::event::parse_events;
+use crate::mark::MarkSet;
+use crate::signal::SignalFd;
+use crate::sys::*;
+
+pub const USAGE: &str = "\
+Usage: fanotify_demo run [OPTIONS] -- <COMMAND> [ARGS...]
+
+Runs COMMAND and runs it again whenever files under the watched roots change.
+
+Options:
+  -w, --watch <PATH>      Root to watch (repeatable) [default: .]
+  --debounce <MS>         Wait until changes have stopped for MS milliseconds [default: 100]
+  --on-busy <MODE>        While the command still runs: restart | queue [default: restart]
+  --stop-timeout <MS>     Time between SIGTERM and SIGKILL when stopping the command [default: 2000]
+  -i, --ignore <PATTERN>  gitignore-style pattern to ignore (repeatable)
+  --no-ignore-files       Do not read .gitignore and .ignore in the roots
+  -c, --clear             Clear the screen before each run
+  --postpone              Wait for the first change instead of running right away
+  -h, --help              Print this help
+";
+
+/// What a change does while the previous run has not finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnBusy {
+    /// Stop the process group and start over.
+    Restart,
+    /// Let it finish, then run once more.
+    Queue,
+}
+
+#[derive(Debug)]
+pub struct RerunOptions {
+    pub roots: Vec<PathBuf>,
+    pub debounce: Duration,
+    pub on_busy: OnBusy,
+    pub stop_timeout: Duration,
+    pub ignores: Vec<String>,
+    pub ignore_files: bool,
+    pub clear: bool,
+    pub postpone: bool,
+    pub command: Vec<String>,
+    pub help: bool,
+}
+
+impl Default for RerunOptions {
+    fn default() -> Self {
+        RerunOptions {
+            roots: Vec::new(),
+            debounce: Duration::from_millis(100),
+            on_busy: OnBusy::Restart,
+            stop_timeout: Duration::from_secs(2),
+            ignores: Vec::new(),
+            ignore_files: true,
+            clear: false,
+            postpone: false,
+            command: Vec::new(),
+            help: false,
+        }
+    }
+}
+
+impl RerunOptions {
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<RerunOptions, String> {
+        let mut opts = RerunOptions::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-w" | "--watch" => opts.roots.push(PathBuf::from(value(&mut args, &arg)?)),
+                "--debounce" => opts.debounce = millis(&mut args, &arg)?,
+                "--stop-timeout" => opts.stop_timeout = millis(&mut args, &arg)?,
+                "--on-busy" => {
+                    opts.on_busy = match value(&mut args, &arg)?.as_str() {
+                        "restart" => OnBusy::Restart,
+                        "queue" => OnBusy::Queue,
+                        other => return Err(format!("{}: expected restart or queue, got {}", arg, other)),
+                    }
+                }
+                "-i" | "--ignore" => opts.ignores.push(value(&mut args, &arg)?),
+                "--no-ignore-files" => opts.ignore_files = false,
+                "-c" | "--clear" => opts.clear = true,
+                "--postpone" => opts.postpone = true,
+                "-h" | "--help" => opts.help = true,
+                "--" => {
+                    opts.command = args.by_ref().collect();
+                    break;
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown argument: {}", arg)),
+                _ => {
+                    opts.command = std::iter::once(arg).chain(args.by_ref()).collect();
+                    break;
+                }
+            }
+        }
+        if opts.command.is_empty() && !opts.help {
+            return Err("run: no command given".to_string());
+        }
+        if opts.roots.is_empty() {
+            opts.roots.push(PathBuf::from("."));
+        }
+        Ok(opts)
+    }
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} requires a value", flag))
+}
+
+fn millis<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<Duration, String> {
+    let ms = value(args, flag)?;
+    ms.parse().map(Duration::from_millis).map_err(|_| format!("{}: not a number of milliseconds: {}", flag, ms))
+}
+
+struct IgnoreRule {
+    /// Directory the pattern is relative to.
+    base: PathBuf,
+    glob: String,
+    negate: bool,
+    dir_only: bool,
+    /// Contains a slash, so it matches the whole relative path, not just a name.
+    anchored: bool,
+}
+
+/// gitignore-style rules: `#` comments, `!` re-includes, a trailing `/`
+/// only matches directories, and a pattern with a slash is relative to the
+/// file it came from. Ignoring a directory ignores everything below it.
+#[derive(Default)]
+pub struct IgnoreRules {
+    rules: Vec<IgnoreRule>,
+}
+
+impl IgnoreRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, base: &Path, line: &str) {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return;
+        }
+        let (negate, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let line = line.strip_prefix("**/").unwrap_or(line);
+        let anchored = line.contains('/');
+        let glob = line.trim_start_matches('/').to_string();
+        self.rules.push(IgnoreRule { base: base.to_path_buf(), glob, negate, dir_only, anchored });
+    }
+
+    /// Reads an ignore file; patterns are relative to its directory.
+    pub fn add_file(&mut self, path: &Path) -> io::Result<usize> {
+        let text = std::fs::read_to_string(path)?;
+        let base = path.parent().unwrap_or(Path::new("/"));
+        let before = self.rules.len();
+        for line in text.lines() {
+            self.add(base, line);
+        }
+        Ok(self.rules.len() - before)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        // Each directory on the way down first, then the path itself
+        let ancestors: Vec<&Path> = path.ancestors().collect();
+        for (depth, prefix) in ancestors.iter().rev().enumerate() {
+            let last = depth == ancestors.len() - 1;
+            if self.matches(prefix, !last || is_dir) {
+                return true;
+            }
+        }
+        false
+    }
+
+    // The last matching rule decides, as in git
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for rule in &self.rules {
+            let Ok(relative) = path.strip_prefix(&rule.base) else {
+                continue;
+            };
+            if relative.as_os_str().is_empty() || (rule.dir_only && !is_dir) {
+                continue;
+            }
+            let subject = match (rule.anchored, relative.file_name()) {
+                (false, Some(name)) => name.as_bytes(),
+                _ => relative.as_os_str().as_bytes(),
+            };
+            if glob_match(rule.glob.as_bytes(), subject) {
+                ignored = !rule.negate;
+            }
+        }
+        ignored
+    }
+}
+
+/// Ignore files read from each root.
+const IGNORE_FILES: &[&str] = &[".gitignore", ".ignore"];
+
+fn start(opts: &RerunOptions) -> io::Result<Child> {
+    if opts.clear {
+        print!("\x1b[2J\x1b[3J\x1b[H");
+    }
+    println!("▶️  Running: {}", opts.command.join(" "));
+    Command::new(&opts.command[0])
+        .args(&opts.command[1..])
+        // Not in the terminal's foreground group, so it must not read the terminal
+        .stdin(Stdio::null())
+        .process_group(0)
+        .spawn()
+}
+
+/// SIGTERM to the whole process group, SIGKILL if it is still there after `timeout`.
+fn stop(child: &mut Child, timeout: Duration) -> io::Result<ExitStatus> {
+    let group = -(child.id() as i32);
+    unsafe { libc::kill(group, libc::SIGTERM) };
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            // The leader is gone; make sure the rest of its group is too
+            unsafe { libc::kill(group, libc::SIGKILL) };
+            return Ok(status);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    println!("DEBUG: Process group {} ignored SIGTERM for {:?}, sending SIGKILL", -group, timeout);
+    unsafe { libc::kill(group, libc::SIGKILL) };
+    child.wait()
+}
+
+fn describe(status: ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(0), _) => "✅ Command finished successfully".to_string(),
+        (Some(code), _) => format!("❌ Command exited with status {}", code),
+        (None, Some(signal)) => format!("❌ Command killed by signal {}", signal),
+        (None, None) => "❌ Command ended".to_string(),
+    }
+}
+
+/// Entry point of `fanotify_demo run ...`.
+pub fn main(args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut opts = RerunOptions::parse(args)?;
+    if opts.help {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    opts.roots = opts.roots.iter().map(std::fs::canonicalize).collect::<io::Result<_>>()?;
+
+    // Blocked before the command is spawned; the child gets a clean mask from std
+    let signals = SignalFd::new(&[libc::SIGINT, libc::SIGTERM])?;
+
+    let mut ignores = IgnoreRules::new();
+    for root in &opts.roots {
+        ignores.add(root, ".git/");
+        for pattern in &opts.ignores {
+            ignores.add(root, pattern);
+        }
+        if opts.ignore_files {
+            for name in IGNORE_FILES {
+                if let Ok(n) = ignores.add_file(&root.join(name)) {
+                    println!("DEBUG: {} pattern(s) from {}", n, root.join(name).display());
+                }
+            }
+        }
+    }
+
+    let fanotify_fd = unsafe {
+        fanotify_init(FAN_CLASS_NOTIF | FAN_CLOEXEC | FAN_NONBLOCK | FAN_REPORT_FID | FAN_REPORT_DFID_NAME, libc::O_RDONLY as u32)
+    };
+    if fanotify_fd == -1 {
+        return Err(format!("fanotify_init failed: {}", io::Error::from_raw_os_error(get_errno())).into());
+    }
+    let mut marks = MarkSet::new(fanotify_fd);
+    for root in &opts.roots {
+        marks.add(root, FAN_MARK_MOUNT, FAN_CLOSE_WRITE, 0)?;
+        // Mount marks cannot report directory entry events; a filesystem mark can
+        match marks.add(root, FAN_MARK_FILESYSTEM, FAN_CREATE | FAN_DELETE | FAN_MOVE | FAN_ONDIR, 0) {
+            Ok(()) => println!("👀 Watching {} (writes via its mount, renames and deletions via its filesystem)", root.display()),
+            Err(e) => println!("👀 Watching {} for writes only (no filesystem mark: {})", root.display(), e),
+        }
+    }
+
+    let mut child = if opts.postpone { None } else { Some(start(&opts)?) };
+    let mut queued = false;
+    let mut deadline: Option<Instant> = None;
+    let mut changed: Vec<PathBuf> = Vec::new();
+    let mut buffer = [0u8; 8192];
+
+    loop {
+        let wait = [
+            deadline.map(|d| d.saturating_duration_since(Instant::now())),
+            child.as_ref().map(|_| Duration::from_millis(100)),
+        ];
+        let timeout = wait.into_iter().flatten().min().map_or(-1, |w| w.as_millis().max(1) as libc::c_int);
+        let mut fds = [
+            libc::pollfd { fd: fanotify_fd, events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: signals.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } == -1 && get_errno() != libc::EINTR {
+            return Err(io::Error::from_raw_os_error(get_errno()).into());
+        }
+
+        if fds[1].revents & libc::POLLIN != 0 && signals.read().is_some() {
+            if let Some(mut running) = child.take() {
+                println!("\n⏹️  Stopping the command");
+                stop(&mut running, opts.stop_timeout)?;
+            }
+            unsafe { libc::close(fanotify_fd) };
+            return Ok(());
+        }
+
+        if fds[0].revents & libc::POLLIN != 0 {
+            let n = unsafe { libc::read(fanotify_fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if n > 0 {
+                for mut event in parse_events(&buffer[..n as usize]) {
+                    marks.resolver().resolve(&mut event);
+                    let is_dir = event.is_dir();
+                    let Some(path) = event.path else {
+                        continue;
+                    };
+                    if !opts.roots.iter().any(|root| path.starts_with(root)) || ignores.is_ignored(&path, is_dir) {
+                        continue;
+                    }
+                    deadline = Some(Instant::now() + opts.debounce);
+                    if !changed.contains(&path) {
+                        changed.push(path);
+                    }
+                }
+            }
+        }
+
+        if let Some(running) = &mut child
+            && let Some(status) = running.try_wait()?
+        {
+            println!("{}", describe(status));
+            child = None;
+            if queued {
+                queued = false;
+                child = Some(start(&opts)?);
+            }
+        }
+
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            deadline = None;
+            match changed.as_slice() {
+                [one] => println!("\n🔄 Changed: {}", one.display()),
+                [first, rest @ ..] => println!("\n🔄 Changed: {} and {} more", first.display(), rest.len()),
+                [] => {}
+            }
+            changed.clear();
+            match (&mut child, opts.on_busy) {
+                (Some(running), OnBusy::Restart) => {
+                    println!("🔁 Restarting");
+                    stop(running, opts.stop_timeout)?;
+                    child = Some(start(&opts)?);
+                }
+                (Some(_), OnBusy::Queue) => {
+                    println!("⏳ Still running; will run again when it finishes");
+                    queued = true;
+                }
+                (None, _) => child = Some(start(&opts)?),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(lines: &[&str]) -> IgnoreRules {
+        let mut rules = IgnoreRules::new();
+        for line in lines {
+            rules.add(Path::new("/repo"), line);
+        }
+        rules
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        assert!(rules(&["# comment", "", "   "]).is_empty());
+        assert!(rules(&["\\#literal"]).is_ignored(Path::new("/repo/#literal"), false));
+    }
+
+    #[test]
+    fn unanchored_patterns_match_the_name_anywhere() {
+        let rules = rules(&["*.o"]);
+        assert!(rules.is_ignored(Path::new("/repo/main.o"), false));
+        assert!(rules.is_ignored(Path::new("/repo/src/deep/main.o"), false));
+        assert!(!rules.is_ignored(Path::new("/repo/main.c"), false));
+        assert!(!rules.is_ignored(Path::new("/elsewhere/main.o"), false));
+    }
+
+    #[test]
+    fn anchored_patterns_match_from_the_base() {
+        let rules = rules(&["/build", "docs/*.html"]);
+        assert!(rules.is_ignored(Path::new("/repo/build"), false));
+        assert!(!rules.is_ignored(Path::new("/repo/src/build"), false));
+        assert!(rules.is_ignored(Path::new("/repo/docs/index.html"), false));
+        assert!(!rules.is_ignored(Path::new("/repo/src/docs/index.html"), false));
+    }
+
+    #[test]
+    fn negation_re_includes_and_last_rule_wins() {
+        let negated = rules(&["*.log", "!keep.log"]);
+        assert!(negated.is_ignored(Path::new("/repo/debug.log"), false));
+        assert!(!negated.is_ignored(Path::new("/repo/keep.log"), false));
+        let reversed = rules(&["!keep.log", "*.log"]);
+        assert!(reversed.is_ignored(Path::new("/repo/keep.log"), false));
+    }
+
+    #[test]
+    fn files_in_an_ignored_directory_cannot_be_re_included() {
+        let rules = rules(&["target/", "!target/keep.txt"]);
+        assert!(rules.is_ignored(Path::new("/repo/target/keep.txt"), false));
+    }
+
+    #[test]
+    fn dir_only_patterns_skip_files() {
+        let rules = rules(&["cache/"]);
+        assert!(rules.is_ignored(Path::new("/repo/cache"), true));
+        assert!(!rules.is_ignored(Path::new("/repo/cache"), false));
+        // ... but apply to what is inside the directory
+        assert!(rules.is_ignored(Path::new("/repo/cache/entry"), false));
+        assert!(rules.is_ignored(Path::new("/repo/a/cache/entry"), false));
+    }
+
+    #[test]
+    fn leading_double_star_is_unanchored() {
+        let rules = rules(&["**/node_modules/"]);
+        assert!(rules.is_ignored(Path::new("/repo/web/node_modules/x.js"), false));
+    }
+}
@@ -0,0 +1,519 @@
+// Recognition of editor save sequences.
+//
+// Editors rarely save with a single write. Raw events for a file are held
+// back for a short window; when they form a known save sequence they are
+// replaced by one `saved` record for the real target, and otherwise they are
+// released unchanged once the window has passed. Recognised sequences:
+//
+//   in-place       open, truncate/write ..., close_write by one process
+//                  (an open for writing that changed nothing is passed
+//                  through; the queue merges these events, so a truncating
+//                  open cannot be told from the writes after it)
+//   temp-rename    create tmp, write tmp, rename tmp -> target
+//                  (VS Code, JetBrains' ___jb_tmp___/___jb_old___ dance)
+//   backup-rename  rename target -> target~, create target, write, close,
+//                  delete target~ (vim with the default backupcopy=auto)
+//
+// vim's swap files (.name.swp) never reach the output while detection is on,
+// nor does its 4913 directory probe: a file by that name created and deleted
+// again by the same process without being written.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::coalesce::{Clock, MonotonicClock};
+use crate::event::Event;
+use crate::mask::mask_names;
+use crate::sys::*;
+
+pub const DEFAULT_WINDOW: Duration = Duration::from_millis(500);
+
+/// How the file was saved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveKind {
+    InPlace,
+    TempRename,
+    BackupRename,
+}
+
+impl SaveKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            SaveKind::InPlace => "in-place",
+            SaveKind::TempRename => "temp-rename",
+            SaveKind::BackupRename => "backup-rename",
+        }
+    }
+}
+
+/// One logical save of `path`.
+#[derive(Debug, Clone)]
+pub struct Saved {
+    pub path: PathBuf,
+    pub kind: SaveKind,
+    pub pid: i32,
+    /// Union of the raw events the save consisted of.
+    pub mask: u64,
+    pub count: usize,
+    /// The temporary file that was renamed over `path`, for temp-rename saves.
+    pub temp: Option<PathBuf>,
+}
+
+impl Saved {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "event": "saved",
+            "kind": self.kind.name(),
+            "mask": self.mask,
+            "events": mask_names(self.mask),
+            "pid": self.pid,
+            "path": self.path,
+            "temp": self.temp,
+            "count": self.count,
+        })
+    }
+}
+
+impl std::fmt::Display for Saved {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} saved by pid {} ({}, {} raw events", self.path.display(), self.pid, self.kind.name(), self.count)?;
+        if let Some(temp) = &self.temp {
+            write!(f, " via {}", temp.display())?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[derive(Debug)]
+pub enum SaveOutput {
+    /// An event that is not part of a save, passed through unchanged.
+    Raw(Event),
+    Saved(Saved),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Written in place; saved at close_write if opened and changed.
+    Writing,
+    /// Created during the window: a new file, or a temp file about to be renamed.
+    Fresh,
+    /// Renamed away (possibly into a backup name); waiting to be recreated.
+    Displaced,
+    /// Recreated after being displaced; saved at close_write.
+    Rewriting,
+    /// Saved; trailing events (chmod, backup removal) are swallowed.
+    Done,
+    /// Looks like vim's probe; dropped when its creator deletes it unwritten.
+    Probe,
+}
+
+struct Session {
+    state: State,
+    events: Vec<Event>,
+    mask: u64,
+    pid: i32,
+    last: Duration,
+    kind: SaveKind,
+}
+
+impl Session {
+    fn new(state: State, pid: i32, now: Duration) -> Self {
+        Session { state, events: Vec::new(), mask: 0, pid, last: now, kind: SaveKind::InPlace }
+    }
+
+    fn push(&mut self, event: Event, now: Duration) {
+        self.mask |= event.mask;
+        self.last = now;
+        self.events.push(event);
+    }
+
+    /// Opened and truncated or written: the MODIFY/OPEN pair of a rewrite,
+    /// in whatever order the queue merged them.
+    fn rewritten(&self) -> bool {
+        self.mask & FAN_OPEN != 0 && self.mask & FAN_MODIFY != 0
+    }
+
+    fn saved(&mut self, path: &Path, temp: Option<PathBuf>) -> Saved {
+        let count = self.events.len();
+        self.events.clear();
+        self.state = State::Done;
+        Saved { path: path.to_path_buf(), kind: self.kind, pid: self.pid, mask: self.mask, count, temp }
+    }
+}
+
+/// vim's swap files (.name.swp, .swo, ...).
+pub fn is_editor_scratch(path: &Path) -> bool {
+    let Some(name) = path.file_name().map(OsStr::as_bytes) else {
+        return false;
+    };
+    name.starts_with(b".")
+        && name.len() > 5
+        && name[name.len() - 4..name.len() - 1] == *b".sw"
+        && name[name.len() - 1].is_ascii_lowercase()
+}
+
+// vim gives up looking for a free probe name long before this
+const PROBE_RETRIES: u64 = 8;
+
+/// Whether `path` has the name of vim's "can I create files here" probe:
+/// 4913, or 4913 + 123 * n when that is taken.
+pub fn is_probe_name(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(OsStr::to_str) else {
+        return false;
+    };
+    // Plain digits only: "+4913" and "04913" parse too but are not vim's
+    if !name.bytes().all(|b| b.is_ascii_digit()) || name.starts_with('0') {
+        return false;
+    }
+    name.parse::<u64>().is_ok_and(|n| n >= 4913 && (n - 4913) % 123 == 0 && (n - 4913) / 123 < PROBE_RETRIES)
+}
+
+/// Whether `backup` is the name an editor moves `target` to while saving.
+/// When name~ is taken, vim replaces the last character of the name
+/// (name~ -> namz~ -> namy~ ...), so that counts too.
+pub fn is_backup_of(backup: &Path, target: &Path) -> bool {
+    let (Some(backup_name), Some(target_name)) = (backup.file_name(), target.file_name()) else {
+        return false;
+    };
+    let (backup_name, target_name) = (backup_name.as_bytes(), target_name.as_bytes());
+    if backup.parent() != target.parent() {
+        return false;
+    }
+    if let Some(suffix) = backup_name.strip_prefix(target_name)
+        && [&b"~"[..], b"___jb_old___", b".bak", b".orig"].contains(&suffix)
+    {
+        return true;
+    }
+    backup_name.strip_suffix(b"~").is_some_and(|stem| {
+        stem.len() == target_name.len() && !stem.is_empty() && stem[..stem.len() - 1] == target_name[..target_name.len() - 1]
+    })
+}
+
+pub struct SaveDetector<C: Clock = MonotonicClock> {
+    window: Duration,
+    clock: C,
+    sessions: HashMap<PathBuf, Session>,
+    /// Backup name -> the target it was moved away from.
+    backups: HashMap<PathBuf, PathBuf>,
+    /// The last MOVED_FROM, to pair with the MOVED_TO that follows it.
+    moved_from: Option<(PathBuf, i32)>,
+}
+
+impl<C: Clock> SaveDetector<C> {
+    pub fn new(window: Duration, clock: C) -> Self {
+        SaveDetector { window, clock, sessions: HashMap::new(), backups: HashMap::new(), moved_from: None }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Feeds one raw event; returns what can be output now.
+    pub fn push(&mut self, event: Event) -> Vec<SaveOutput> {
+        let now = self.clock.now();
+        let path = match &event.path {
+            Some(path) if !event.is_dir() => path.clone(),
+            _ => return vec![SaveOutput::Raw(event)],
+        };
+        if is_editor_scratch(&path) {
+            return Vec::new();
+        }
+        let mut out = Vec::new();
+        if let Some(session) = self.sessions.get_mut(&path)
+            && session.state == State::Probe
+        {
+            if event.pid == session.pid && event.mask & FAN_MODIFY == 0 {
+                if event.mask & FAN_DELETE != 0 {
+                    self.sessions.remove(&path);
+                } else {
+                    session.push(event, now);
+                }
+                return out;
+            }
+            // Written to, or touched by someone else: a real file after all
+            out = self.release(&path);
+        }
+        out.extend(self.track(path, event, now));
+        out
+    }
+
+    fn track(&mut self, path: PathBuf, event: Event, now: Duration) -> Vec<SaveOutput> {
+        if let Some(target) = self.backups.get(&path).cloned() {
+            if let Some(session) = self.sessions.get_mut(&target) {
+                if event.mask & FAN_DELETE != 0 {
+                    self.backups.remove(&path);
+                }
+                session.push(event, now);
+                return Vec::new();
+            }
+            self.backups.remove(&path);
+        }
+
+        let mut out = Vec::new();
+        if event.mask & FAN_MOVED_FROM != 0 {
+            self.moved_from = Some((path.clone(), event.pid));
+            let session = self.sessions.entry(path).or_insert_with(|| Session::new(State::Displaced, event.pid, now));
+            if session.state == State::Done {
+                *session = Session::new(State::Displaced, event.pid, now);
+            }
+            session.push(event, now);
+        } else if event.mask & FAN_MOVED_TO != 0 {
+            let from = self.moved_from.take().filter(|(_, pid)| *pid == event.pid).map(|(from, _)| from);
+            match from {
+                // The target moved out of the way into its backup name
+                Some(from) if is_backup_of(&path, &from) && self.sessions.contains_key(&from) => {
+                    let session = self.sessions.get_mut(&from).unwrap();
+                    session.state = State::Displaced;
+                    session.push(event, now);
+                    self.backups.insert(path, from);
+                }
+                // A freshly created or written file renamed over the target
+                Some(from) if self.sessions.get(&from).is_some_and(|s| s.state == State::Fresh || s.mask & FAN_CLOSE_WRITE != 0) => {
+                    let mut temp = self.sessions.remove(&from).unwrap();
+                    let mut session = self.sessions.remove(&path).unwrap_or_else(|| Session::new(State::Writing, event.pid, now));
+                    session.events.append(&mut temp.events);
+                    session.mask |= temp.mask;
+                    session.pid = event.pid;
+                    session.kind = SaveKind::TempRename;
+                    session.push(event, now);
+                    out.push(SaveOutput::Saved(session.saved(&path, Some(from))));
+                    self.sessions.insert(path, session);
+                }
+                Some(from) => {
+                    out.extend(self.release(&from));
+                    out.push(SaveOutput::Raw(event));
+                }
+                None => out.push(SaveOutput::Raw(event)),
+            }
+        } else if event.mask & FAN_CREATE != 0 {
+            match self.sessions.get_mut(&path) {
+                // Created and deleted again in one merged event
+                None if is_probe_name(&path) && event.mask & FAN_DELETE != 0 && event.mask & FAN_MODIFY == 0 => {}
+                None if is_probe_name(&path) => {
+                    let mut session = Session::new(State::Probe, event.pid, now);
+                    session.push(event, now);
+                    self.sessions.insert(path, session);
+                }
+                Some(session) if session.state == State::Displaced => {
+                    // The queue may have merged the whole rewrite into this one event
+                    let closed = event.mask & FAN_CLOSE_WRITE != 0;
+                    session.state = State::Rewriting;
+                    session.kind = SaveKind::BackupRename;
+                    session.pid = event.pid;
+                    session.push(event, now);
+                    if closed {
+                        out.push(SaveOutput::Saved(session.saved(&path, None)));
+                    }
+                }
+                _ => {
+                    out.extend(self.release(&path));
+                    let mut session = Session::new(State::Fresh, event.pid, now);
+                    session.push(event, now);
+                    self.sessions.insert(path, session);
+                }
+            }
+        } else if event.mask & FAN_DELETE != 0 {
+            match self.sessions.get_mut(&path) {
+                // e.g. a temp file cleaned up after its content was copied over
+                Some(session) if session.state == State::Done => session.push(event, now),
+                _ => {
+                    out.extend(self.release(&path));
+                    out.push(SaveOutput::Raw(event));
+                }
+            }
+        } else if event.mask & FAN_CLOSE_WRITE != 0 {
+            let session = self.sessions.entry(path.clone()).or_insert_with(|| Session::new(State::Writing, event.pid, now));
+            if session.state == State::Done {
+                *session = Session::new(State::Writing, event.pid, now);
+            }
+            session.push(event, now);
+            match session.state {
+                State::Writing if session.rewritten() => out.push(SaveOutput::Saved(session.saved(&path, None))),
+                // Opened for writing but left alone, or an open we did not see
+                State::Writing => out.extend(self.release(&path)),
+                State::Rewriting => out.push(SaveOutput::Saved(session.saved(&path, None))),
+                _ => {}
+            }
+        } else if event.mask & (FAN_MODIFY | FAN_OPEN | FAN_ATTRIB | FAN_CLOSE_NOWRITE) != 0 {
+            match self.sessions.get_mut(&path) {
+                Some(session) if session.state == State::Done && event.mask & FAN_MODIFY != 0 => {
+                    *session = Session::new(State::Writing, event.pid, now);
+                    session.push(event, now);
+                }
+                Some(session) => {
+                    let closed = event.mask & FAN_CLOSE_NOWRITE != 0;
+                    session.push(event, now);
+                    // Only read: nothing will be saved
+                    let read_only = session.mask & (FAN_MODIFY | FAN_CLOSE_WRITE | FAN_CREATE | FAN_MOVE) == 0;
+                    if session.state == State::Writing && closed && read_only {
+                        out.extend(self.release(&path));
+                    }
+                }
+                None if event.mask & (FAN_MODIFY | FAN_OPEN) != 0 && event.mask & FAN_CLOSE_NOWRITE == 0 => {
+                    let mut session = Session::new(State::Writing, event.pid, now);
+                    session.push(event, now);
+                    self.sessions.insert(path, session);
+                }
+                None => out.push(SaveOutput::Raw(event)),
+            }
+        } else {
+            out.push(SaveOutput::Raw(event));
+        }
+        out
+    }
+
+    /// Sessions idle for a whole window: everything unrecognised is released
+    /// as raw events.
+    pub fn expire(&mut self) -> Vec<SaveOutput> {
+        let now = self.clock.now();
+        let idle: Vec<PathBuf> =
+            self.sessions.iter().filter(|(_, s)| now >= s.last + self.window).map(|(p, _)| p.clone()).collect();
+        let mut out = Vec::new();
+        for path in idle {
+            match self.sessions.get(&path).map(|s| s.state) {
+                Some(State::Done) => {
+                    self.sessions.remove(&path);
+                }
+                _ => out.extend(self.release(&path)),
+            }
+        }
+        self.backups.retain(|_, target| self.sessions.contains_key(target));
+        out
+    }
+
+    /// Time until the next session goes idle, for use as a poll() timeout.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        let now = self.clock.now();
+        self.sessions.values().map(|s| (s.last + self.window).saturating_sub(now)).min()
+    }
+
+    fn release(&mut self, path: &Path) -> Vec<SaveOutput> {
+        let events = self.sessions.remove(path).map(|s| s.events).unwrap_or_default();
+        events.into_iter().map(SaveOutput::Raw).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coalesce::ManualClock;
+
+    fn event(mask: u64, pid: i32, path: &Path) -> Event {
+        Event { mask, pid, path: Some(path.to_path_buf()), ..Event::default() }
+    }
+
+    fn raw_masks(out: &[SaveOutput]) -> Vec<u64> {
+        out.iter().filter_map(|o| if let SaveOutput::Raw(e) = o { Some(e.mask) } else { None }).collect()
+    }
+
+    #[test]
+    fn backup_names() {
+        let target = Path::new("/src/main.rs");
+        assert!(is_backup_of(Path::new("/src/main.rs~"), target));
+        assert!(is_backup_of(Path::new("/src/main.rs.bak"), target));
+        assert!(is_backup_of(Path::new("/src/main.rs___jb_old___"), target));
+        assert!(is_backup_of(Path::new("/src/main.rz~"), target));
+        assert!(!is_backup_of(Path::new("/other/main.rs~"), target));
+        assert!(!is_backup_of(Path::new("/src/main.rs.tmp"), target));
+        assert!(!is_backup_of(Path::new("/src/lib.rs~"), target));
+    }
+
+    #[test]
+    fn swap_files_are_scratch() {
+        assert!(is_editor_scratch(Path::new("/src/.main.rs.swp")));
+        assert!(is_editor_scratch(Path::new("/src/.main.rs.swo")));
+        assert!(!is_editor_scratch(Path::new("/src/main.rs.swp")));
+        assert!(!is_editor_scratch(Path::new("/src/.swp")));
+        assert!(!is_editor_scratch(Path::new("/src/4913")));
+    }
+
+    #[test]
+    fn probe_names() {
+        assert!(is_probe_name(Path::new("/src/4913")));
+        assert!(is_probe_name(Path::new("/src/5036")));
+        assert!(!is_probe_name(Path::new("/src/9833")));
+        assert!(!is_probe_name(Path::new("/src/4914")));
+        assert!(!is_probe_name(Path::new("/src/04913")));
+        assert!(!is_probe_name(Path::new("/src/+4913")));
+    }
+
+    #[test]
+    fn probe_created_and_deleted_is_dropped() {
+        let clock = ManualClock::new();
+        let mut detector = SaveDetector::new(DEFAULT_WINDOW, &clock);
+        let probe = Path::new("/src/4913");
+        assert!(detector.push(event(FAN_CREATE, 7, probe)).is_empty());
+        assert!(detector.push(event(FAN_ATTRIB, 7, probe)).is_empty());
+        assert!(detector.push(event(FAN_DELETE, 7, probe)).is_empty());
+        clock.advance(DEFAULT_WINDOW);
+        assert!(detector.expire().is_empty());
+    }
+
+    #[test]
+    fn probe_name_that_is_written_passes_through() {
+        let clock = ManualClock::new();
+        let mut detector = SaveDetector::new(DEFAULT_WINDOW, &clock);
+        let file = Path::new("/src/5036");
+        assert!(detector.push(event(FAN_CREATE, 7, file)).is_empty());
+        let out = detector.push(event(FAN_MODIFY, 7, file));
+        assert_eq!(raw_masks(&out), vec![FAN_CREATE]);
+        clock.advance(DEFAULT_WINDOW);
+        assert_eq!(raw_masks(&detector.expire()), vec![FAN_MODIFY]);
+    }
+
+    #[test]
+    fn probe_deleted_by_someone_else_passes_through() {
+        let clock = ManualClock::new();
+        let mut detector = SaveDetector::new(DEFAULT_WINDOW, &clock);
+        let file = Path::new("/src/4913");
+        detector.push(event(FAN_CREATE, 7, file));
+        assert_eq!(raw_masks(&detector.push(event(FAN_DELETE, 8, file))), vec![FAN_CREATE, FAN_DELETE]);
+    }
+
+    #[test]
+    fn open_without_change_is_not_a_save() {
+        let clock = ManualClock::new();
+        let mut detector = SaveDetector::new(DEFAULT_WINDOW, &clock);
+        let file = Path::new("/src/main.rs");
+        assert!(detector.push(event(FAN_OPEN, 7, file)).is_empty());
+        let out = detector.push(event(FAN_CLOSE_WRITE, 7, file));
+        assert_eq!(raw_masks(&out), vec![FAN_OPEN, FAN_CLOSE_WRITE]);
+        // A close whose open was not seen
+        assert_eq!(raw_masks(&detector.push(event(FAN_CLOSE_WRITE, 7, file))), vec![FAN_CLOSE_WRITE]);
+    }
+
+    #[test]
+    fn truncating_rewrite_is_one_in_place_save() {
+        let clock = ManualClock::new();
+        let mut detector = SaveDetector::new(DEFAULT_WINDOW, &clock);
+        let file = Path::new("/src/main.rs");
+        assert!(detector.push(event(FAN_OPEN, 7, file)).is_empty());
+        clock.advance(DEFAULT_WINDOW / 4);
+        assert!(detector.push(event(FAN_MODIFY, 7, file)).is_empty());
+        clock.advance(DEFAULT_WINDOW / 4);
+        let out = detector.push(event(FAN_CLOSE_WRITE, 7, file));
+        let [SaveOutput::Saved(saved)] = &out[..] else {
+            panic!("expected one save, got {:?}", out);
+        };
+        assert_eq!(saved.kind, SaveKind::InPlace);
+        assert_eq!(saved.mask, FAN_OPEN | FAN_MODIFY | FAN_CLOSE_WRITE);
+        assert_eq!(saved.count, 3);
+        // A chmod right after belongs to the save
+        assert!(detector.push(event(FAN_ATTRIB, 7, file)).is_empty());
+        clock.advance(DEFAULT_WINDOW);
+        assert!(detector.expire().is_empty());
+    }
+
+    #[test]
+    fn merged_rewrite_is_a_save() {
+        let clock = ManualClock::new();
+        let mut detector = SaveDetector::new(DEFAULT_WINDOW, &clock);
+        let out = detector.push(event(FAN_OPEN | FAN_MODIFY | FAN_CLOSE_WRITE, 7, Path::new("/src/main.rs")));
+        assert!(matches!(&out[..], [SaveOutput::Saved(Saved { kind: SaveKind::InPlace, count: 1, .. })]));
+    }
+}
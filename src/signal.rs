@@ -0,0 +1,49 @@
+// Synchronous signal delivery through signalfd(2), so signals can be polled
+// next to the fanotify fd instead of interrupting it at random points.
+
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use crate::sys::get_errno;
+
+pub struct SignalFd {
+    fd: OwnedFd,
+}
+
+impl SignalFd {
+    /// Blocks `signals` for the calling thread and returns a non-blocking fd
+    /// that becomes readable when one is pending. Call this before spawning
+    /// threads: they inherit the mask, so the signal is never delivered
+    /// asynchronously anywhere.
+    pub fn new(signals: &[libc::c_int]) -> io::Result<Self> {
+        let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe { libc::sigemptyset(&mut set) };
+        for signal in signals {
+            unsafe { libc::sigaddset(&mut set, *signal) };
+        }
+        let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+        let fd = unsafe { libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        Ok(SignalFd { fd: unsafe { OwnedFd::from_raw_fd(fd) } })
+    }
+
+    /// Returns the next pending signal, if any.
+    pub fn read(&self) -> Option<libc::c_int> {
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::signalfd_siginfo>();
+        let n = unsafe { libc::read(self.fd.as_raw_fd(), &mut info as *mut _ as *mut libc::c_void, size) };
+        (n == size as isize).then_some(info.ssi_signo as libc::c_int)
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
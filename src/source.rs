@@ -0,0 +1,548 @@
+// Event sources behind one interface.
+//
+// The event loop polls `EventSource::fd` and takes normalised `Event`s from
+// `read`, whichever kernel API produced them. fanotify is preferred; when
+// the kernel lacks it (ENOSYS) or the caller may not use it (EPERM), a
+// recursive inotify source takes its place. Paths on filesystems neither
+// can watch properly (NFS, FUSE, procfs) are polled with stat(2) instead.
+// The sources do not see the same things, and `Semantics` spells out the
+// differences so they can be shown to the user instead of surprising them
+// later.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::control::ControlState;
+use crate::event::{parse_events, Event};
+use crate::mark::path_cstring;
+use crate::sys::*;
+
+/// What events from a source can and cannot tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Semantics {
+    /// Events carry the pid of the process that caused them (otherwise 0).
+    pub pid: bool,
+    /// Paths can be resolved from the file handles of FID events.
+    pub paths: bool,
+    /// A whole mount or filesystem is one mark; otherwise trees need one watch per directory.
+    pub mount_marks: bool,
+    /// Marks live in a `MarkSet`: sticky, wait-for, ignore and ready watches, the
+    /// control socket and config reloads can change them.
+    pub marks: bool,
+    /// Changes are seen by diffing snapshots rather than reported by the kernel.
+    pub polled: bool,
+}
+
+impl std::fmt::Display for Semantics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut notes = Vec::new();
+        if !self.pid {
+            notes.push("no pid (reported as 0)");
+        }
+        if !self.paths {
+            notes.push("no paths for file handles (needs CAP_DAC_READ_SEARCH)");
+        }
+        if !self.mount_marks {
+            notes.push("no mount or filesystem marks");
+        }
+        if !self.marks {
+            notes.push("no sticky, wait-for, ignore or ready watches; watches are fixed at startup");
+        }
+        if self.polled {
+            notes.push("changes between two polls are merged into one event");
+        }
+        match notes.is_empty() {
+            true => f.write_str("full semantics"),
+            false => f.write_str(&notes.join("; ")),
+        }
+    }
+}
+
+pub trait EventSource {
+    /// Short backend name for logs.
+    fn name(&self) -> &'static str;
+
+    fn semantics(&self) -> Semantics;
+
+    /// Descriptor to poll; readable when `read` has events.
+    fn fd(&self) -> RawFd;
+
+    /// Events available now with their paths resolved. Blocks like read(2)
+    /// when nothing is queued and `fd` is blocking.
+    fn read(&mut self) -> io::Result<Vec<Event>>;
+}
+
+const CAP_DAC_READ_SEARCH: u32 = 2;
+const CAP_SYS_ADMIN: u32 = 21;
+
+// Effective capability bit from /proc/self/status
+fn has_capability(cap: u32) -> bool {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|bits| u64::from_str_radix(bits.trim(), 16).ok())
+        .is_some_and(|bits| bits & (1 << cap) != 0)
+}
+
+/// A fanotify group whose marks are kept in the shared `MarkSet`.
+pub struct FanotifySource {
+    fd: RawFd,
+    state: Arc<ControlState>,
+    buffer: Vec<u8>,
+    semantics: Semantics,
+}
+
+impl FanotifySource {
+    pub fn new(fanotify_fd: RawFd, state: Arc<ControlState>) -> Self {
+        // Unprivileged groups (Linux 5.13+) get pid 0 for other processes' events
+        // and only inode marks
+        let admin = has_capability(CAP_SYS_ADMIN);
+        let semantics = Semantics {
+            pid: admin,
+            paths: has_capability(CAP_DAC_READ_SEARCH),
+            mount_marks: admin,
+            marks: true,
+            polled: false,
+        };
+        FanotifySource { fd: fanotify_fd, state, buffer: vec![0; 4096], semantics }
+    }
+}
+
+impl EventSource for FanotifySource {
+    fn name(&self) -> &'static str {
+        "fanotify"
+    }
+
+    fn semantics(&self) -> Semantics {
+        self.semantics
+    }
+
+    fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    fn read(&mut self) -> io::Result<Vec<Event>> {
+        let n = unsafe { libc::read(self.fd, self.buffer.as_mut_ptr() as *mut libc::c_void, self.buffer.len()) };
+        if n == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        println!("DEBUG: Read {} bytes from fanotify", n);
+        let mut events = parse_events(&self.buffer[..n as usize]);
+        // Turn file handles back into paths
+        let marks = self.state.marks.lock().unwrap();
+        for event in &mut events {
+            marks.resolver().resolve(event);
+        }
+        Ok(events)
+    }
+}
+
+const IN_Q_OVERFLOW: u32 = 0x4000;
+const IN_IGNORED: u32 = 0x8000;
+const IN_ISDIR: u32 = 0x4000_0000;
+const IN_MASK_ADD: u32 = 0x2000_0000;
+/// Entry events a recursive watch needs for itself, whatever was asked for.
+const IN_TREE: u32 = (FAN_CREATE | FAN_MOVED_FROM | FAN_MOVED_TO) as u32;
+
+#[repr(C)]
+struct InotifyEventHeader {
+    wd: i32,
+    mask: u32,
+    cookie: u32,
+    len: u32,
+}
+
+struct InotifyWatch {
+    path: PathBuf,
+    /// Event bits to report (fanotify and inotify share the low 12 bits).
+    wanted: u64,
+    recursive: bool,
+}
+
+/// Directory-tree watching with inotify, for when fanotify is not available.
+pub struct InotifySource {
+    fd: OwnedFd,
+    watches: HashMap<i32, InotifyWatch>,
+    buffer: Vec<u8>,
+}
+
+impl InotifySource {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        Ok(InotifySource { fd: unsafe { OwnedFd::from_raw_fd(fd) }, watches: HashMap::new(), buffer: vec![0; 4096] })
+    }
+
+    /// Number of inotify watches (one per directory of recursive trees).
+    pub fn len(&self) -> usize {
+        self.watches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    /// Watches `path` for `mask`; a directory reports events on its entries
+    /// too, and with `recursive` every directory below it is watched as well.
+    /// Returns the number of watches added.
+    pub fn watch(&mut self, path: &Path, mask: u64, recursive: bool) -> io::Result<usize> {
+        let mut added = 0;
+        let mut stack = vec![path.to_path_buf()];
+        while let Some(path) = stack.pop() {
+            match self.add_one(&path, mask, recursive) {
+                Ok(()) => added += 1,
+                // Gone before we got to it
+                Err(e) if e.kind() == io::ErrorKind::NotFound && added > 0 => continue,
+                Err(e) => return Err(e),
+            }
+            if recursive && let Ok(entries) = std::fs::read_dir(&path) {
+                // file_type() does not follow symlinks, so linked trees are not entered
+                stack.extend(entries.flatten().filter(|e| e.file_type().is_ok_and(|t| t.is_dir())).map(|e| e.path()));
+            }
+        }
+        Ok(added)
+    }
+
+    fn add_one(&mut self, path: &Path, mask: u64, recursive: bool) -> io::Result<()> {
+        let mut bits = (mask & 0xfff) as u32 | IN_MASK_ADD;
+        if recursive {
+            bits |= IN_TREE;
+        }
+        let path_cstr = path_cstring(path)?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path_cstr.as_ptr(), bits) };
+        if wd == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        let watch = self.watches.entry(wd).or_insert(InotifyWatch { path: path.to_path_buf(), wanted: 0, recursive });
+        watch.wanted |= mask & 0xfff;
+        watch.recursive |= recursive;
+        Ok(())
+    }
+
+    // A directory in a recursive tree was renamed from `from` to `to`
+    fn rekey(&mut self, from: &Path, to: &Path) {
+        for watch in self.watches.values_mut() {
+            if let Ok(below) = watch.path.strip_prefix(from) {
+                watch.path = to.join(below);
+            }
+        }
+    }
+
+    // A directory was moved out of sight; its watches would report stale paths
+    fn unwatch_tree(&mut self, top: &Path) {
+        let gone: Vec<i32> = self.watches.iter().filter(|(_, w)| w.path.starts_with(top)).map(|(wd, _)| *wd).collect();
+        for wd in gone {
+            unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), wd) };
+            self.watches.remove(&wd);
+        }
+    }
+}
+
+impl EventSource for InotifySource {
+    fn name(&self) -> &'static str {
+        "inotify"
+    }
+
+    fn semantics(&self) -> Semantics {
+        Semantics { pid: false, paths: true, mount_marks: false, marks: false, polled: false }
+    }
+
+    fn fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+
+    fn read(&mut self) -> io::Result<Vec<Event>> {
+        let n = unsafe { libc::read(self.fd.as_raw_fd(), self.buffer.as_mut_ptr() as *mut libc::c_void, self.buffer.len()) };
+        if n == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        println!("DEBUG: Read {} bytes from inotify", n);
+
+        let mut events = Vec::new();
+        // Directory renames are paired by cookie within one read
+        let mut moved_from: HashMap<u32, PathBuf> = HashMap::new();
+        let header_len = mem::size_of::<InotifyEventHeader>();
+        let mut offset = 0;
+        while offset + header_len <= n as usize {
+            let header: InotifyEventHeader =
+                unsafe { std::ptr::read_unaligned(self.buffer.as_ptr().add(offset) as *const InotifyEventHeader) };
+            let name_bytes = &self.buffer[offset + header_len..offset + header_len + header.len as usize];
+            offset += header_len + header.len as usize;
+            let name = OsStr::from_bytes(name_bytes.split(|b| *b == 0).next().unwrap_or_default()).to_os_string();
+
+            if header.mask & IN_Q_OVERFLOW != 0 {
+                println!("⚠ inotify queue overflowed; events were lost");
+                continue;
+            }
+            if header.mask & IN_IGNORED != 0 {
+                self.watches.remove(&header.wd);
+                continue;
+            }
+            let Some(watch) = self.watches.get(&header.wd) else {
+                continue;
+            };
+            let path = match name.is_empty() {
+                true => watch.path.clone(),
+                false => watch.path.join(&name),
+            };
+            let is_dir = header.mask & IN_ISDIR != 0;
+            let (wanted, recursive) = (watch.wanted, watch.recursive);
+
+            if recursive && is_dir {
+                if header.mask & FAN_MOVED_FROM as u32 != 0 {
+                    moved_from.insert(header.cookie, path.clone());
+                } else if header.mask & FAN_MOVED_TO as u32 != 0 && let Some(from) = moved_from.remove(&header.cookie) {
+                    self.rekey(&from, &path);
+                } else if header.mask & (FAN_CREATE | FAN_MOVED_TO) as u32 != 0
+                    && let Err(e) = self.watch(&path, wanted, true)
+                {
+                    println!("DEBUG: ✗ Failed to watch new directory {}: {}", path.display(), e);
+                }
+            }
+
+            let mask = (header.mask & 0xfff) as u64 & wanted;
+            if mask != 0 {
+                events.push(Event {
+                    mask: mask | if is_dir { FAN_ONDIR } else { 0 },
+                    pid: 0,
+                    fd: None,
+                    fid: None,
+                    dir_fid: None,
+                    name: (!name.is_empty()).then_some(name),
+                    path: Some(path),
+                    range: None,
+                    error: None,
+                    mnt_id: None,
+                    fs: None,
+                });
+            }
+        }
+        for from in moved_from.into_values() {
+            self.unwatch_tree(&from);
+        }
+        Ok(events)
+    }
+}
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// statfs(2) f_type of filesystems whose marks fail (ENODEV, EXDEV) or only
+/// see changes made through this kernel.
+const UNSUPPORTED_FILESYSTEMS: &[(i64, &str)] = &[
+    (0x6969, "nfs"),
+    (0x65735546, "fuse"),
+    (0x9fa0, "proc"),
+    (0x62656572, "sysfs"),
+    (0x517b, "smb"),
+    (0xff534d42, "cifs"),
+    (0xfe534d42, "smb2"),
+    (0x00c36400, "ceph"),
+    (0x01021997, "9p"),
+    (0x5346414f, "afs"),
+];
+
+/// Name of the filesystem holding `path` (or its nearest existing ancestor)
+/// when fanotify and inotify cannot be trusted on it.
+pub fn unsupported_filesystem(path: &Path) -> Option<&'static str> {
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let path_cstr = path_cstring(existing).ok()?;
+    let mut buf: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statfs(path_cstr.as_ptr(), &mut buf) } == -1 {
+        return None;
+    }
+    UNSUPPORTED_FILESYSTEMS.iter().find(|(magic, _)| *magic == buf.f_type as i64).map(|(_, name)| *name)
+}
+
+/// What one poll remembers about a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Snapshot {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+    is_dir: bool,
+}
+
+impl Snapshot {
+    fn of(meta: &std::fs::Metadata) -> Self {
+        Snapshot {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            size: meta.size(),
+            mtime: (meta.mtime(), meta.mtime_nsec()),
+            ctime: (meta.ctime(), meta.ctime_nsec()),
+            is_dir: meta.is_dir(),
+        }
+    }
+}
+
+struct PolledRoot {
+    path: PathBuf,
+    wanted: u64,
+    recursive: bool,
+    entries: BTreeMap<PathBuf, Snapshot>,
+}
+
+impl PolledRoot {
+    // The root, its entries if it is a directory, and with `recursive` everything below
+    fn scan(&self) -> BTreeMap<PathBuf, Snapshot> {
+        let mut entries = BTreeMap::new();
+        let Ok(meta) = std::fs::symlink_metadata(&self.path) else {
+            return entries;
+        };
+        entries.insert(self.path.clone(), Snapshot::of(&meta));
+        let mut stack = match meta.is_dir() {
+            true => vec![self.path.clone()],
+            false => Vec::new(),
+        };
+        while let Some(dir) = stack.pop() {
+            let Ok(children) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for child in children.flatten() {
+                // Entries vanish all the time on procfs; skip them until the next poll
+                let Ok(meta) = child.metadata() else {
+                    continue;
+                };
+                if self.recursive && meta.is_dir() {
+                    stack.push(child.path());
+                }
+                entries.insert(child.path(), Snapshot::of(&meta));
+            }
+        }
+        entries
+    }
+}
+
+/// Synthetic events from diffing stat(2) snapshots on a timer.
+pub struct PollingSource {
+    timer: OwnedFd,
+    interval: Duration,
+    roots: Vec<PolledRoot>,
+}
+
+impl PollingSource {
+    pub fn new(interval: Duration) -> io::Result<Self> {
+        let fd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        let timer = unsafe { OwnedFd::from_raw_fd(fd) };
+        let period = libc::timespec { tv_sec: interval.as_secs() as libc::time_t, tv_nsec: interval.subsec_nanos() as libc::c_long };
+        let spec = libc::itimerspec { it_interval: period, it_value: period };
+        if unsafe { libc::timerfd_settime(timer.as_raw_fd(), 0, &spec, std::ptr::null_mut()) } == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        Ok(PollingSource { timer, interval, roots: Vec::new() })
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Number of polled roots.
+    pub fn len(&self) -> usize {
+        self.roots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    /// Polls `path` for `mask`; a directory reports changes to its entries,
+    /// with `recursive` to everything below it. Returns the number of paths
+    /// in the first snapshot.
+    pub fn watch(&mut self, path: &Path, mask: u64, recursive: bool) -> io::Result<usize> {
+        std::fs::symlink_metadata(path)?;
+        let mut root = PolledRoot { path: path.to_path_buf(), wanted: mask, recursive, entries: BTreeMap::new() };
+        root.entries = root.scan();
+        let count = root.entries.len();
+        self.roots.push(root);
+        Ok(count)
+    }
+}
+
+// Events for one path between two snapshots
+fn diff_snapshot(old: Option<&Snapshot>, new: Option<&Snapshot>) -> Vec<u64> {
+    match (old, new) {
+        (None, Some(_)) => vec![FAN_CREATE],
+        (Some(_), None) => vec![FAN_DELETE],
+        // Replaced, e.g. by an atomic save
+        (Some(old), Some(new)) if (old.dev, old.ino) != (new.dev, new.ino) => vec![FAN_DELETE, FAN_CREATE],
+        // A directory's times change with its entries, which are reported themselves
+        (Some(old), Some(new)) if new.is_dir => match old.ctime != new.ctime && old.mtime == new.mtime {
+            true => vec![FAN_ATTRIB],
+            false => Vec::new(),
+        },
+        // Closes are invisible to a poller; a finished change counts as one
+        (Some(old), Some(new)) if old.mtime != new.mtime || old.size != new.size => vec![FAN_MODIFY | FAN_CLOSE_WRITE],
+        (Some(old), Some(new)) if old.ctime != new.ctime => vec![FAN_ATTRIB],
+        _ => Vec::new(),
+    }
+}
+
+impl EventSource for PollingSource {
+    fn name(&self) -> &'static str {
+        "polling"
+    }
+
+    fn semantics(&self) -> Semantics {
+        Semantics { pid: false, paths: true, mount_marks: false, marks: false, polled: true }
+    }
+
+    fn fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+
+    fn read(&mut self) -> io::Result<Vec<Event>> {
+        let mut expirations = 0u64;
+        let n = unsafe { libc::read(self.timer.as_raw_fd(), &mut expirations as *mut u64 as *mut libc::c_void, 8) };
+        if n == -1 {
+            return Err(io::Error::from_raw_os_error(get_errno()));
+        }
+        println!("DEBUG: Polling {} root(s) ({} timer expiration(s))", self.roots.len(), expirations);
+
+        let mut events = Vec::new();
+        for root in &mut self.roots {
+            let entries = root.scan();
+            let paths: std::collections::BTreeSet<&PathBuf> = root.entries.keys().chain(entries.keys()).collect();
+            for path in paths {
+                let (old, new) = (root.entries.get(path), entries.get(path));
+                let is_dir = new.or(old).is_some_and(|s| s.is_dir);
+                for mask in diff_snapshot(old, new) {
+                    let mask = mask & root.wanted;
+                    if mask == 0 {
+                        continue;
+                    }
+                    events.push(Event {
+                        mask: mask | if is_dir { FAN_ONDIR } else { 0 },
+                        pid: 0,
+                        fd: None,
+                        fid: None,
+                        dir_fid: None,
+                        name: path.file_name().map(OsStr::to_os_string),
+                        path: Some(path.clone()),
+                        range: None,
+                        error: None,
+                        mnt_id: None,
+                        fs: None,
+                    });
+                }
+            }
+            root.entries = entries;
+        }
+        Ok(events)
+    }
+}
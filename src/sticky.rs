@@ -0,0 +1,274 @@
+// Path-based ("sticky") watches that survive atomic saves.
+//
+// An inode mark follows the inode, not the name. Editors and config tools
+// save by writing a temp file and rename()-ing it over the target, which
+// leaves the mark on the old, now unlinked inode. A sticky watch also marks
+// the parent directory for entry events, and when a new inode takes the
+// watched name it moves the mark over and reports the replacement.
+//
+// Names are matched against the absolute, resolved paths the kernel reports,
+// so the watched path is made absolute through its parent directory. A
+// symlink is followed: the target's name in the target's directory is what
+// gets watched, and replacing the link itself goes unnoticed.
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::event::{fd_path, Event};
+use crate::mark::{path_cstring, MarkObject, MarkSet};
+use crate::mask::mask_names;
+use crate::sys::*;
+
+/// Entry events on the parent directory that can change what the name points to.
+pub const PARENT_MASK: u64 = FAN_CREATE | FAN_MOVED_TO | FAN_MOVED_FROM | FAN_DELETE;
+
+/// What happened to the watched name.
+#[derive(Debug, Clone)]
+pub enum StickyChange {
+    /// A new inode now has the name and carries the mark.
+    Replaced { path: PathBuf, old: Option<MarkObject>, new: MarkObject, pid: i32 },
+    /// The name was unlinked or renamed away; waiting for it to come back.
+    Gone { path: PathBuf, pid: i32 },
+}
+
+impl StickyChange {
+    pub fn path(&self) -> &Path {
+        match self {
+            StickyChange::Replaced { path, .. } | StickyChange::Gone { path, .. } => path,
+        }
+    }
+
+    pub fn pid(&self) -> i32 {
+        match self {
+            StickyChange::Replaced { pid, .. } | StickyChange::Gone { pid, .. } => *pid,
+        }
+    }
+
+    /// Event bits that caused the change, for filtering like regular events.
+    pub fn mask(&self) -> u64 {
+        match self {
+            StickyChange::Replaced { .. } => FAN_CREATE | FAN_MOVED_TO,
+            StickyChange::Gone { .. } => FAN_DELETE | FAN_MOVED_FROM,
+        }
+    }
+
+    /// JSON representation for subscribers, shaped like [`Event::to_json`].
+    pub fn to_json(&self) -> Value {
+        let (event, old, new) = match self {
+            StickyChange::Replaced { old, new, .. } => ("replaced", old.map(|o| o.to_string()), Some(new.to_string())),
+            StickyChange::Gone { .. } => ("gone", None, None),
+        };
+        json!({
+            "mask": self.mask(),
+            "events": mask_names(self.mask()),
+            "event": event,
+            "pid": self.pid(),
+            "path": self.path(),
+            "old": old,
+            "new": new,
+        })
+    }
+}
+
+impl std::fmt::Display for StickyChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StickyChange::Replaced { path, old: Some(old), new, pid } => {
+                write!(f, "{} replaced by pid {} ({} -> {}), mark moved to the new file", path.display(), pid, old, new)
+            }
+            StickyChange::Replaced { path, old: None, new, pid } => {
+                write!(f, "{} re-created by pid {} ({}), mark re-armed", path.display(), pid, new)
+            }
+            StickyChange::Gone { path, pid } => {
+                write!(f, "{} removed by pid {}, waiting for it to reappear", path.display(), pid)
+            }
+        }
+    }
+}
+
+pub struct StickyWatch {
+    path: PathBuf,
+    mask: u64,
+    object: Option<MarkObject>,
+    // O_PATH reference to the marked inode, so that after a rename we can
+    // still find out where it went and take our mark off it.
+    held: Option<OwnedFd>,
+    // The parent directory carrying PARENT_MASK for us, held the same way
+    parent: Option<(MarkObject, OwnedFd)>,
+}
+
+impl StickyWatch {
+    pub fn new(path: &Path, mask: u64) -> Self {
+        StickyWatch { path: resolve(path), mask, object: None, held: None, parent: None }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Resolves the path again while disarmed: directories or links on the
+    /// way may not have existed when the watch was made.
+    pub fn reresolve(&mut self) {
+        if self.parent.is_none() {
+            self.path = resolve(&self.path);
+        }
+    }
+
+    /// The inode currently carrying the mark.
+    pub fn object(&self) -> Option<MarkObject> {
+        self.object
+    }
+
+    /// Takes the marks off the file and its directory, e.g. because the
+    /// directory went away. Directory bits another watch holds stay.
+    pub fn disarm(&mut self, marks: &mut MarkSet) {
+        self.release(marks);
+        let Some((object, held)) = self.parent.take() else {
+            return;
+        };
+        let removed = linked_path(&held).is_some_and(|p| {
+            MarkObject::of(&p, FAN_MARK_INODE, true).ok() == Some(object) && marks.remove(&p, FAN_MARK_INODE, PARENT_MASK).is_ok()
+        });
+        if !removed {
+            marks.forget(&object);
+        }
+    }
+
+    /// Marks the parent directory for entry events and the file itself.
+    pub fn arm(&mut self, marks: &mut MarkSet) -> io::Result<()> {
+        self.reresolve();
+        let parent = self
+            .path
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "sticky watch needs a path with a parent"))?;
+        if self.parent.is_none() {
+            let held = open_path(parent)?;
+            marks.add(parent, FAN_MARK_INODE, PARENT_MASK, FAN_MARK_ONLYDIR)?;
+            self.parent = Some((MarkObject::of(parent, FAN_MARK_INODE, false)?, held));
+        }
+        self.mark_current(marks)?;
+        Ok(())
+    }
+
+    /// Follows the watched name through a directory entry event.
+    pub fn handle(&mut self, marks: &mut MarkSet, event: &Event) -> Option<StickyChange> {
+        if event.is_dir() || event.path.as_deref() != Some(self.path.as_path()) {
+            return None;
+        }
+        if event.mask & (FAN_CREATE | FAN_MOVED_TO) != 0 {
+            let old = self.object;
+            let new = match MarkObject::of(&self.path, FAN_MARK_INODE, false) {
+                Ok(new) if Some(new) == old => return None,
+                Ok(new) => new,
+                // Already gone again; the next event tells us more
+                Err(_) => return None,
+            };
+            self.release(marks);
+            if let Err(e) = self.mark_current(marks) {
+                println!("DEBUG: ✗ Failed to re-arm sticky watch on {}: {}", self.path.display(), e);
+                return None;
+            }
+            return Some(StickyChange::Replaced { path: self.path.clone(), old, new, pid: event.pid });
+        }
+        if event.mask & (FAN_DELETE | FAN_MOVED_FROM) != 0 && self.object.is_some() {
+            self.release(marks);
+            return Some(StickyChange::Gone { path: self.path.clone(), pid: event.pid });
+        }
+        None
+    }
+
+    fn mark_current(&mut self, marks: &mut MarkSet) -> io::Result<MarkObject> {
+        let held = open_path(&self.path)?;
+        marks.add(&self.path, FAN_MARK_INODE, self.mask, 0)?;
+        let object = MarkObject::of(&self.path, FAN_MARK_INODE, false)?;
+        self.object = Some(object);
+        self.held = Some(held);
+        Ok(object)
+    }
+
+    // Takes the mark off the inode that used to have the name. If it is still
+    // linked somewhere (a vim backup, say) the kernel mark is removed there;
+    // once unlinked the kernel drops it with the inode and we only forget it.
+    fn release(&mut self, marks: &mut MarkSet) {
+        let Some(object) = self.object.take() else {
+            return;
+        };
+        let removed = self.held.take().and_then(|fd| linked_path(&fd)).is_some_and(|p| {
+            MarkObject::of(&p, FAN_MARK_INODE, true).ok() == Some(object) && marks.remove(&p, FAN_MARK_INODE, self.mask).is_ok()
+        });
+        if !removed {
+            marks.forget(&object);
+        }
+    }
+}
+
+// The name the kernel will report for path: its longest existing prefix
+// resolved (symlinks followed), with the missing components after it. Those
+// cannot be symlinks, so ".." among them is taken lexically.
+fn resolve(path: &Path) -> PathBuf {
+    let mut missing = Vec::new();
+    for prefix in path.ancestors() {
+        let existing = if prefix.as_os_str().is_empty() { Path::new(".") } else { prefix };
+        if let Ok(mut resolved) = std::fs::canonicalize(existing) {
+            for component in missing.into_iter().rev() {
+                match component {
+                    Component::ParentDir => {
+                        resolved.pop();
+                    }
+                    Component::Normal(name) => resolved.push(name),
+                    _ => {}
+                }
+            }
+            return resolved;
+        }
+        missing.extend(prefix.components().next_back());
+    }
+    path.to_path_buf()
+}
+
+// O_PATH reference that finds an inode again after a rename
+fn open_path(path: &Path) -> io::Result<OwnedFd> {
+    let path_cstr = path_cstring(path)?;
+    let fd = unsafe { libc::open(path_cstr.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd == -1 {
+        return Err(io::Error::from_raw_os_error(get_errno()));
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+// Where a held inode is linked now; None once it was unlinked
+fn linked_path(held: &OwnedFd) -> Option<PathBuf> {
+    fd_path(held.as_raw_fd()).ok().filter(|p| !p.as_os_str().as_bytes().ends_with(b" (deleted)"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths_are_matched_as_the_kernel_reports_them() {
+        let cwd = std::env::current_dir().unwrap().canonicalize().unwrap();
+        assert_eq!(StickyWatch::new(Path::new("./Cargo.toml"), FAN_MODIFY).path(), cwd.join("Cargo.toml"));
+        assert_eq!(StickyWatch::new(Path::new("src/../app.conf"), FAN_MODIFY).path(), cwd.join("app.conf"));
+        assert_eq!(StickyWatch::new(Path::new("app.conf"), FAN_MODIFY).path(), cwd.join("app.conf"));
+        assert_eq!(StickyWatch::new(Path::new("conf.d/../app.conf"), FAN_MODIFY).path(), cwd.join("app.conf"));
+    }
+
+    #[test]
+    fn symlinks_are_followed_to_the_target() {
+        let dir = std::env::temp_dir().join(format!("sticky-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("run")).unwrap();
+        std::os::unix::fs::symlink(dir.join("run/resolv.conf"), dir.join("resolv.conf")).unwrap();
+        let real = dir.canonicalize().unwrap();
+        // Dangling until the target is written
+        assert_eq!(StickyWatch::new(&dir.join("resolv.conf"), FAN_MODIFY).path(), real.join("resolv.conf"));
+        std::fs::write(dir.join("run/resolv.conf"), "").unwrap();
+        assert_eq!(StickyWatch::new(&dir.join("resolv.conf"), FAN_MODIFY).path(), real.join("run/resolv.conf"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
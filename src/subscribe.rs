@@ -0,0 +1,232 @@
+// Live subscription server: many clients stream filtered events from one
+// fanotify group over a Unix socket.
+//
+// A client connects and sends one JSON line describing its subscription:
+//   {"events": ["open", "modify"], "path_prefix": "/srv", "pid": 1234,
+//    "queue": 1024, "policy": "drop" | "disconnect" | "block"}
+// All fields are optional. The server answers {"subscribed": <id>} and then
+// writes one JSON object per matching event.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::event::Event;
+use crate::filter::EventFilter;
+use crate::mask::mask_from_name;
+
+const DEFAULT_QUEUE: usize = 1024;
+
+/// What to do when a subscriber's queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlowConsumerPolicy {
+    /// Discard the new event and report the number of drops to the client.
+    #[default]
+    Drop,
+    /// Close the connection.
+    Disconnect,
+    /// Stall the event loop until the client catches up. The kernel queue
+    /// keeps absorbing events meanwhile.
+    Block,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscribeRequest {
+    #[serde(default)]
+    events: Vec<String>,
+    #[serde(default)]
+    path_prefix: Option<PathBuf>,
+    #[serde(default)]
+    pid: Option<i32>,
+    #[serde(default)]
+    queue: Option<usize>,
+    #[serde(default)]
+    policy: SlowConsumerPolicy,
+}
+
+struct Subscriber {
+    id: u64,
+    filter: EventFilter,
+    policy: SlowConsumerPolicy,
+    capacity: usize,
+    queue: Mutex<VecDeque<Arc<str>>>,
+    ready: Condvar,
+    closed: AtomicBool,
+    dropped: AtomicU64,
+    stream: UnixStream,
+}
+
+impl Subscriber {
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let _ = self.stream.shutdown(Shutdown::Both);
+        self.ready.notify_all();
+    }
+
+    fn push(&self, line: &Arc<str>) {
+        let mut queue = self.queue.lock().unwrap();
+        while queue.len() >= self.capacity {
+            match self.policy {
+                SlowConsumerPolicy::Drop => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    drop(queue);
+                    println!("DEBUG: subscriber {} too slow, disconnecting", self.id);
+                    self.close();
+                    return;
+                }
+                SlowConsumerPolicy::Block => {
+                    if self.closed.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    queue = self.ready.wait(queue).unwrap();
+                }
+            }
+        }
+        queue.push_back(Arc::clone(line));
+        self.ready.notify_all();
+    }
+
+    // Runs on the subscriber's own thread so one slow client never holds up another
+    fn write_loop(&self) {
+        let mut stream = &self.stream;
+        loop {
+            let line = {
+                let mut queue = self.queue.lock().unwrap();
+                loop {
+                    if self.closed.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    if let Some(line) = queue.pop_front() {
+                        break line;
+                    }
+                    queue = self.ready.wait(queue).unwrap();
+                }
+            };
+            self.ready.notify_all();
+            let dropped = self.dropped.swap(0, Ordering::Relaxed);
+            let result = if dropped > 0 {
+                writeln!(stream, "{}", json!({ "dropped": dropped })).and_then(|_| writeln!(stream, "{}", line))
+            } else {
+                writeln!(stream, "{}", line)
+            };
+            if result.is_err() {
+                self.close();
+                return;
+            }
+        }
+    }
+}
+
+/// Accepts subscribers and fans events out to them.
+pub struct SubscriptionServer {
+    path: PathBuf,
+    subscribers: Arc<Mutex<Vec<Arc<Subscriber>>>>,
+}
+
+impl SubscriptionServer {
+    /// Binds the socket and starts accepting subscribers in the background.
+    pub fn start(path: &Path, gid: Option<libc::gid_t>) -> io::Result<Self> {
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "path exists and is not a socket"));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))?;
+        if let Some(gid) = gid {
+            std::os::unix::fs::chown(path, None, Some(gid))?;
+        }
+
+        let subscribers: Arc<Mutex<Vec<Arc<Subscriber>>>> = Arc::default();
+        let registry = Arc::clone(&subscribers);
+        thread::Builder::new().name("subscribe".into()).spawn(move || {
+            for (id, stream) in (1..).zip(listener.incoming().flatten()) {
+                let registry = Arc::clone(&registry);
+                let _ = thread::Builder::new().name(format!("subscriber-{}", id)).spawn(move || {
+                    match handshake(id, stream) {
+                        Ok(subscriber) => {
+                            registry.lock().unwrap().push(Arc::clone(&subscriber));
+                            subscriber.write_loop();
+                            println!("DEBUG: subscriber {} disconnected", id);
+                        }
+                        Err(e) => println!("DEBUG: subscriber {} rejected: {}", id, e),
+                    }
+                });
+            }
+        })?;
+        Ok(SubscriptionServer { path: path.to_path_buf(), subscribers })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+
+    /// Delivers `event` to every matching subscriber. Serializes at most once.
+    pub fn publish(&self, event: &Event) {
+        self.publish_with(event.mask, event.pid, event.path.as_deref(), || event.to_json());
+    }
+
+    /// Delivers a monitor-generated record (e.g. a sticky watch "replaced"
+    /// notice) to subscribers whose filter matches `mask`, `pid` and `path`.
+    pub fn publish_with(&self, mask: u64, pid: i32, path: Option<&Path>, to_json: impl FnOnce() -> Value) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|s| !s.closed.load(Ordering::Relaxed));
+        let mut to_json = Some(to_json);
+        let mut line: Option<Arc<str>> = None;
+        for subscriber in subscribers.iter() {
+            if !subscriber.filter.matches(mask, pid, path) {
+                continue;
+            }
+            let line = line.get_or_insert_with(|| (to_json.take().unwrap())().to_string().into());
+            subscriber.push(line);
+        }
+    }
+}
+
+fn handshake(id: u64, stream: UnixStream) -> io::Result<Arc<Subscriber>> {
+    let mut first = String::new();
+    BufReader::new(&stream).read_line(&mut first)?;
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let request: SubscribeRequest = serde_json::from_str(&first).map_err(|e| invalid(e.to_string()))?;
+    let mask = request.events.iter().try_fold(0, |mask, name| Ok::<_, String>(mask | mask_from_name(name)?));
+    let mask = match mask {
+        Ok(mask) => mask,
+        Err(e) => {
+            let _ = writeln!(&stream, "{}", json!({ "error": e }));
+            return Err(invalid(e));
+        }
+    };
+    let subscriber = Subscriber {
+        id,
+        filter: EventFilter { mask, path_prefix: request.path_prefix, pid: request.pid },
+        policy: request.policy,
+        capacity: request.queue.unwrap_or(DEFAULT_QUEUE).max(1),
+        queue: Mutex::new(VecDeque::new()),
+        ready: Condvar::new(),
+        closed: AtomicBool::new(false),
+        dropped: AtomicU64::new(0),
+        stream,
+    };
+    let ack: Value = json!({ "subscribed": id, "policy": format!("{:?}", subscriber.policy).to_lowercase() });
+    writeln!(&subscriber.stream, "{}", ack)?;
+    println!("DEBUG: subscriber {} connected with filter {:?}", id, subscriber.filter);
+    Ok(Arc::new(subscriber))
+}
@@ -0,0 +1,242 @@
+// Raw fanotify ABI: constants, structures and system call wrappers.
+// Values mirror <linux/fanotify.h>.
+
+// fanotify_init() flags
+pub const FAN_CLASS_NOTIF: u32 = 0;
+pub const FAN_CLOEXEC: u32 = 0x00000001;
+pub const FAN_NONBLOCK: u32 = 0x00000002;
+pub const FAN_CLASS_CONTENT: u32 = 0x00000004;  // Permission events; no FID reporting
+pub const FAN_CLASS_PRE_CONTENT: u32 = 0x00000008;  // Also pre-content events
+pub const FAN_ENABLE_AUDIT: u32 = 0x00000040;  // Needs CAP_AUDIT_WRITE
+pub const FAN_REPORT_TID: u32 = 0x00000100;
+pub const FAN_REPORT_FID: u32 = 0x00000200;  // Required for FAN_ATTRIB since Linux 5.1
+pub const FAN_REPORT_DIR_FID: u32 = 0x00000400;  // Optional: for parent directory handles
+pub const FAN_REPORT_NAME: u32 = 0x00000800;
+pub const FAN_REPORT_DFID_NAME: u32 = FAN_REPORT_DIR_FID | FAN_REPORT_NAME;
+pub const FAN_REPORT_TARGET_FID: u32 = 0x00001000;  // Child FID on dirent events, Linux 5.17+
+pub const FAN_REPORT_MNT: u32 = 0x00004000;  // Mount events, Linux 6.15+; not with FID reporting
+
+// Event mask bits
+pub const FAN_ACCESS: u64 = 0x00000001;
+pub const FAN_MODIFY: u64 = 0x00000002;
+pub const FAN_ATTRIB: u64 = 0x00000004;
+pub const FAN_CLOSE_WRITE: u64 = 0x00000008;
+pub const FAN_CLOSE_NOWRITE: u64 = 0x00000010;
+pub const FAN_OPEN: u64 = 0x00000020;
+pub const FAN_MOVED_FROM: u64 = 0x00000040;
+pub const FAN_MOVED_TO: u64 = 0x00000080;
+pub const FAN_CREATE: u64 = 0x00000100;
+pub const FAN_DELETE: u64 = 0x00000200;
+pub const FAN_DELETE_SELF: u64 = 0x00000400;
+pub const FAN_MOVE_SELF: u64 = 0x00000800;
+pub const FAN_OPEN_EXEC: u64 = 0x00001000;  // Linux 5.0+
+pub const FAN_FS_ERROR: u64 = 0x00008000;  // Linux 5.16+, filesystem marks of FID groups
+pub const FAN_OPEN_PERM: u64 = 0x00010000;
+pub const FAN_ACCESS_PERM: u64 = 0x00020000;
+pub const FAN_OPEN_EXEC_PERM: u64 = 0x00040000;  // Linux 5.0+
+pub const FAN_PRE_ACCESS: u64 = 0x00100000;  // Linux 6.14+, FAN_CLASS_PRE_CONTENT groups
+pub const FAN_MNT_ATTACH: u64 = 0x01000000;  // Linux 6.15+, mount namespace marks
+pub const FAN_MNT_DETACH: u64 = 0x02000000;
+pub const FAN_MOVE: u64 = FAN_MOVED_FROM | FAN_MOVED_TO;
+pub const FAN_ALL_PERM_EVENTS: u64 = FAN_OPEN_PERM | FAN_ACCESS_PERM | FAN_OPEN_EXEC_PERM | FAN_PRE_ACCESS;
+
+pub const FAN_EVENT_ON_CHILD: u64 = 0x08000000;
+pub const FAN_ONDIR: u64 = 0x40000000;
+
+// fanotify_mark() flags
+pub const FAN_MARK_ADD: u32 = 0x00000001;
+pub const FAN_MARK_REMOVE: u32 = 0x00000002;
+pub const FAN_MARK_DONT_FOLLOW: u32 = 0x00000004;
+pub const FAN_MARK_ONLYDIR: u32 = 0x00000008;
+pub const FAN_MARK_IGNORED_MASK: u32 = 0x00000020;
+pub const FAN_MARK_IGNORED_SURV_MODIFY: u32 = 0x00000040;
+pub const FAN_MARK_FLUSH: u32 = 0x00000080;
+pub const FAN_MARK_IGNORE: u32 = 0x00000400;  // Linux 6.0+
+pub const FAN_MARK_IGNORE_SURV: u32 = FAN_MARK_IGNORE | FAN_MARK_IGNORED_SURV_MODIFY;
+
+// Mark types
+pub const FAN_MARK_INODE: u32 = 0x00000000;
+pub const FAN_MARK_MOUNT: u32 = 0x00000010;
+pub const FAN_MARK_FILESYSTEM: u32 = 0x00000100;
+pub const FAN_MARK_MNTNS: u32 = 0x00000110;  // Path of a mount namespace, e.g. /proc/self/ns/mnt
+pub const FAN_MARK_TYPE_MASK: u32 = FAN_MARK_MOUNT | FAN_MARK_FILESYSTEM;
+
+pub const AT_FDCWD: libc::c_int = -100;
+
+pub const FANOTIFY_METADATA_VERSION: u8 = 3;
+pub const FAN_NOFD: i32 = -1;
+
+// Info record types following the metadata in FID mode
+pub const FAN_EVENT_INFO_TYPE_FID: u8 = 1;
+pub const FAN_EVENT_INFO_TYPE_DFID_NAME: u8 = 2;
+pub const FAN_EVENT_INFO_TYPE_DFID: u8 = 3;
+pub const FAN_EVENT_INFO_TYPE_ERROR: u8 = 5;  // FAN_FS_ERROR
+pub const FAN_EVENT_INFO_TYPE_RANGE: u8 = 6;  // Pre-content events
+pub const FAN_EVENT_INFO_TYPE_MNT: u8 = 7;  // Mount events
+
+// fanotify_event_metadata structure
+#[repr(C)]
+#[derive(Debug)]
+pub struct FanotifyEventMetadata {
+    pub event_len: u32,
+    pub vers: u8,
+    pub reserved: u8,
+    pub metadata_len: u16,
+    pub mask: u64,
+    pub fd: i32,
+    pub pid: i32,
+}
+
+// Answers written back for permission events
+pub const FAN_ALLOW: u32 = 0x01;
+pub const FAN_DENY: u32 = 0x02;
+pub const FAN_AUDIT: u32 = 0x10;  // Also write an audit record (FAN_ENABLE_AUDIT groups)
+
+// fanotify_response structure
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FanotifyResponse {
+    pub fd: i32,
+    pub response: u32,
+}
+
+// fanotify_event_info_header structure
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FanotifyEventInfoHeader {
+    pub info_type: u8,
+    pub pad: u8,
+    pub len: u16,
+}
+
+// fanotify_event_info_error structure
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FanotifyEventInfoError {
+    pub hdr: FanotifyEventInfoHeader,
+    pub error: i32,
+    pub error_count: u32,
+}
+
+// fanotify_event_info_range structure
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FanotifyEventInfoRange {
+    pub hdr: FanotifyEventInfoHeader,
+    pub pad: u32,
+    pub offset: u64,
+    pub count: u64,
+}
+
+// fanotify_event_info_mnt structure
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FanotifyEventInfoMnt {
+    pub hdr: FanotifyEventInfoHeader,
+    /// 64-bit mount id, never reused (statx STATX_MNT_ID_UNIQUE).
+    pub mnt_id: u64,
+}
+
+// struct mnt_id_req for statmount(2) and listmount(2), Linux 6.8+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MntIdReq {
+    pub size: u32,
+    pub spare: u32,
+    pub mnt_id: u64,
+    /// statmount: the STATMOUNT_* mask; listmount: the last id already returned.
+    pub param: u64,
+    pub mnt_ns_id: u64,
+}
+
+// open_tree(2) flags, Linux 5.2+
+pub const OPEN_TREE_CLONE: u32 = 0x00000001;  // A detached copy of the mount
+pub const OPEN_TREE_CLOEXEC: u32 = libc::O_CLOEXEC as u32;
+
+pub const LSMT_ROOT: u64 = u64::MAX;  // listmount: every mount of the namespace
+pub const STATMOUNT_SB_BASIC: u64 = 0x00000001;
+pub const STATMOUNT_MNT_BASIC: u64 = 0x00000002;
+pub const STATMOUNT_MNT_ROOT: u64 = 0x00000008;
+pub const STATMOUNT_MNT_POINT: u64 = 0x00000010;
+pub const STATMOUNT_FS_TYPE: u64 = 0x00000020;
+pub const STATMOUNT_SB_SOURCE: u64 = 0x00000200;  // Linux 6.14+
+
+// System call numbers libc does not know yet; syscalls added since 5.1 have
+// the same number on every architecture
+const SYS_STATMOUNT: libc::c_long = 457;
+const SYS_LISTMOUNT: libc::c_long = 458;
+
+// Raw system call wrappers
+
+/// # Safety
+/// Thin wrapper around the raw system call; the caller owns the returned fd.
+pub unsafe fn fanotify_init(flags: u32, event_f_flags: u32) -> libc::c_int {
+    unsafe { libc::syscall(libc::SYS_fanotify_init, flags, event_f_flags) as libc::c_int }
+}
+
+/// # Safety
+/// `pathname` must be null or point to a valid NUL-terminated string.
+pub unsafe fn fanotify_mark(
+    fanotify_fd: libc::c_int,
+    flags: u32,
+    mask: u64,
+    dirfd: libc::c_int,
+    pathname: *const libc::c_char,
+) -> libc::c_int {
+    unsafe { libc::syscall(libc::SYS_fanotify_mark, fanotify_fd, flags, mask, dirfd, pathname) as libc::c_int }
+}
+
+/// # Safety
+/// `pathname` must point to a valid NUL-terminated string and `handle` to a
+/// `struct file_handle` with room for `handle_bytes` bytes.
+pub unsafe fn name_to_handle_at(
+    dirfd: libc::c_int,
+    pathname: *const libc::c_char,
+    handle: *mut u8,
+    mount_id: *mut libc::c_int,
+    flags: libc::c_int,
+) -> libc::c_int {
+    unsafe { libc::syscall(libc::SYS_name_to_handle_at, dirfd, pathname, handle, mount_id, flags) as libc::c_int }
+}
+
+/// # Safety
+/// `handle` must point to a `struct file_handle` followed by `handle_bytes` bytes.
+pub unsafe fn open_by_handle_at(mount_fd: libc::c_int, handle: *const u8, flags: libc::c_int) -> libc::c_int {
+    unsafe { libc::syscall(libc::SYS_open_by_handle_at, mount_fd, handle, flags) as libc::c_int }
+}
+
+/// # Safety
+/// `pathname` must point to a valid NUL-terminated string; the caller owns the returned fd.
+pub unsafe fn open_tree(dirfd: libc::c_int, pathname: *const libc::c_char, flags: u32) -> libc::c_int {
+    unsafe { libc::syscall(libc::SYS_open_tree, dirfd, pathname, flags) as libc::c_int }
+}
+
+/// # Safety
+/// `buf` must be valid for `bufsize` bytes.
+pub unsafe fn statmount(req: &MntIdReq, buf: *mut u8, bufsize: usize) -> libc::c_int {
+    unsafe { libc::syscall(SYS_STATMOUNT, req as *const MntIdReq, buf, bufsize, 0) as libc::c_int }
+}
+
+/// # Safety
+/// `ids` must be valid for `nr` u64s.
+pub unsafe fn listmount(req: &MntIdReq, ids: *mut u64, nr: usize) -> libc::c_int {
+    unsafe { libc::syscall(SYS_LISTMOUNT, req as *const MntIdReq, ids, nr, 0) as libc::c_int }
+}
+
+/// strftime(3) of `time` in the local timezone.
+pub fn format_local_time(time: libc::time_t, format: &str) -> String {
+    let Ok(format_cstr) = std::ffi::CString::new(format) else {
+        return String::new();
+    };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let mut out = [0 as libc::c_char; 256];
+    let len = unsafe {
+        libc::localtime_r(&time, &mut tm);
+        libc::strftime(out.as_mut_ptr(), out.len(), format_cstr.as_ptr(), &tm)
+    };
+    String::from_utf8_lossy(unsafe { std::slice::from_raw_parts(out.as_ptr() as *const u8, len) }).into_owned()
+}
+
+pub fn get_errno() -> i32 {
+    unsafe { *libc::__errno_location() }
+}